@@ -0,0 +1,156 @@
+use std::{io::Write, path::PathBuf};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Key, Nonce,
+};
+use age::{
+    armor::{ArmoredWriter, Format},
+    secrecy::ExposeSecret,
+    x25519::Identity,
+};
+use base64::{engine::general_purpose, Engine as _};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use secnix::{
+    enc::age::{decrypt, decrypt_kek, Identities, SopsGcm},
+    sops::load_sops_file,
+};
+use ulid::Ulid;
+
+const DATA_KEY: [u8; 32] = [7u8; 32];
+const KEY_COUNT: usize = 32;
+
+/// A sops file written to a scratch directory and the identities that can decrypt it
+struct Fixture {
+    directory: PathBuf,
+    identities: Identities,
+    source: String,
+    kek: String,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let directory = std::env::temp_dir().join(format!("secnix-bench-{}", Ulid::new()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let identity = Identity::generate();
+        let recipient = identity.to_public();
+
+        let keyfile = directory.join("keys.txt");
+        std::fs::write(
+            &keyfile,
+            format!("{}\n", identity.to_string().expose_secret()),
+        )
+        .unwrap();
+
+        let kek = wrap_data_key(&recipient);
+
+        let mut contents = String::new();
+        for i in 0..KEY_COUNT {
+            let key = format!("key{}", i);
+            let value = encrypt_value(&format!("value{}", i), &[&key]);
+            contents.push_str(&format!("{}: {}\n", key, value));
+        }
+        contents.push_str("sops:\n  age:\n");
+        contents.push_str(&format!("    - recipient: {}\n", recipient));
+        contents.push_str("      enc: |\n");
+        for line in kek.lines() {
+            contents.push_str(&format!("        {}\n", line));
+        }
+        contents.push_str("  lastmodified: \"2024-01-01T00:00:00Z\"\n");
+        // Loading requires a mac, the benchmarks never verify it
+        contents.push_str("  mac: unverified\n");
+        contents.push_str("  unencrypted_suffix: _unencrypted\n");
+        contents.push_str("  version: 3.8.1\n");
+
+        let source = directory.join("secrets.yaml");
+        std::fs::write(&source, contents).unwrap();
+
+        Fixture {
+            identities: Identities::from_keyfile(&keyfile.to_string_lossy()).unwrap(),
+            source: source.to_string_lossy().into_owned(),
+            directory,
+            kek,
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+fn wrap_data_key(recipient: &age::x25519::Recipient) -> String {
+    let encryptor = age::Encryptor::with_recipients(vec![
+        Box::new(recipient.clone()) as Box<dyn age::Recipient + Send>
+    ])
+    .unwrap();
+    let mut output = vec![];
+    let armor = ArmoredWriter::wrap_output(&mut output, Format::AsciiArmor).unwrap();
+    let mut writer = encryptor.wrap_output(armor).unwrap();
+    writer.write_all(&DATA_KEY).unwrap();
+    writer.finish().and_then(|armor| armor.finish()).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+fn encrypt_value(value: &str, path: &[&str]) -> String {
+    let iv = [1u8; 32];
+    let aad = path.join(":") + ":";
+    let cipher = SopsGcm::new(Key::<SopsGcm>::from_slice(&DATA_KEY));
+    let encrypted = cipher
+        .encrypt(
+            Nonce::from_slice(&iv),
+            Payload {
+                msg: value.as_bytes(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .unwrap();
+    let (data, tag) = encrypted.split_at(encrypted.len() - 16);
+    format!(
+        "ENC[AES256_GCM,data:{},iv:{},tag:{},type:str]",
+        general_purpose::STANDARD.encode(data),
+        general_purpose::STANDARD.encode(iv),
+        general_purpose::STANDARD.encode(tag)
+    )
+}
+
+fn bench_decrypt_value(c: &mut Criterion) {
+    let value = encrypt_value("hunter2", &["password"]);
+    c.bench_function("decrypt_value", |b| {
+        b.iter_batched(
+            || value.clone(),
+            |value| decrypt(value, &DATA_KEY, vec!["password".to_string()], false).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_decrypt_kek(c: &mut Criterion) {
+    let fixture = Fixture::new();
+    c.bench_function("decrypt_kek", |b| {
+        b.iter(|| decrypt_kek(&fixture.kek, &fixture.identities).unwrap())
+    });
+}
+
+fn bench_decrypt_file(c: &mut Criterion) {
+    let fixture = Fixture::new();
+    let keys: Vec<String> = (0..KEY_COUNT).map(|i| format!("key{}", i)).collect();
+    c.bench_function("decrypt_file", |b| {
+        b.iter(|| {
+            let file = load_sops_file(&fixture.source, None).unwrap();
+            for key in &keys {
+                file.decrypt(&[key.as_str()], &fixture.identities).unwrap();
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_decrypt_value,
+    bench_decrypt_kek,
+    bench_decrypt_file
+);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use secnix::{
+    enc::age::DecryptedValue,
+    fs::{render_template, TemplateSecret},
+    manifest::Template,
+};
+
+const SECRET_COUNT: usize = 100;
+const LINE_COUNT: usize = 10_000;
+
+fn bench_render_literal(c: &mut Criterion) {
+    let template: Template = serde_json::from_value(serde_json::json!({
+        "name": "bench",
+        "source": "bench.conf",
+        "destination": "bench.conf",
+    }))
+    .unwrap();
+
+    let names: Vec<String> = (0..SECRET_COUNT).map(|i| format!("secret{}", i)).collect();
+    let secrets: HashMap<&str, TemplateSecret> = names
+        .iter()
+        .map(|n| {
+            let text = format!("value of {}", n);
+            let value = DecryptedValue::String(text.clone());
+            (n.as_str(), TemplateSecret { text, value })
+        })
+        .collect();
+
+    let text: String = (0..LINE_COUNT)
+        .map(|i| {
+            format!(
+                "option{} = $$SECNIX::secret{}::SECNIX$$\n",
+                i,
+                i % SECRET_COUNT
+            )
+        })
+        .collect();
+
+    c.bench_function("render_literal", |b| {
+        b.iter_batched(
+            || text.clone(),
+            |text| render_template(&template, text, &secrets).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_render_literal);
+criterion_main!(benches);
@@ -19,10 +19,75 @@
       };
       key = lib.mkOption {
         type = lib.types.str;
-        description = "The key used in the secret file";
+        description = "The key used in the secret file. With * every value is written to its own file in a directory at the link, named after its key";
+      };
+      max_files = lib.mkOption {
+        type = lib.types.nullOr lib.types.ints.positive;
+        description = "The most files a secret with the * key may write";
+        default = null;
+      };
+      expected_type = lib.mkOption {
+        type = lib.types.nullOr (lib.types.enum ["str" "int" "float" "bytes" "bool"]);
+        description = "The type the secret is expected to be stored as";
+        default = null;
+      };
+      expected_recipients = lib.mkOption {
+        type = lib.types.nullOr (lib.types.listOf lib.types.str);
+        description = "The exact set of age recipients the secret file is expected to be encrypted for";
+        default = null;
+      };
+      decompress = lib.mkOption {
+        type = lib.types.nullOr (lib.types.enum ["gzip" "zstd"]);
+        description = "The compression to undo after decrypting the secret";
+        default = null;
+      };
+      format = lib.mkOption {
+        type = lib.types.nullOr (lib.types.enum ["raw" "json" "quoted" "yes-no"]);
+        description = "How scalar values are represented when written";
+        default = null;
+      };
+      subtree = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the key refers to a subtree that should be written out as a whole";
+        default = false;
+      };
+      fifo = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the secret is served once through a named pipe at its link instead of a file. The value can be read a single time per activation and can't be used in templates";
+        default = false;
+      };
+      credential = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the secret is written as a systemd credential named after the secret to credentialsDirectory instead of its link";
+        default = false;
+      };
+      copy = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the secret file is copied to its link instead of symlinked";
+        default = false;
+      };
+      immutable = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the secret file is made immutable so not even its owner can change it. Requires CAP_LINUX_IMMUTABLE";
+        default = false;
+      };
+      sha256 = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        description = "The SHA-256 the decrypted secret file is expected to have. Installing fails if it doesn't match";
+        default = null;
+      };
+      verify_mac = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether the MAC of the secret file is verified before decrypting";
+        default = true;
+      };
+      sops_path = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        description = "The dotted path of the sops metadata in the secret file, if it isn't the top level sops key";
+        default = null;
       };
       type = lib.mkOption {
-        type = lib.types.enum ["yaml" "json" "yml" "binary"];
+        type = lib.types.enum ["yaml" "json" "yml" "binary" "dotenv" "ini"];
         description = "The type of the secret file";
         default = "yaml";
       };
@@ -50,6 +115,16 @@
         type = lib.types.path;
         description = "The source of the secret file";
       };
+      restart_units = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        description = "The units to restart when reloading the secret";
+        default = [];
+      };
+      reload_units = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        description = "The units to reload when reloading the secret";
+        default = [];
+      };
     };
   });
   templateType = lib.types.submodule ({
@@ -76,6 +151,46 @@
         description = "Whether the template should be copied or linked";
         default = false;
       };
+      preserve_xattrs = lib.mkOption {
+        type = lib.types.bool;
+        description = "Whether a copied template keeps the extended attributes and ACLs of the file it replaces";
+        default = false;
+      };
+      engine = lib.mkOption {
+        type = lib.types.enum ["literal" "tera" "handlebars"];
+        description = "The engine used to render the template";
+        default = "literal";
+      };
+      placeholder = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        description = "The placeholder replaced by secrets in literal templates, where {key} is the name of the secret";
+        default = null;
+      };
+      inputs = lib.mkOption {
+        type = lib.types.listOf (lib.types.submodule {
+          options = {
+            name = lib.mkOption {
+              type = lib.types.str;
+              description = "The name the template refers to the secret by";
+            };
+            source = lib.mkOption {
+              type = lib.types.str;
+              description = "The secret file";
+            };
+            key = lib.mkOption {
+              type = lib.types.str;
+              description = "The key used in the secret file";
+            };
+            verify_mac = lib.mkOption {
+              type = lib.types.bool;
+              description = "Whether the MAC of the secret file is verified before decrypting";
+              default = true;
+            };
+          };
+        });
+        description = "Secrets that are only decrypted to render this template";
+        default = [];
+      };
       mode = lib.mkOption {
         type = lib.types.str;
         description = "The mode of the template";
@@ -90,6 +205,16 @@
         type = lib.types.nullOr lib.types.str;
         description = "The user that will own the template";
       };
+      restart_units = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        description = "The units to restart when reloading the template";
+        default = [];
+      };
+      reload_units = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        description = "The units to reload when reloading the template";
+        default = [];
+      };
     };
   });
   script = pkgs.writeShellScriptBin "secnix-activation" ''
@@ -113,9 +238,17 @@
         ssh_keys = cfg.sshKeys;
         write_manifest = true;
         secret_directory = cfg.mount;
+        umask = cfg.umask;
+        trust_mtime = cfg.trustMtime;
+        copy_all = cfg.copyAll;
+        active_link_name = cfg.activeLinkName;
+        keep_generations = cfg.keepGenerations;
+        credentials_directory = cfg.credentialsDirectory;
+        default_owner = cfg.defaultOwner;
+        default_group = cfg.defaultGroup;
       };
       checkPhase = ''
-        ${secnix}/bin/secnix "$out" check
+        ${secnix}/bin/secnix "$out" check --no-check-users --no-verify-mac
       '';
     };
 in {
@@ -134,12 +267,52 @@ in {
       sshKeys = lib.mkOption {
         type = lib.types.listOf lib.types.str;
         default = [];
-        description = "The ssh keys used to decrypt the secrets";
+        description = "The ssh keys used to decrypt the secrets. Entries prefixed with env: are read from the named environment variable";
+      };
+      umask = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "The umask, in octal, used while creating the secret files and directories";
+      };
+      copyAll = lib.mkOption {
+        type = lib.types.nullOr lib.types.bool;
+        default = null;
+        description = "Whether every secret and template is copied instead of symlinked, for systems that don't allow symlinks";
+      };
+      trustMtime = lib.mkOption {
+        type = lib.types.nullOr lib.types.bool;
+        default = null;
+        description = "Skip reading sources whose modification time and size haven't changed since the last install. Faster with many large sources, but a source changed without its modification time or size changing is only picked up by an install with --force";
+      };
+      credentialsDirectory = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "The directory secrets marked as credentials are written to, such as /run/credstore, for services to load with ImportCredential=";
+      };
+      activeLinkName = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "The name of the symlink in the mount point that points at the active generation. Defaults to secrets";
+      };
+      keepGenerations = lib.mkOption {
+        type = lib.types.nullOr lib.types.ints.unsigned;
+        default = null;
+        description = "How many inactive generations are kept after installing, so they can be activated again. Defaults to 0";
       };
       mount = lib.mkOption {
         type = lib.types.str;
         default = "%r/secnix";
-        description = "The mount point for the secrets. %r will be replaced with $XDG_RUNTIME_DIR";
+        description = "The mount point for the secrets. %r, %u and %h will be replaced with $XDG_RUNTIME_DIR, the current user and their home directory. The same specifiers are supported in secret links and template destinations";
+      };
+      defaultOwner = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "The user that will own secrets and templates that don't set an owner";
+      };
+      defaultGroup = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "The group that will own secrets and templates that don't set a group";
       };
       defaultSymlinkPath = lib.mkOption {
         type = lib.types.str;
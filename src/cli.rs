@@ -1,22 +1,42 @@
 use std::{
-    env::consts::OS,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ffi::CString,
     fs::OpenOptions,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
+use age::secrecy::ExposeSecret;
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tracing::{debug, info};
+use tracing::{debug, info, info_span, warn};
+use users::os::unix::UserExt;
+use zeroize::Zeroizing;
 
 use crate::{
-    fs::{activate_new_generation, clean_old_generations},
-    manifest::SecnixManifest,
-    sops::load_sops_file,
-    ssh::AgeKey,
+    enc::age::{is_identity_file, parse_identities, DecryptOptions, Identities},
+    fs::{
+        activate_generation, activate_new_generation, clean_old_generations, export_generation,
+        find_duplicate_link, get_active_secret_files, get_last_install, get_source_stats,
+        import_generation, is_generation_current, list_generations, lookup_id, plan_generation,
+        record_install, record_source_stats, resolve_gid, resolve_uid, secret_history, split_owner,
+        validate_link_path, validate_template, GenerationInfo, SourceStat,
+    },
+    manifest::{FileType, SecnixManifest, SecretFile},
+    progress,
+    sops::{
+        find_candidate, load_sops_file, normalize_recipient, rekey_sops_file, DecryptionError,
+        SopsFile,
+    },
+    source::{is_remote, read_large_source, read_source},
+    ssh::{self, is_certificate, parse_private_key, recipient_only, AgeKey},
 };
 
-use std::io::Write;
+use std::io::{BufRead, IsTerminal, Write};
 use std::os::unix::fs::OpenOptionsExt;
 
 #[derive(Parser)]
@@ -24,6 +44,75 @@ use std::os::unix::fs::OpenOptionsExt;
 pub struct Cli {
     /// The path to the manifest file.
     pub manifest: String,
+    /// Deploy a new generation even if nothing has changed since the active one.
+    #[arg(long, global = true)]
+    pub force: bool,
+    /// Lock all memory to prevent decrypted secrets from being swapped to disk. Requires
+    /// CAP_IPC_LOCK or a large enough RLIMIT_MEMLOCK, as every page the process maps counts against
+    /// the limit.
+    #[arg(long, global = true)]
+    pub mlock: bool,
+    /// Leave the links of the previous generation in place when installing. Links that are no
+    /// longer in the manifest are orphaned until they are removed by hand.
+    #[arg(long, global = true)]
+    pub no_cleanup: bool,
+    /// Install into a root filesystem, such as a chroot or disk image. Output paths are relative
+    /// to the root and owners and groups are resolved from its `/etc/passwd` and `/etc/group`.
+    #[arg(long, global = true)]
+    pub root: Option<PathBuf>,
+    /// Copy every secret and template instead of symlinking it. Overrides `copy_all` in the
+    /// manifest.
+    #[arg(long, global = true)]
+    pub copy_all: bool,
+    /// Don't show a progress bar while installing.
+    #[arg(long, global = true)]
+    pub quiet: bool,
+    /// Show what installing would change and ask for confirmation before installing.
+    #[arg(short = 'I', long, global = true, conflicts_with = "dry_run")]
+    pub interactive: bool,
+    /// With --interactive, install without asking. Needed when stdin is not a terminal.
+    #[arg(long, global = true, requires = "interactive")]
+    pub yes: bool,
+    /// Show what installing would deploy without changing anything.
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// With --dry-run, decrypt the secrets and compare them to the deployed files.
+    #[arg(long, global = true, requires = "dry_run")]
+    pub diff: bool,
+    /// With --diff, show the changed lines of secrets rather than only their size.
+    #[arg(long, global = true, requires = "diff")]
+    pub show_secrets: bool,
+    /// Use this directory for generations instead of the manifest's `secret_directory`. Takes
+    /// precedence over the SECNIX_BASEDIR environment variable.
+    #[arg(long, global = true)]
+    pub basedir: Option<String>,
+    /// Use values that don't parse as their declared sops type, like an int that isn't a number,
+    /// as strings instead of failing.
+    #[arg(long, global = true)]
+    pub lenient_types: bool,
+    /// Skip checking that the owners and groups of files exist on this system.
+    #[arg(long, global = true)]
+    pub no_check_users: bool,
+    /// Deploy the secrets of another user, resolving specifiers like `%r` for them and making
+    /// them the default owner of files. Overrides `target_user` in the manifest.
+    #[arg(long, global = true)]
+    pub target_user: Option<String>,
+    /// Write the age keyfile derived from the ssh keys to the secret directory and keep it, for
+    /// debugging. By default the derived keys are only held in memory.
+    #[arg(long, global = true, conflicts_with = "wipe_keyfile")]
+    pub keep_keyfile: bool,
+    /// Read the passphrase of encrypted ssh keys and of passphrase-protected sops files from this
+    /// file. SECNIX_SSH_PASSPHRASE and SECNIX_PASSPHRASE take precedence for each, and without
+    /// either the passphrase is prompted for.
+    #[arg(long, global = true)]
+    pub passphrase_file: Option<PathBuf>,
+    /// Keep this many inactive generations after installing, so they can be activated again.
+    /// Overrides `keep_generations` in the manifest.
+    #[arg(long, global = true)]
+    pub keep_generations: Option<usize>,
+    /// Wipe any age keyfile left in the secret directory. This is the default.
+    #[arg(long, global = true)]
+    pub wipe_keyfile: bool,
     #[clap(subcommand)]
     pub command: Option<Commands>,
 }
@@ -31,24 +120,211 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     /// Checks the provided manifest file for any issues.
-    Check,
+    Check {
+        /// Rewrite the manifest in a canonical form before checking it, printing what changed.
+        /// YAML manifests with comments are refused, as rewriting them would drop the comments.
+        #[arg(long)]
+        fix: bool,
+        /// Skip verifying the MACs of the sources. MACs are verified by default, which decrypts
+        /// every value. Sources none of the ssh keys can decrypt, such as when only the public
+        /// keys are available, are reported as unverified instead of failing.
+        #[arg(long)]
+        no_verify_mac: bool,
+    },
     /// Installs the secret files
-    Install,
+    Install {
+        /// Only decrypt these secrets and render these templates, carrying the rest over from the
+        /// active generation. Can be repeated.
+        #[arg(long)]
+        only: Vec<String>,
+    },
+    /// Diagnoses common problems with the environment without decrypting anything
+    Doctor,
+    /// Restarts and reloads the units of the active generation's secrets
+    Reload,
+    /// Activates a generation that has already been deployed or imported
+    Activate {
+        /// The id of the generation to activate
+        generation: String,
+    },
+    /// Exports a generation to a tarball
+    Export {
+        /// The id of the generation to export
+        generation: String,
+        /// The path of the tarball to write
+        output: String,
+    },
+    /// Imports a generation from a tarball created by export
+    Import {
+        /// The path of the tarball to import
+        archive: String,
+    },
+    /// Re-wraps the data key of a sops file for an updated set of age recipients
+    Rekey {
+        /// The sops file to update
+        source: String,
+        /// Recipients to add
+        #[arg(long)]
+        add_recipient: Vec<String>,
+        /// Recipients to remove
+        #[arg(long)]
+        remove_recipient: Vec<String>,
+    },
+    /// Prints which configured ssh key can decrypt a sops file
+    WhichKey {
+        /// The sops file
+        source: String,
+    },
+    /// Prints the age recipient of an ssh key
+    ConvertKey {
+        /// The ssh key, either a path or `env:VAR`
+        key: String,
+        /// Print a `.sops.yaml` creation rule for the recipient instead
+        #[arg(long)]
+        sops_rule: bool,
+        /// The path_regex of the creation rule
+        #[arg(long, requires = "sops_rule")]
+        path_regex: Option<String>,
+    },
+    /// Lists the generations in which a secret or template changed, oldest first
+    History {
+        /// The name of the secret or template
+        name: String,
+        /// Print the generations as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints a JSON Schema of the manifest format, for editor validation
+    #[command(hide = true)]
+    Schema,
+    /// Lists the deployed generations, oldest first
+    List {
+        /// Only show generations deployed within this duration, e.g. `2d` or `12h`
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+        /// Only show the most recent n generations
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print the generations as json
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 const MAX_SUPPORTED_VERSION: u64 = 1;
 
+/// The prefix of ssh key entries that are read from an environment variable
+const ENV_KEY_PREFIX: &str = "env:";
+
+/// The environment variable overriding the secret directory of the manifest
+const BASEDIR_VARIABLE: &str = "SECNIX_BASEDIR";
+
+/// The name of the age keyfile derived from the ssh keys, relative to the secret directory
+const KEYFILE: &str = "keys.txt";
+
 #[derive(Error, Debug)]
-enum Error {
+pub(crate) enum Error {
     #[error("Unsupported manifest version: {0}. The maximum supported version is {1}")]
     UnsupportedVersion(u64, u64),
     #[error("Checking {0} failed: {1}")]
     CheckFailed(String, String),
+    #[error("Environment variable {0} holding an ssh key is not set")]
+    MissingKeyVariable(String),
+    #[error("Environment variable {0} holding an ssh key is empty")]
+    EmptyKeyVariable(String),
+    #[error("Could not read ssh key {0}: {1}")]
+    UnreadableKey(String, #[source] std::io::Error),
+    #[error("{0} of {1} manifests failed")]
+    ManifestsFailed(usize, usize),
+    #[error("{0} environment check(s) failed")]
+    DoctorFailed(usize),
+    #[error("There is no active generation")]
+    NoActiveGeneration,
+    #[error("Target user {0} does not exist")]
+    UnknownTargetUser(String),
+    #[error("There is no secret or template named {0} in the manifest")]
+    UnknownSecret(String),
+    #[error("The runtime directory {0} of {1} does not exist")]
+    MissingRuntimeDirectory(String, String),
+    #[error("Not enough space in {0}: {1} bytes are needed but only {2} are available")]
+    InsufficientSpace(String, u64, u64),
+    #[error("Failed to {0} units {1:?}")]
+    UnitsFailed(&'static str, Vec<String>),
+    #[error("Installation was not confirmed{0}")]
+    NotConfirmed(&'static str),
+    #[error("{0} has comments that fixing it would drop")]
+    CommentedManifest(String),
+}
+
+/// Locks the process's current and future memory so it can't be swapped out. Failing to lock
+/// memory is not fatal.
+pub fn lock_memory() {
+    #[cfg(target_os = "linux")]
+    {
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0 {
+            debug!("Locked process memory");
+        } else {
+            warn!(
+                "Failed to lock memory, secrets may be swapped to disk: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    warn!(
+        "Locking memory is not supported on {}",
+        std::env::consts::OS
+    );
 }
 
 pub fn check(args: Cli) -> Result<()> {
-    info!("Checking manifest {}", args.manifest);
-    let manifest = load_manifest(&args.manifest)?;
+    let path = PathBuf::from(shellexpand::tilde(&args.manifest).as_ref());
+    if !path.is_dir() {
+        return check_manifest(&args, &args.manifest);
+    }
+
+    let mut manifests = std::fs::read_dir(&path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    manifests.retain(|p| {
+        p.is_file()
+            && p.extension()
+                .is_some_and(|e| e == "json" || e == "yaml" || e == "yml")
+    });
+    manifests.sort();
+    info!(
+        "Checking {} manifests in {}",
+        manifests.len(),
+        path.display()
+    );
+
+    let mut failed = 0;
+    for manifest in &manifests {
+        let manifest = manifest.to_string_lossy();
+        match check_manifest(&args, &manifest) {
+            Ok(()) => println!("[PASS] {}", manifest),
+            Err(e) => {
+                println!("[FAIL] {}: {:#}", manifest, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(Error::ManifestsFailed(failed, manifests.len()).into())
+    } else {
+        info!("All {} manifests are valid", manifests.len());
+        Ok(())
+    }
+}
+
+fn check_manifest(args: &Cli, path: &str) -> Result<()> {
+    if matches!(args.command, Some(Commands::Check { fix: true, .. })) {
+        fix_manifest(path)?;
+    }
+
+    info!("Checking manifest {}", path);
+    let manifest = read_manifest(path)?;
 
     debug!("Read manifest: {:?}", manifest);
 
@@ -58,17 +334,94 @@ pub fn check(args: Cli) -> Result<()> {
     for name in secrets.iter().map(|s| &s.name) {
         if !seen.insert(name) {
             return Err(Error::CheckFailed(
-                args.manifest.clone(),
+                path.to_string(),
                 format!("Duplicate name: {}", name).to_string(),
             )
             .into());
         }
     }
 
+    if let Some(secret) = secrets
+        .iter()
+        .find(|s| s.fifo.unwrap_or(false) && s.link.is_none())
+    {
+        return Err(Error::CheckFailed(
+            secret.name.clone(),
+            "Secrets served through a pipe need a link".to_string(),
+        )
+        .into());
+    }
+
+    // The link sits next to the generations and metadata, so it has to be a plain file name
+    let active_link = manifest.active_link_name();
+    if active_link.is_empty()
+        || active_link.contains('/')
+        || [".", "..", "generations", KEYFILE].contains(&active_link)
+    {
+        return Err(Error::CheckFailed(
+            path.to_string(),
+            format!("Invalid active link name: {}", active_link),
+        )
+        .into());
+    }
+
+    debug!("Checking for duplicate links");
+    if let Some(link) = find_duplicate_link(&manifest.secrets, &manifest.templates) {
+        return Err(
+            Error::CheckFailed(path.to_string(), format!("Duplicate link: {}", link)).into(),
+        );
+    }
+
+    debug!("Checking link paths");
+    match &args.root {
+        Some(root) => {
+            let mut manifest = read_manifest(path)?;
+            apply_root(&mut manifest, root)?;
+            validate_links(&manifest, Some(root))?;
+        }
+        None => validate_links(&manifest, None)?,
+    }
+
+    if !args.no_check_users {
+        debug!("Checking that owners and groups exist");
+        let secret_owners = manifest
+            .secrets
+            .iter()
+            .map(|s| (&s.name, s.owner.as_deref(), s.group.as_deref()));
+        let template_owners = manifest
+            .templates
+            .iter()
+            .map(|t| (&t.name, t.owner.as_deref(), t.group.as_deref()));
+        let defaults = &manifest.defaults;
+        let path = path.to_string();
+        let default_owner = std::iter::once((
+            &path,
+            defaults.default_owner.as_deref(),
+            defaults.default_group.as_deref(),
+        ));
+        for (name, owner, group) in secret_owners.chain(template_owners).chain(default_owner) {
+            let (owner, group) = split_owner(owner, group);
+            if let Some(owner) = owner.filter(|o| resolve_uid(o).is_none()) {
+                return Err(
+                    Error::CheckFailed(name.clone(), format!("Unknown user: {}", owner)).into(),
+                );
+            }
+            if let Some(group) = group.filter(|g| resolve_gid(g).is_none()) {
+                return Err(
+                    Error::CheckFailed(name.clone(), format!("Unknown group: {}", group)).into(),
+                );
+            }
+        }
+    }
+
+    let recipients = read_recipients(&manifest.ssh_keys, args.passphrase_file.as_deref());
+    debug!("Recipients of the configured ssh keys: {:?}", recipients);
+
     for file in &manifest.secrets {
+        let _span = info_span!("secret", name = %file.name).entered();
         debug!("Checking file: {:?}", file);
 
-        let sops_file = load_sops_file(&file.source)?;
+        let sops_file = file.load()?;
         debug!("Deserialized sops file");
         let metadata = sops_file.sops_metadata();
 
@@ -80,6 +433,69 @@ pub fn check(args: Cli) -> Result<()> {
         }
         debug!("Age keys found!");
 
+        if let Some(expected) = &file.expected_recipients {
+            let actual: Vec<&String> = metadata.age.iter().map(|a| &a.recipient).collect();
+            let missing: Vec<&String> = expected.iter().filter(|r| !actual.contains(r)).collect();
+            let extra: Vec<&String> = actual
+                .iter()
+                .copied()
+                .filter(|r| !expected.contains(r))
+                .collect();
+            if !missing.is_empty() || !extra.is_empty() {
+                return Err(Error::CheckFailed(
+                    file.source.clone(),
+                    format!(
+                        "Recipients don't match, missing: {:?}, extra: {:?}",
+                        missing, extra
+                    ),
+                )
+                .into());
+            }
+        }
+
+        if !recipients.is_empty() && find_candidate(metadata, &recipients).is_none() {
+            warn!(
+                "None of the configured ssh keys can decrypt {}",
+                file.source
+            );
+        }
+
+        if let Some(sha256) = &file.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::CheckFailed(
+                    file.name.clone(),
+                    format!("Invalid sha256: {}", sha256),
+                )
+                .into());
+            }
+        }
+
+        if file.credential.unwrap_or(false) {
+            check_credential(&manifest, file)?;
+        }
+
+        if file.is_wildcard() {
+            if file.sha256.is_some() {
+                return Err(Error::CheckFailed(
+                    file.name.clone(),
+                    "Secrets with the `*` key can't have a sha256".to_string(),
+                )
+                .into());
+            }
+            if file.fifo.unwrap_or(false) {
+                return Err(Error::CheckFailed(
+                    file.name.clone(),
+                    "Secrets with the `*` key can't be served through a pipe".to_string(),
+                )
+                .into());
+            }
+            let count = file
+                .count_files()
+                .map_err(|e| Error::CheckFailed(file.source.clone(), format!("{:#}", e)))?;
+            debug!("{} writes {} files", file.name, count);
+            continue;
+        }
+
         let key = file.get_key();
 
         let key = if let Some(key) = key {
@@ -95,17 +511,109 @@ pub fn check(args: Cli) -> Result<()> {
         let key = key.split('.').collect::<Vec<_>>();
 
         debug!("Checking if {:?} exists in the file", key);
-        let data = sops_file.get_key(&key);
-        if data.is_none() {
+        let exists = if file.subtree.unwrap_or(false) {
+            sops_file.get_value(&key).is_some()
+        } else {
+            sops_file.get_key(&key).is_some() || sops_file.is_null(&key)
+        };
+        if !exists {
             let raw = key.join(".");
-            return Err(Error::CheckFailed(
-                file.source.clone(),
-                format!("Key {:?} not found in file", raw).to_string(),
-            )
-            .into());
+            let message = if file.uses_default_binary_key() {
+                "Binary file has no `data` key, set `key` to the key holding its payload"
+                    .to_string()
+            } else {
+                format!("Key {:?} not found in file", raw)
+            };
+            return Err(Error::CheckFailed(file.source.clone(), message).into());
+        }
+
+        if let Some(expected) = &file.expected_type {
+            debug!("Checking if {:?} is stored as {:?}", key, expected);
+            let actual = sops_file.get_type(&key)?;
+            if &actual != expected {
+                warn!(
+                    "Key {:?} in {} is stored as {:?} but {:?} was expected",
+                    key.join("."),
+                    file.source,
+                    actual,
+                    expected
+                );
+            }
         }
     }
 
+    if !matches!(
+        args.command,
+        Some(Commands::Check {
+            no_verify_mac: true,
+            ..
+        })
+    ) {
+        debug!("Verifying the MACs of the sources");
+        // Checks also run where only the public keys are, such as in CI, so keys that can't be
+        // read only leave the MACs they would verify unverified
+        let options = decrypt_options(args);
+        let mut identities = vec![];
+        for key in &manifest.ssh_keys {
+            match key_identities(key, &options) {
+                Ok(derived) => identities.extend(derived),
+                Err(e) => warn!("Cannot verify MACs with {}: {:#}", key, e),
+            }
+        }
+        let identities = Identities::with_options(identities, options);
+
+        let mut seen = BTreeSet::new();
+        for file in &manifest.secrets {
+            if file.verify_mac.unwrap_or(true) && seen.insert((&file.source, &file.sops_path)) {
+                check_mac(&file.source, file.load()?.as_ref(), &identities)?;
+            }
+        }
+        let inputs = manifest
+            .templates
+            .iter()
+            .flat_map(|t| t.inputs.iter().flatten());
+        for input in inputs {
+            if input.verify_mac.unwrap_or(true) && seen.insert((&input.source, &input.sops_path)) {
+                let file = load_sops_file(&input.source, input.sops_path.as_deref())?;
+                check_mac(&input.source, file.as_ref(), &identities)?;
+            }
+        }
+    }
+
+    debug!("Checking that templates render");
+    let mut failed_templates = 0;
+    for template in &manifest.templates {
+        let _span = info_span!("template", name = %template.name).entered();
+        let known: HashSet<&str> = manifest
+            .secrets
+            .iter()
+            .map(|s| s.name.as_str())
+            .chain(template.inputs.iter().flatten().map(|i| i.name.as_str()))
+            .collect();
+        match validate_template(template, &known) {
+            Ok(unresolved) if unresolved.is_empty() => {}
+            Ok(unresolved) => {
+                warn!(
+                    "Template {} references unknown secrets: {}",
+                    template.name,
+                    unresolved.join(", ")
+                );
+                failed_templates += 1;
+            }
+            Err(e) => {
+                warn!("Template {} is invalid: {:#}", template.name, e);
+                failed_templates += 1;
+            }
+        }
+    }
+    if failed_templates > 0 {
+        return Err(Error::CheckFailed(
+            path.to_string(),
+            format!("{} template(s) can't be rendered", failed_templates),
+        )
+        .into());
+    }
+
     info!("Manifest is valid");
 
     Ok(())
@@ -114,26 +622,841 @@ pub fn check(args: Cli) -> Result<()> {
 pub fn install(args: Cli) -> Result<()> {
     info!("Installing secrets");
 
-    let manifest = load_manifest(&args.manifest)?;
+    let mut manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
+    if let Some(root) = &args.root {
+        apply_root(&mut manifest, root)?;
+    }
+    validate_links(&manifest, args.root.as_deref())?;
+
+    let directory = get_secret_directory(&manifest)?;
+    let directory = Path::new(&directory);
+    let active_link = manifest.active_link_name().to_string();
+
+    let only = match &args.command {
+        Some(Commands::Install { only }) => only.clone(),
+        _ => vec![],
+    };
+    if let Some(name) = only.iter().find(|name| {
+        !manifest.secrets.iter().any(|s| &s.name == *name)
+            && !manifest.templates.iter().any(|t| &t.name == *name)
+    }) {
+        return Err(Error::UnknownSecret(name.clone()).into());
+    }
+
+    if args.copy_all || manifest.copy_all.unwrap_or(false) {
+        debug!("Copying every secret and template");
+        for secret in &mut manifest.secrets {
+            secret.copy = Some(true);
+        }
+        for template in &mut manifest.templates {
+            template.copy = Some(true);
+        }
+    }
+
+    apply_credentials(&mut manifest)?;
+
+    if args.dry_run {
+        return dry_run(&args, directory, &manifest);
+    }
+
+    // Sources are always read when forcing, so a misleading modification time can be corrected
+    let cached_stats = if manifest.trust_mtime.unwrap_or(false) && !args.force {
+        get_source_stats(directory, &active_link)?
+    } else {
+        BTreeMap::new()
+    };
+    let (manifest_hash, source_stats) = hash_manifest(&args, directory, &manifest, &cached_stats)?;
+    debug!("Manifest hash: {}", manifest_hash);
+    if !args.force
+        && only.is_empty()
+        && is_generation_current(directory, &active_link, &manifest_hash)?
+    {
+        info!("No changes since the active generation, skipping");
+        if !args.keep_keyfile {
+            // Earlier versions decrypted through a keyfile, which may have been left behind
+            wipe_keyfile(&directory.join(KEYFILE))?;
+        }
+        record_install(directory, &active_link)?;
+        return Ok(());
+    }
+
+    if args.interactive {
+        print_diff(&args, directory, &manifest)?;
+        let stdin = std::io::stdin();
+        confirm(&args, stdin.lock(), stdin.is_terminal())?;
+    }
+
+    ensure_free_space(directory, &manifest)?;
+
+    if !args.quiet {
+        progress::start((manifest.secrets.len() + manifest.templates.len()) as u64);
+    }
+    let previous_umask = manifest.umask.as_deref().map(set_umask).transpose()?;
+    let result = with_identities(&args, directory, &manifest.ssh_keys, |identities| {
+        activate_new_generation(
+            directory,
+            &active_link,
+            manifest.secrets,
+            manifest.templates,
+            &manifest.defaults,
+            identities,
+            &manifest_hash,
+            &manifest.retry,
+            !args.no_cleanup,
+            &only,
+        )
+    });
+    if let Some(previous_umask) = previous_umask {
+        unsafe { libc::umask(previous_umask) };
+    }
+    progress::finish();
+    result?;
+    // Carried over secrets weren't read, so their sources may not match the recorded stats
+    let source_stats = if only.is_empty() {
+        source_stats
+    } else {
+        BTreeMap::new()
+    };
+    record_source_stats(directory, &active_link, source_stats)?;
+
+    if let Some(user) = manifest
+        .target_user
+        .as_deref()
+        .and_then(users::get_user_by_name)
+    {
+        debug!("Giving {} to {:?}", directory.display(), user.name());
+        std::os::unix::fs::chown(directory, Some(user.uid()), Some(user.primary_group_id()))?;
+    }
+
+    let keep_generations = args
+        .keep_generations
+        .or(manifest.keep_generations)
+        .unwrap_or(0);
+    clean_old_generations(directory, &active_link, keep_generations)?;
+    record_install(directory, &active_link)?;
+
+    Ok(())
+}
+
+pub fn reload(args: Cli) -> Result<()> {
+    let manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
 
     let directory = get_secret_directory(&manifest)?;
     let directory = Path::new(&directory);
 
-    let keyfile = write_ssh_keys(directory, &manifest.ssh_keys[..])?;
-    let keyfile = keyfile.to_str();
+    let Some(secret_files) = get_active_secret_files(directory, manifest.active_link_name())?
+    else {
+        return Err(Error::NoActiveGeneration.into());
+    };
+    debug!("Active secret files: {:?}", secret_files);
+
+    let secret_units = manifest
+        .secrets
+        .iter()
+        .filter(|s| s.link.as_ref().is_some_and(|l| secret_files.contains(l)))
+        .map(|s| (&s.restart_units, &s.reload_units));
+    let template_units = manifest
+        .templates
+        .iter()
+        .filter(|t| secret_files.contains(&t.destination))
+        .map(|t| (&t.restart_units, &t.reload_units));
+
+    let mut restart_units = vec![];
+    let mut reload_units = vec![];
+    for (restart, reload) in secret_units.chain(template_units) {
+        for unit in restart.iter().flatten() {
+            if !restart_units.contains(unit) {
+                restart_units.push(unit.clone());
+            }
+        }
+        for unit in reload.iter().flatten() {
+            if !reload_units.contains(unit) {
+                reload_units.push(unit.clone());
+            }
+        }
+    }
+    // Units that are restarted don't need to be reloaded as well
+    reload_units.retain(|u| !restart_units.contains(u));
+
+    run_systemctl("restart", restart_units)?;
+    run_systemctl("reload", reload_units)?;
+
+    Ok(())
+}
+
+fn run_systemctl(action: &'static str, units: Vec<String>) -> Result<()> {
+    if units.is_empty() {
+        return Ok(());
+    }
+    info!("Running {} on units {:?}", action, units);
+
+    let mut command = std::process::Command::new("systemctl");
+    if users::get_current_uid() != 0 {
+        command.arg("--user");
+    }
+    let status = command.arg(action).args(&units).status()?;
+    if !status.success() {
+        return Err(Error::UnitsFailed(action, units).into());
+    }
+    Ok(())
+}
+
+pub fn rekey(args: &Cli, source: &str, add: &[String], remove: &[String]) -> Result<()> {
+    let manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
+    let directory = get_secret_directory(&manifest)?;
+
+    // The manifest knows where the metadata of sources that keep it elsewhere is
+    let inputs = manifest
+        .templates
+        .iter()
+        .flat_map(|t| t.inputs.iter().flatten());
+    let sops_path = manifest
+        .secrets
+        .iter()
+        .map(|s| (&s.source, &s.sops_path))
+        .chain(inputs.map(|i| (&i.source, &i.sops_path)))
+        .find(|(s, _)| *s == source)
+        .and_then(|(_, sops_path)| sops_path.as_deref());
+
+    info!("Rekeying {}", source);
+    with_identities(
+        args,
+        Path::new(&directory),
+        &manifest.ssh_keys,
+        |identities| rekey_sops_file(source, sops_path, identities, add, remove),
+    )
+}
+
+pub fn which_key(args: &Cli, source: &str) -> Result<()> {
+    let manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
+    let sops_file = load_sops_file(source, None)?;
+
+    let keys: Vec<(&String, String)> = manifest
+        .ssh_keys
+        .iter()
+        .flat_map(|key| {
+            let recipients =
+                key_recipients(key, args.passphrase_file.as_deref()).unwrap_or_default();
+            recipients
+                .into_iter()
+                .map(move |recipient| (key, recipient))
+        })
+        .collect();
+    let recipients: Vec<String> = keys.iter().map(|(_, r)| r.clone()).collect();
+
+    let Some(candidate) = find_candidate(sops_file.sops_metadata(), &recipients) else {
+        return Err(anyhow!("No configured identity can decrypt {}", source));
+    };
+    let recipient = normalize_recipient(&candidate.recipient);
+    for (key, _) in keys.iter().filter(|(_, r)| r == &recipient) {
+        println!("{} decrypts {} as {}", key, source, candidate.recipient);
+    }
+    Ok(())
+}
+
+/// A creation rule in `.sops.yaml`
+#[derive(Serialize)]
+struct CreationRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_regex: Option<String>,
+    age: String,
+}
+
+pub fn schema() -> Result<()> {
+    let schema = schemars::schema_for!(SecnixManifest);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+pub fn convert_key(key: &str, sops_rule: bool, path_regex: Option<String>) -> Result<()> {
+    let public_key =
+        read_public_key(key).ok_or_else(|| anyhow!("Could not read the public key of {}", key))?;
+    let recipient = recipient_only(&public_key)?;
+
+    if sops_rule {
+        // A list entry that can be appended to the creation_rules of an existing .sops.yaml
+        let rule = vec![CreationRule {
+            path_regex,
+            age: recipient,
+        }];
+        print!("{}", serde_yaml::to_string(&rule)?);
+    } else {
+        println!("{}", recipient);
+    }
+    Ok(())
+}
+
+/// The output of `list --json`
+#[derive(Serialize)]
+struct ListSummary {
+    /// The unix timestamp of the last successful install
+    last_install: Option<u64>,
+    generations: Vec<GenerationInfo>,
+}
+
+pub fn list(
+    manifest: &str,
+    target_user: Option<&str>,
+    basedir: Option<&str>,
+    since: Option<Duration>,
+    limit: Option<usize>,
+    json: bool,
+) -> Result<()> {
+    let manifest = load_manifest(manifest, target_user, basedir)?;
+    let directory = get_secret_directory(&manifest)?;
+
+    let since = since
+        .map(|since| -> Result<u64> {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+            Ok(now.saturating_sub(since).as_secs())
+        })
+        .transpose()?;
+    let generations = list_generations(
+        Path::new(&directory),
+        manifest.active_link_name(),
+        since,
+        limit,
+    )?;
+    let last_install = get_last_install(Path::new(&directory), manifest.active_link_name())?;
+
+    if json {
+        let summary = ListSummary {
+            last_install,
+            generations,
+        };
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+    for generation in generations {
+        println!(
+            "{} {}{}",
+            generation.id,
+            generation.deployed_at,
+            if generation.active { " (active)" } else { "" }
+        );
+    }
+    if let Some(last_install) = last_install {
+        println!("Last install: {}", last_install);
+    }
+    Ok(())
+}
+
+pub fn history(
+    manifest: &str,
+    target_user: Option<&str>,
+    basedir: Option<&str>,
+    name: &str,
+    json: bool,
+) -> Result<()> {
+    let manifest = load_manifest(manifest, target_user, basedir)?;
+    let directory = get_secret_directory(&manifest)?;
+    if !manifest.secrets.iter().any(|s| s.name == name)
+        && !manifest.templates.iter().any(|t| t.name == name)
+    {
+        return Err(Error::UnknownSecret(name.to_string()).into());
+    }
+
+    let changes = secret_history(Path::new(&directory), manifest.active_link_name(), name)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+        return Ok(());
+    }
+    for change in changes {
+        println!("{} {}", change.generation, change.deployed_at);
+    }
+    Ok(())
+}
+
+pub fn activate(args: &Cli, generation: &str) -> Result<()> {
+    let manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
+    let directory = get_secret_directory(&manifest)?;
+
+    activate_generation(
+        Path::new(&directory),
+        manifest.active_link_name(),
+        generation,
+        &manifest.retry,
+        !args.no_cleanup,
+    )?;
+    info!("Activated generation {}", generation);
+    Ok(())
+}
+
+pub fn export(
+    manifest: &str,
+    target_user: Option<&str>,
+    basedir: Option<&str>,
+    generation: &str,
+    output: &str,
+) -> Result<()> {
+    let manifest = load_manifest(manifest, target_user, basedir)?;
+    let directory = get_secret_directory(&manifest)?;
+
+    info!("Exporting generation {} to {}", generation, output);
+    export_generation(Path::new(&directory), generation, Path::new(output))
+}
+
+pub fn import(
+    manifest: &str,
+    target_user: Option<&str>,
+    basedir: Option<&str>,
+    archive: &str,
+) -> Result<()> {
+    let manifest = load_manifest(manifest, target_user, basedir)?;
+    let directory = get_secret_directory(&manifest)?;
+
+    info!("Importing generation from {}", archive);
+    let generation = import_generation(
+        Path::new(&directory),
+        manifest.active_link_name(),
+        Path::new(archive),
+    )?;
+    info!("Imported generation {}", generation);
+    Ok(())
+}
+
+/// Prints the results of environment checks, keeping track of any failures
+#[derive(Default)]
+struct Doctor {
+    failures: usize,
+}
+
+impl Doctor {
+    fn pass(&self, message: impl AsRef<str>) {
+        println!("[PASS] {}", message.as_ref());
+    }
+
+    fn warn(&self, message: impl AsRef<str>) {
+        println!("[WARN] {}", message.as_ref());
+    }
+
+    fn fail(&mut self, message: impl AsRef<str>) {
+        println!("[FAIL] {}", message.as_ref());
+        self.failures += 1;
+    }
+}
+
+pub fn doctor(args: Cli) -> Result<()> {
+    info!("Diagnosing environment for manifest {}", args.manifest);
+    let manifest = load_manifest(
+        &args.manifest,
+        args.target_user.as_deref(),
+        args.basedir.as_deref(),
+    )?;
+    let mut doctor = Doctor::default();
+
+    match ssh::self_test() {
+        Ok(()) => doctor.pass("SSH keys are converted to the known-good age keys"),
+        Err(e) => doctor.fail(format!("SSH key conversion is broken: {}", e)),
+    }
+
+    let mut recipients = vec![];
+    for key in &manifest.ssh_keys {
+        if key.strip_prefix(ENV_KEY_PREFIX).is_none() {
+            let path = shellexpand::tilde(key);
+            match std::fs::metadata(path.as_ref()) {
+                Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => doctor.warn(format!(
+                    "SSH key {} is accessible by other users (mode {:o})",
+                    key,
+                    metadata.permissions().mode() & 0o777
+                )),
+                Ok(_) => {}
+                Err(e) => {
+                    doctor.fail(format!("SSH key {} can't be read: {}", key, e));
+                    continue;
+                }
+            }
+        }
+
+        match key_recipients(key, args.passphrase_file.as_deref()) {
+            Ok(derived) => {
+                for recipient in derived {
+                    doctor.pass(format!("SSH key {} maps to recipient {}", key, recipient));
+                    recipients.push(recipient);
+                }
+            }
+            Err(e) => doctor.fail(format!("SSH key {} can't be used: {:#}", key, e)),
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    for file in &manifest.secrets {
+        let source = &file.source;
+        if !seen.insert((source, &file.sops_path)) {
+            continue;
+        }
+        match file.load() {
+            Ok(sops_file) => {
+                let metadata = sops_file.sops_metadata();
+                if find_candidate(metadata, &recipients).is_some() {
+                    doctor.pass(format!("{} can be decrypted by a configured key", source));
+                } else {
+                    doctor.fail(format!(
+                        "{} can't be decrypted by any configured key",
+                        source
+                    ));
+                }
+            }
+            Err(e) => doctor.fail(format!("{} can't be loaded: {}", source, e)),
+        }
+    }
+
+    match get_secret_directory(&manifest) {
+        Ok(directory) => {
+            doctor.pass(format!("Secret directory resolves to {}", directory));
+            check_secret_directory(&mut doctor, Path::new(&directory));
+        }
+        Err(e) => doctor.fail(format!("Secret directory can't be resolved: {}", e)),
+    }
+
+    if doctor.failures > 0 {
+        Err(Error::DoctorFailed(doctor.failures).into())
+    } else {
+        info!("No problems found");
+        Ok(())
+    }
+}
+
+/// Checks that the secret directory's filesystem has room for the estimated size of a generation
+fn ensure_free_space(directory: &Path, manifest: &SecnixManifest) -> Result<()> {
+    let mut needed = 0;
+    for secret in &manifest.secrets {
+        needed += secret.estimated_size()?;
+    }
+    for template in &manifest.templates {
+        needed += read_source(&template.source)?.len() as u64;
+    }
+
+    let Some(existing) = directory.ancestors().find(|p| p.exists()) else {
+        return Ok(());
+    };
+    let path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        warn!(
+            "Could not determine the free space in {}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    debug!(
+        "{} bytes are needed and {} are available in {}",
+        needed,
+        available,
+        existing.display()
+    );
+
+    if needed > available {
+        return Err(
+            Error::InsufficientSpace(existing.display().to_string(), needed, available).into(),
+        );
+    }
+    Ok(())
+}
+
+fn check_secret_directory(doctor: &mut Doctor, directory: &Path) {
+    // The directory may not exist until the first install, so check the closest existing ancestor
+    let Some(existing) = directory.ancestors().find(|p| p.exists()) else {
+        doctor.fail(format!("No parent of {} exists", directory.display()));
+        return;
+    };
+    let Ok(path) = CString::new(existing.as_os_str().as_bytes()) else {
+        doctor.fail(format!("{} is not a valid path", existing.display()));
+        return;
+    };
+
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0 {
+        doctor.pass(format!("{} is writable", existing.display()));
+    } else {
+        doctor.fail(format!("{} is not writable", existing.display()));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // libc only exports the tmpfs magic, so the ramfs one comes from linux/magic.h
+        const RAMFS_MAGIC: libc::c_long = 0x858458f6;
+
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+            doctor.warn(format!(
+                "Could not determine the filesystem of {}",
+                existing.display()
+            ));
+        } else if stat.f_type == libc::TMPFS_MAGIC || stat.f_type == RAMFS_MAGIC {
+            doctor.pass(format!("{} is on a tmpfs", existing.display()));
+        } else {
+            doctor.warn(format!(
+                "{} is not on a tmpfs, secrets may be persisted to disk",
+                existing.display()
+            ));
+        }
+    }
+}
+
+/// Moves every output path under the root and resolves owners and groups to the ids they have in
+/// the root's `/etc/passwd` and `/etc/group`
+fn apply_root(manifest: &mut SecnixManifest, root: &Path) -> Result<()> {
+    debug!("Installing into root {}", root.display());
+    let prefix = |path: &str| {
+        root.join(path.trim_start_matches('/'))
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    manifest.secret_directory = prefix(&get_secret_directory(manifest)?);
+    for secret in &mut manifest.secrets {
+        if let Some(link) = &secret.link {
+            secret.link = Some(prefix(link));
+        }
+        resolve_owner_in_root(root, &mut secret.owner, &mut secret.group)?;
+    }
+    for template in &mut manifest.templates {
+        template.destination = prefix(&template.destination);
+        resolve_owner_in_root(root, &mut template.owner, &mut template.group)?;
+    }
+    let defaults = &mut manifest.defaults;
+    resolve_owner_in_root(
+        root,
+        &mut defaults.default_owner,
+        &mut defaults.default_group,
+    )
+}
+
+/// Checks that a secret can be written as a systemd credential
+fn check_credential(manifest: &SecnixManifest, file: &SecretFile) -> Result<()> {
+    let problem = if manifest.credentials_directory.is_none() {
+        Some("Credentials need a credentials_directory")
+    } else if file.is_wildcard() {
+        Some("Secrets with the `*` key can't be credentials")
+    } else if file.fifo.unwrap_or(false) {
+        Some("Secrets served through a pipe can't be credentials")
+    } else if file.name.contains('/') || file.name.len() > 255 {
+        Some("The name of a credential has to be a valid file name")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => Err(Error::CheckFailed(file.name.clone(), problem.to_string()).into()),
+        None => Ok(()),
+    }
+}
+
+/// Points secrets that are systemd credentials at their file in the credentials directory. They
+/// are copied there read-only, so services can load them by the secret's name.
+fn apply_credentials(manifest: &mut SecnixManifest) -> Result<()> {
+    for secret in &mut manifest.secrets {
+        if !secret.credential.unwrap_or(false) {
+            continue;
+        }
+        let Some(directory) = &manifest.credentials_directory else {
+            return Err(Error::CheckFailed(
+                secret.name.clone(),
+                "Credentials need a credentials_directory".to_string(),
+            )
+            .into());
+        };
+        let link = Path::new(directory).join(&secret.name);
+        debug!(
+            "Writing {} as the credential {}",
+            secret.name,
+            link.display()
+        );
+        secret.link = Some(link.to_string_lossy().into_owned());
+        secret.copy = Some(true);
+        secret.mode = Some("0400".to_string());
+    }
+    Ok(())
+}
+
+/// Checks that every secret link and template destination can be created safely
+fn validate_links(manifest: &SecnixManifest, root: Option<&Path>) -> Result<()> {
+    let links = manifest.secrets.iter().filter_map(|s| s.link.as_deref());
+    let destinations = manifest.templates.iter().map(|t| t.destination.as_str());
+    for link in links.chain(destinations) {
+        validate_link_path(Path::new(link), root)
+            .map_err(|e| Error::CheckFailed(link.to_string(), format!("{:#}", e)))?;
+    }
+    Ok(())
+}
+
+/// Replaces an owner and group with their numeric ids in the root. Numeric ids are kept as-is and
+/// names that aren't found are dropped so they aren't resolved against this system instead.
+fn resolve_owner_in_root(
+    root: &Path,
+    owner: &mut Option<String>,
+    group: &mut Option<String>,
+) -> Result<()> {
+    let (user, user_group) = split_owner(owner.as_deref(), group.as_deref());
+    let uid = user
+        .map(|u| resolve_id_in_root(&root.join("etc/passwd"), u))
+        .transpose()?
+        .flatten();
+    let gid = user_group
+        .map(|g| resolve_id_in_root(&root.join("etc/group"), g))
+        .transpose()?
+        .flatten();
+    *owner = uid;
+    *group = gid;
+    Ok(())
+}
 
-    if let Some(keyfile) = keyfile {
-        activate_new_generation(directory, manifest.secrets, manifest.templates, keyfile)?;
+fn resolve_id_in_root(file: &Path, name: &str) -> Result<Option<String>> {
+    if name.parse::<u32>().is_ok() {
+        return Ok(Some(name.to_string()));
+    }
+    match lookup_id(file, name)? {
+        Some(id) => Ok(Some(id.to_string())),
+        None => {
+            warn!("{} was not found in {}", name, file.display());
+            Ok(None)
+        }
+    }
+}
+
+/// Fields of secrets and templates that are redundant when set to these values
+const DEFAULTED_FIELDS: &[(&str, bool)] = &[
+    ("subtree", false),
+    ("copy", false),
+    ("preserve_xattrs", false),
+    ("fifo", false),
+    ("verify_mac", true),
+    ("immutable", false),
+    ("credential", false),
+];
+
+/// Rewrites the manifest with canonical modes, secrets and templates sorted by name, and defaulted
+/// fields removed, printing a diff of what changed
+fn fix_manifest(path: &str) -> Result<()> {
+    let expanded = shellexpand::tilde(path);
+    let manifest_path = Path::new(expanded.as_ref());
+    let original = std::fs::read_to_string(manifest_path)?;
+    let is_yaml = manifest_path
+        .extension()
+        .is_some_and(|e| e == "yaml" || e == "yml");
+    if is_yaml && has_yaml_comments(&original) {
+        return Err(Error::CommentedManifest(path.to_string()).into());
+    }
+    let mut manifest: serde_yaml::Value = serde_yaml::from_str(&original)?;
+
+    strip_defaulted(&mut manifest);
+    canonicalize_mode(&mut manifest, "default_mode")?;
+    for list in ["secrets", "templates"] {
+        let Some(serde_yaml::Value::Sequence(entries)) = manifest.get_mut(list) else {
+            continue;
+        };
+        for entry in entries.iter_mut() {
+            strip_defaulted(entry);
+            canonicalize_mode(entry, "mode")?;
+        }
+        entries.sort_by(|a, b| {
+            let name =
+                |v: &serde_yaml::Value| v.get("name").and_then(|n| n.as_str()).map(String::from);
+            name(a).cmp(&name(b))
+        });
+    }
+
+    let fixed = if is_yaml {
+        serde_yaml::to_string(&manifest)?
     } else {
-        return Err(anyhow!("Failed to convert keyfile path to string"));
+        serde_json::to_string_pretty(&manifest)? + "\n"
+    };
+    if fixed == original {
+        debug!("{} is already canonical", path);
+        return Ok(());
     }
 
-    clean_old_generations(directory, 1)?;
+    print!(
+        "{}",
+        similar::TextDiff::from_lines(&original, &fixed)
+            .unified_diff()
+            .header(path, path)
+    );
+    // Writing a copy and renaming it over the manifest never leaves it half written
+    let temp = crate::fs::temp_path(manifest_path);
+    let result = std::fs::write(&temp, fixed)
+        .and_then(|()| {
+            std::fs::set_permissions(&temp, std::fs::metadata(manifest_path)?.permissions())
+        })
+        .and_then(|()| std::fs::rename(&temp, manifest_path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result?;
+    info!("Rewrote {}", path);
+    Ok(())
+}
+
+/// Checks if a YAML document has comments, which are a `#` at the start of a line or after
+/// whitespace outside of quotes
+fn has_yaml_comments(document: &str) -> bool {
+    document.lines().any(|line| {
+        let mut quote = None;
+        let mut previous = ' ';
+        for c in line.chars() {
+            match (quote, c) {
+                (None, '#') if previous.is_whitespace() => return true,
+                (None, '\'' | '"') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                _ => {}
+            }
+            previous = c;
+        }
+        false
+    })
+}
+
+/// Removes null fields and fields set to their default value
+fn strip_defaulted(value: &mut serde_yaml::Value) {
+    if let serde_yaml::Value::Mapping(m) = value {
+        m.retain(|k, v| {
+            !v.is_null()
+                && !DEFAULTED_FIELDS.iter().any(|(field, default)| {
+                    k.as_str() == Some(*field) && v.as_bool() == Some(*default)
+                })
+        });
+    }
+}
 
+/// Rewrites a mode as octal with a leading zero
+fn canonicalize_mode(value: &mut serde_yaml::Value, field: &str) -> Result<()> {
+    let Some(mode) = value.get_mut(field) else {
+        return Ok(());
+    };
+    let raw = match mode {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        _ => return Ok(()),
+    };
+    let parsed = u32::from_str_radix(&raw, 8).map_err(|_| anyhow!("Invalid mode: {}", raw))?;
+    *mode = serde_yaml::Value::String(format!("0{:o}", parsed));
     Ok(())
 }
 
-fn load_manifest(path: &str) -> Result<SecnixManifest> {
+/// Reads the manifest without resolving any specifiers in its paths
+fn read_manifest(path: &str) -> Result<SecnixManifest> {
     let manifest = shellexpand::tilde(path);
     let path = Path::new(manifest.as_ref());
     let manifest = SecnixManifest::new(path)?;
@@ -145,14 +1468,394 @@ fn load_manifest(path: &str) -> Result<SecnixManifest> {
     }
 }
 
-fn write_ssh_keys(directory: &Path, keys: &[String]) -> Result<PathBuf> {
+/// Reads the manifest and resolves the specifiers in secret links and template destinations. The
+/// secret directory is replaced by `basedir` if one is provided, otherwise by SECNIX_BASEDIR.
+fn load_manifest(
+    path: &str,
+    target_user: Option<&str>,
+    basedir: Option<&str>,
+) -> Result<SecnixManifest> {
+    let mut manifest = read_manifest(path)?;
+    if let Some(basedir) = basedir {
+        debug!("Using the secret directory {} from --basedir", basedir);
+        manifest.secret_directory = basedir.to_string();
+    } else if let Ok(basedir) = std::env::var(BASEDIR_VARIABLE) {
+        debug!(
+            "Using the secret directory {} from the environment",
+            basedir
+        );
+        manifest.secret_directory = basedir;
+    }
+    if let Some(target_user) = target_user {
+        manifest.target_user = Some(target_user.to_string());
+    }
+    let target_user = manifest.target_user.clone();
+    let target_user = target_user.as_deref();
+
+    for secret in &mut manifest.secrets {
+        if let Some(link) = &secret.link {
+            secret.link = Some(substitute_specifiers(link, target_user)?);
+        }
+    }
+    for template in &mut manifest.templates {
+        template.destination = substitute_specifiers(&template.destination, target_user)?;
+    }
+    if let Some(target_user) = target_user {
+        // Files belong to the target user unless the manifest says otherwise
+        let defaults = &mut manifest.defaults;
+        if defaults.default_owner.is_none() {
+            defaults.default_owner = Some(target_user.to_string());
+        }
+        if defaults.default_group.is_none() {
+            defaults.default_group = users::get_user_by_name(target_user)
+                .map(|user| user.primary_group_id().to_string());
+        }
+    }
+    Ok(manifest)
+}
+
+/// Hashes the resolved manifest, the options that change what it deploys and every secret and
+/// template source it references, returning the stats of the local sources. A local source whose
+/// modification time and size match its entry in `cached` isn't read, its recorded hash is used
+/// instead.
+///
+/// The manifest is hashed after its specifiers, root, target user, `--copy-all` and credentials
+/// are applied, so the same file deployed differently doesn't count as unchanged.
+fn hash_manifest(
+    args: &Cli,
+    directory: &Path,
+    manifest: &SecnixManifest,
+    cached: &BTreeMap<String, SourceStat>,
+) -> Result<(String, BTreeMap<String, SourceStat>)> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(manifest)?);
+    hasher.update(directory.as_os_str().as_bytes());
+    hasher.update([args.lenient_types as u8]);
+
+    // Binary sources aren't limited to the maximum source size
+    let secret_sources = manifest
+        .secrets
+        .iter()
+        .map(|s| (&s.source, s.file_type == FileType::Binary));
+    let template_sources = manifest.templates.iter().map(|t| (&t.source, false));
+    let input_sources = manifest
+        .templates
+        .iter()
+        .flat_map(|t| t.inputs.iter().flatten())
+        .map(|i| (&i.source, false));
+
+    let mut stats = BTreeMap::new();
+    for (source, large) in secret_sources.chain(template_sources).chain(input_sources) {
+        let read = |source: &str| {
+            if large {
+                read_large_source(source)
+            } else {
+                read_source(source)
+            }
+        };
+        hasher.update(source.as_bytes());
+        if is_remote(source) {
+            hasher.update(read(source)?);
+            continue;
+        }
+
+        let metadata = std::fs::metadata(source)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let size = metadata.len();
+        let hash = match cached.get(source) {
+            Some(stat) if stat.modified == modified && stat.size == size => {
+                debug!("{} is unchanged, reusing its hash", source);
+                stat.hash.clone()
+            }
+            _ => format!("{:x}", Sha256::digest(read(source)?)),
+        };
+        hasher.update(hash.as_bytes());
+        stats.insert(
+            source.clone(),
+            SourceStat {
+                modified,
+                size,
+                hash,
+            },
+        );
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), stats))
+}
+
+/// Derives the age recipients of the provided ssh keys and age identity files. Keys that can't be
+/// read are skipped.
+fn read_recipients(keys: &[String], passphrase_file: Option<&Path>) -> Vec<String> {
+    keys.iter()
+        .filter_map(|key| match key_recipients(key, passphrase_file) {
+            Ok(recipients) => Some(recipients),
+            Err(e) => {
+                debug!("Could not derive a recipient for {}: {:#}", key, e);
+                None
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+fn read_public_key(key: &str) -> Option<ssh_key::PublicKey> {
+    if key.strip_prefix(ENV_KEY_PREFIX).is_none() {
+        let public_key = std::fs::read_to_string(format!("{}.pub", shellexpand::tilde(key)))
+            .ok()
+            .and_then(|data| {
+                // Certificates are signed over a plain public key, which is what recipients use
+                if is_certificate(&data) {
+                    let certificate = ssh_key::Certificate::from_openssh(&data).ok()?;
+                    Some(ssh_key::PublicKey::from(certificate.public_key().clone()))
+                } else {
+                    ssh_key::PublicKey::from_openssh(&data).ok()
+                }
+            });
+        if public_key.is_some() {
+            return public_key;
+        }
+    }
+    let data = read_ssh_key(key).ok()?;
+    let private_key = parse_private_key(&data).ok()?;
+    Some(private_key.public_key().clone())
+}
+
+/// Reads the private key material of an ssh key entry, which is either a path or the name of an
+/// environment variable holding the key prefixed with `env:`
+fn read_ssh_key(key: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if let Some(var) = key.strip_prefix(ENV_KEY_PREFIX) {
+        let data = Zeroizing::new(
+            std::env::var(var).map_err(|_| Error::MissingKeyVariable(var.to_string()))?,
+        );
+        if data.trim().is_empty() {
+            return Err(Error::EmptyKeyVariable(var.to_string()).into());
+        }
+        Ok(Zeroizing::new(data.as_bytes().to_vec()))
+    } else {
+        let path = shellexpand::tilde(key);
+        let data =
+            std::fs::read(path.as_ref()).map_err(|e| Error::UnreadableKey(key.to_string(), e))?;
+        Ok(Zeroizing::new(data))
+    }
+}
+
+/// Prints the files an install would deploy, optionally comparing them to the deployed files
+fn dry_run(args: &Cli, directory: &Path, manifest: &SecnixManifest) -> Result<()> {
+    if !args.diff {
+        let links = manifest.secrets.iter().filter_map(|s| s.link.as_ref());
+        let destinations = manifest.templates.iter().map(|t| &t.destination);
+        for path in links.chain(destinations) {
+            println!("{}", path);
+        }
+        return Ok(());
+    }
+    print_diff(args, directory, manifest)
+}
+
+/// Prints how the files a generation would deploy differ from the deployed ones
+fn print_diff(args: &Cli, directory: &Path, manifest: &SecnixManifest) -> Result<()> {
+    let planned = with_identities(args, directory, &manifest.ssh_keys, |identities| {
+        plan_generation(&manifest.secrets, &manifest.templates, identities)
+    })?;
+    for file in planned {
+        let Ok(current) = std::fs::read(&file.path).map(Zeroizing::new) else {
+            println!("{}: new, {} bytes", file.path, file.contents.len());
+            continue;
+        };
+        if current == file.contents {
+            println!("{}: unchanged", file.path);
+            continue;
+        }
+        match (
+            std::str::from_utf8(&current),
+            std::str::from_utf8(&file.contents),
+        ) {
+            (Ok(current), Ok(proposed)) if args.show_secrets => print!(
+                "{}",
+                similar::TextDiff::from_lines(current, proposed)
+                    .unified_diff()
+                    .header(&file.path, &file.path)
+            ),
+            (Ok(_), Ok(_)) => println!(
+                "{}: content changed, {} bytes",
+                file.path,
+                file.contents.len()
+            ),
+            _ => println!("{}: binary differs", file.path),
+        }
+    }
+    Ok(())
+}
+
+/// Asks for confirmation on the input, unless it was already given with --yes. Anything other than
+/// yes declines, and so does an input that isn't a terminal.
+fn confirm(args: &Cli, mut input: impl BufRead, is_terminal: bool) -> Result<()> {
+    if args.yes {
+        return Ok(());
+    }
+    if !is_terminal {
+        return Err(
+            Error::NotConfirmed(", stdin is not a terminal and --yes was not passed").into(),
+        );
+    }
+
+    print!("Install these changes? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Error::NotConfirmed("").into()),
+    }
+}
+
+/// Derives the age identities from the ssh keys and passes them to `f`. The identities are only
+/// held in memory unless the keyfile should be kept for debugging.
+fn with_identities<T>(
+    args: &Cli,
+    directory: &Path,
+    keys: &[String],
+    f: impl FnOnce(&Identities) -> Result<T>,
+) -> Result<T> {
+    if args.keep_keyfile {
+        write_ssh_keys(directory, keys, args.passphrase_file.as_deref())?;
+        warn!(
+            "Keeping the keyfile at {}",
+            directory.join(KEYFILE).display()
+        );
+    } else {
+        // Earlier versions decrypted through a keyfile, which may have been left behind
+        wipe_keyfile(&directory.join(KEYFILE))?;
+    }
+    let identities = prepare_identities(keys, decrypt_options(args))?;
+    f(&identities)
+}
+
+/// Gets the options secrets are decrypted with from the flags
+fn decrypt_options(args: &Cli) -> DecryptOptions {
+    DecryptOptions {
+        lenient_types: args.lenient_types,
+        passphrase_file: args.passphrase_file.clone(),
+    }
+}
+
+/// Verifies the MAC of a source, only warning that it is unverified if none of the identities can
+/// decrypt its data key
+fn check_mac(source: &str, file: &dyn SopsFile, identities: &Identities) -> Result<()> {
+    match file.verify_mac(identities) {
+        Ok(()) => Ok(()),
+        Err(e) if e.is::<DecryptionError>() => {
+            warn!("The MAC of {} is unverified: {:#}", source, e);
+            Ok(())
+        }
+        Err(e) => Err(Error::CheckFailed(source.to_string(), format!("{:#}", e)).into()),
+    }
+}
+
+/// Derives the age identities of the ssh keys without writing them to disk, so they can be
+/// reused to decrypt any number of files
+pub fn prepare_identities(keys: &[String], options: DecryptOptions) -> Result<Identities> {
+    let mut identities = vec![];
+    for key in keys {
+        identities.extend(key_identities(key, &options)?);
+    }
+    Ok(Identities::with_options(identities, options))
+}
+
+/// Derives the age identities of one ssh_keys entry
+fn key_identities(key: &str, options: &DecryptOptions) -> Result<Vec<age::x25519::Identity>> {
+    info!("Importing key: {}", key);
+    let mut identities = vec![];
+    for age_key in derive_age_keys(key, options.passphrase_file.as_deref())? {
+        debug!("Derived public key {}", age_key.public_key);
+        let identity = age_key
+            .private_key
+            .parse()
+            .map_err(|e| anyhow!("Invalid identity derived from {}: {}", key, e))?;
+        identities.push(identity);
+    }
+    Ok(identities)
+}
+
+/// Reads the age keys of an ssh_keys entry. Ssh keys are converted, while the identities of age
+/// identity files are used as they are. Encrypted ssh keys are unlocked with the passphrase file
+/// if one is provided.
+fn derive_age_keys(key: &str, passphrase_file: Option<&Path>) -> Result<Vec<AgeKey>> {
+    let data = read_ssh_key(key)?;
+    if is_identity_file(&data) {
+        debug!("{} is an age identity file", key);
+        let identities = parse_identities(std::str::from_utf8(&data)?, key)?;
+        return Ok(identities
+            .into_iter()
+            .map(|identity| AgeKey {
+                public_key: identity.to_public().to_string(),
+                private_key: Zeroizing::new(identity.to_string().expose_secret().clone()),
+            })
+            .collect());
+    }
+    let mut private_key = parse_private_key(&data).map_err(|e| {
+        anyhow!(
+            "{} is neither an ssh key nor an age identity file: {}",
+            key,
+            e
+        )
+    })?;
+    if private_key.is_encrypted() {
+        debug!("Decrypting {}", key);
+        private_key = ssh::decrypt_private_key(key, &private_key, passphrase_file)?;
+    }
+    Ok(vec![private_key.try_into()?])
+}
+
+/// Derives the age recipients of an ssh_keys entry. For ssh keys the adjacent public key is
+/// preferred so the private key is never read, age identity files have to be read in full.
+fn key_recipients(key: &str, passphrase_file: Option<&Path>) -> Result<Vec<String>> {
+    if let Some(public_key) = read_public_key(key) {
+        return Ok(vec![recipient_only(&public_key)?]);
+    }
+    Ok(derive_age_keys(key, passphrase_file)?
+        .into_iter()
+        .map(|age_key| age_key.public_key)
+        .collect())
+}
+
+/// Sets the process umask from an octal string, returning the previous umask
+fn set_umask(umask: &str) -> Result<libc::mode_t> {
+    let umask =
+        libc::mode_t::from_str_radix(umask, 8).map_err(|_| anyhow!("Invalid umask: {}", umask))?;
+    debug!("Setting umask to {:o}", umask);
+    Ok(unsafe { libc::umask(umask) })
+}
+
+/// Overwrites the keyfile with zeros before removing it
+fn wipe_keyfile(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    debug!("Wiping keyfile {}", path.display());
+    let len = std::fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0; len as usize])?;
+    file.sync_all()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+fn write_ssh_keys(
+    directory: &Path,
+    keys: &[String],
+    passphrase_file: Option<&Path>,
+) -> Result<PathBuf> {
     // Ensure the directory exists
     if !directory.exists() {
         debug!("Creating directory {}", directory.display());
         std::fs::create_dir_all(directory)?;
     }
 
-    let path = directory.join("keys.txt");
+    let path = directory.join(KEYFILE);
     debug!("Writing ssh keys to {}", path.display());
 
     if path.exists() {
@@ -168,14 +1871,11 @@ fn write_ssh_keys(directory: &Path, keys: &[String]) -> Result<PathBuf> {
         .open(&path)?;
     let mut buffer = std::io::BufWriter::new(file);
     for key in keys {
-        let key = shellexpand::tilde(key);
-        info!("Importing key: {}", key);
-        let data = std::fs::read(key.into_owned())?;
-        let private_key = ssh_key::PrivateKey::from_openssh(data)?;
-        let age_key: AgeKey = private_key.try_into()?;
-        debug!("Writing public key {}", age_key.public_key);
-        writeln!(buffer, "# {}", age_key.public_key)?;
-        writeln!(buffer, "{}", age_key.private_key)?;
+        for age_key in derive_age_keys(key, passphrase_file)? {
+            debug!("Writing public key {}", age_key.public_key);
+            writeln!(buffer, "# {}", age_key.public_key)?;
+            writeln!(buffer, "{}", age_key.private_key.as_str())?;
+        }
     }
     debug!("Wrote age key to {}", path.display());
     buffer.flush()?;
@@ -183,9 +1883,42 @@ fn write_ssh_keys(directory: &Path, keys: &[String]) -> Result<PathBuf> {
     Ok(path)
 }
 
+/// Resolves the specifiers in a path for another user
+fn substitute_target_specifiers(value: &str, target_user: &str) -> Result<String> {
+    let user = users::get_user_by_name(target_user)
+        .ok_or_else(|| Error::UnknownTargetUser(target_user.to_string()))?;
+
+    let mut value = value.to_string();
+    if value.contains("%r") {
+        let runtime_directory = format!("/run/user/{}", user.uid());
+        if !Path::new(&runtime_directory).is_dir() {
+            return Err(
+                Error::MissingRuntimeDirectory(runtime_directory, target_user.to_string()).into(),
+            );
+        }
+        value = value.replace("%r", &runtime_directory);
+    }
+    value = value.replace("%u", target_user);
+    if value.contains("%h") {
+        value = value.replace("%h", &user.home_dir().to_string_lossy());
+    }
+    debug!("Resolved path for {}: {}", target_user, value);
+    Ok(value)
+}
+
 fn get_secret_directory(manifest: &SecnixManifest) -> Result<String> {
-    let basedir = manifest.secret_directory.as_str();
-    if basedir.contains("%r") {
+    substitute_specifiers(&manifest.secret_directory, manifest.target_user.as_deref())
+}
+
+/// Replaces `%r` with the runtime directory, `%u` with the current user and `%h` with their home
+/// directory. If a target user is provided, the specifiers are resolved for them instead.
+fn substitute_specifiers(value: &str, target_user: Option<&str>) -> Result<String> {
+    if let Some(target_user) = target_user {
+        return substitute_target_specifiers(value, target_user);
+    }
+
+    let mut value = value.to_string();
+    if value.contains("%r") {
         debug!("Replacing %r with runtime directory");
         let runtime_direcotry = if cfg!(target_os = "linux") {
             std::env::var("XDG_RUNTIME_DIR")?
@@ -198,10 +1931,479 @@ fn get_secret_directory(manifest: &SecnixManifest) -> Result<String> {
         } else {
             return Err(anyhow!("Unsupported OS"));
         };
-        let final_string = basedir.replace("%r", &runtime_direcotry);
-        debug!("Runtime directory: {}", final_string);
-        Ok(final_string)
-    } else {
-        Ok(manifest.secret_directory.clone())
+        value = value.replace("%r", &runtime_direcotry);
+    }
+    if value.contains("%u") {
+        debug!("Replacing %u with the current user");
+        let user = users::get_current_username()
+            .and_then(|u| u.into_string().ok())
+            .ok_or(anyhow!("Could not determine the current user"))?;
+        value = value.replace("%u", &user);
+    }
+    if value.contains("%h") {
+        debug!("Replacing %h with the home directory");
+        value = value.replace("%h", &std::env::var("HOME")?);
+    }
+    debug!("Resolved path: {}", value);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        fs::DEFAULT_ACTIVE_LINK,
+        testing::{identity, write_identity, Fixture, Sops},
+    };
+
+    /// Installs change the umask and read the environment some tests change, so they run one at a
+    /// time
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    fn run(args: &[&str]) -> Result<()> {
+        let args = Cli::parse_from(std::iter::once("secnix").chain(args.iter().copied()));
+        match args.command {
+            Some(Commands::Check { .. }) => check(args),
+            _ => install(args),
+        }
+    }
+
+    /// Writes a manifest deploying a single template to `destination`
+    fn template_manifest(fixture: &Fixture, destination: &str) -> String {
+        let template = fixture.template("app.conf", "value", destination);
+        fixture.manifest(json!({ "templates": [template] }))
+    }
+
+    fn active_generation(directory: &Path, active_link: &str) -> Option<String> {
+        list_generations(directory, active_link, None, None)
+            .unwrap()
+            .into_iter()
+            .find(|g| g.active)
+            .map(|g| g.id)
+    }
+
+    /// Runs `f` with an environment variable set, restoring it afterwards
+    fn with_var<T>(name: &str, value: &str, f: impl FnOnce() -> T) -> T {
+        let previous = std::env::var_os(name);
+        std::env::set_var(name, value);
+        let result = f();
+        match previous {
+            Some(previous) => std::env::set_var(name, previous),
+            None => std::env::remove_var(name),
+        }
+        result
+    }
+
+    #[test]
+    fn check_verifies_macs() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let sops = Sops::new(&fixture.identity);
+
+        let original = sops.yaml("password: hunter2\n");
+        let (swapped, _) = sops.encrypt("password: letmein\n");
+        let mut document: serde_yaml::Mapping = serde_yaml::from_str(&original).unwrap();
+        document.insert("password".into(), swapped["password"].clone());
+        let source = fixture
+            .scratch
+            .write("secrets.yaml", serde_yaml::to_string(&document).unwrap());
+
+        let manifest = fixture.manifest(json!({
+            "secrets": [{
+                "type": "yaml",
+                "name": "password",
+                "source": source,
+                "key": "password",
+            }],
+        }));
+
+        let error = run(&[&manifest, "check"]).unwrap_err();
+        assert!(format!("{:#}", error).contains("MAC mismatch"));
+        run(&[&manifest, "check", "--no-verify-mac"]).unwrap();
+
+        std::fs::write(&source, original).unwrap();
+        run(&[&manifest, "check"]).unwrap();
+    }
+
+    #[test]
+    fn check_leaves_macs_unverified_without_a_matching_key() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let password = fixture.secret("password", "value: hunter2\n");
+        let other = Fixture::new();
+
+        for keys in [
+            json!([write_identity(&other.scratch, &other.identity)]),
+            json!([fixture.scratch.join("missing")]),
+        ] {
+            let manifest = fixture.manifest(json!({ "secrets": [password], "ssh_keys": keys }));
+            run(&[&manifest, "check"]).unwrap();
+        }
+    }
+
+    #[test]
+    fn unchanged_install_is_skipped_and_wipes_keyfile() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let manifest = template_manifest(&fixture, &fixture.scratch.join("etc/app.conf"));
+        let store = fixture.store();
+
+        run(&[&manifest, "--quiet"]).unwrap();
+        let deployed = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        std::fs::write(store.join(KEYFILE), "AGE-SECRET-KEY-1").unwrap();
+
+        run(&[&manifest, "--quiet"]).unwrap();
+        assert_eq!(
+            active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap(),
+            deployed
+        );
+        assert!(!store.join(KEYFILE).exists());
+    }
+
+    #[test]
+    fn resolved_manifest_changes_are_deployed() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        // The manifest stays the same, but what %r resolves to doesn't
+        let manifest = template_manifest(&fixture, "%r/app.conf");
+        let store = fixture.store();
+        let first = fixture.scratch.path().join("first");
+        let second = fixture.scratch.path().join("second");
+
+        with_var("XDG_RUNTIME_DIR", &first.to_string_lossy(), || {
+            run(&[&manifest, "--quiet"]).unwrap()
+        });
+        let deployed = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        with_var("XDG_RUNTIME_DIR", &second.to_string_lossy(), || {
+            run(&[&manifest, "--quiet"]).unwrap()
+        });
+
+        assert_ne!(
+            active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap(),
+            deployed
+        );
+        assert_eq!(fixture.read("second/app.conf"), "value");
+
+        // So are options that change how the same manifest is deployed
+        let deployed = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        with_var("XDG_RUNTIME_DIR", &second.to_string_lossy(), || {
+            run(&[&manifest, "--quiet", "--lenient-types"]).unwrap()
+        });
+        assert_ne!(
+            active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap(),
+            deployed
+        );
+    }
+
+    #[test]
+    fn check_compares_expected_recipients() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let other = identity().to_public().to_string();
+        let unused = identity().to_public().to_string();
+        let recipient = fixture.identity.to_public().to_string();
+        let sops = Sops {
+            recipients: vec![recipient.clone(), other.clone()],
+            ..Sops::new(&fixture.identity)
+        };
+        let source = fixture
+            .scratch
+            .write("secrets.yaml", sops.yaml("password: hunter2\n"));
+        let check = |expected: Vec<&String>| {
+            let manifest = fixture.manifest(json!({
+                "secrets": [{
+                    "type": "yaml",
+                    "name": "password",
+                    "source": source,
+                    "key": "password",
+                    "expected_recipients": expected,
+                }],
+            }));
+            run(&[&manifest, "check"]).map_err(|e| format!("{:#}", e))
+        };
+
+        check(vec![&recipient, &other]).unwrap();
+        let error = check(vec![&recipient]).unwrap_err();
+        assert!(error.contains(&format!("missing: [], extra: [{:?}]", other)));
+        let error = check(vec![&recipient, &other, &unused]).unwrap_err();
+        assert!(error.contains(&format!("missing: [{:?}], extra: []", unused)));
+    }
+
+    #[test]
+    fn check_rejects_unknown_owners() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let check = |owner: &str| {
+            let mut template =
+                fixture.template("app.conf", "value", &fixture.scratch.join("etc/app.conf"));
+            template["owner"] = json!(owner);
+            let manifest = fixture.manifest(json!({ "templates": [template] }));
+            run(&[&manifest, "check"]).map_err(|e| format!("{:#}", e))
+        };
+
+        check("root").unwrap();
+        let error = check("secnix-no-such-user").unwrap_err();
+        assert!(error.contains("Unknown user: secnix-no-such-user"));
+    }
+
+    #[test]
+    fn runtime_directory_is_substituted_in_links() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let mut password = fixture.secret("password", "value: hunter2\n");
+        password["link"] = json!("%r/app/password");
+        let manifest = fixture.manifest(json!({ "secrets": [password] }));
+        let runtime = fixture.scratch.path().join("run");
+
+        with_var("XDG_RUNTIME_DIR", &runtime.to_string_lossy(), || {
+            run(&[&manifest, "--quiet"]).unwrap()
+        });
+
+        assert_eq!(fixture.read("run/app/password"), "hunter2");
+    }
+
+    #[test]
+    fn umask_does_not_loosen_file_modes() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let mut password = fixture.secret("password", "value: hunter2\n");
+        password["mode"] = json!("0600");
+        let manifest = fixture.manifest(json!({ "secrets": [password], "umask": "000" }));
+
+        run(&[&manifest, "--quiet"]).unwrap();
+
+        let store = fixture.store();
+        let generation = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        let path = store.join("generations").join(generation).join("password");
+        let mode = std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn no_cleanup_leaves_the_previous_links() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for no_cleanup in [false, true] {
+            let fixture = Fixture::new();
+            let user = fixture.linked_secret("user", "value: hunter2\n");
+            let password = fixture.linked_secret("password", "value: hunter2\n");
+
+            let manifest = fixture.manifest(json!({ "secrets": [user, password] }));
+            run(&[&manifest, "--quiet"]).unwrap();
+            let manifest = fixture.manifest(json!({ "secrets": [user] }));
+            let mut args = vec![manifest.as_str(), "--quiet"];
+            if no_cleanup {
+                args.push("--no-cleanup");
+            }
+            run(&args).unwrap();
+
+            let links = fixture.scratch.path().join("links");
+            let left = std::fs::symlink_metadata(links.join("password")).is_ok();
+            assert_eq!(left, no_cleanup, "no_cleanup: {}", no_cleanup);
+            assert!(links.join("user").exists());
+        }
+    }
+
+    #[test]
+    fn basedir_redirects_generations() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let manifest = template_manifest(&fixture, &fixture.scratch.join("etc/app.conf"));
+        let elsewhere = fixture.scratch.join("elsewhere");
+
+        run(&[&manifest, "--quiet", "--basedir", &elsewhere]).unwrap();
+        let elsewhere = Path::new(&elsewhere);
+
+        assert!(active_generation(elsewhere, DEFAULT_ACTIVE_LINK).is_some());
+        assert!(!fixture.store().exists());
+        assert_eq!(fixture.read("etc/app.conf"), "value");
+    }
+
+    #[test]
+    fn prepared_identities_decrypt_every_file() {
+        let fixture = Fixture::new();
+        let keys = [write_identity(&fixture.scratch, &fixture.identity)];
+        let identities = prepare_identities(&keys, DecryptOptions::default()).unwrap();
+
+        for (name, value) in [("user", "admin"), ("password", "hunter2")] {
+            let secret: crate::manifest::SecretFile =
+                serde_json::from_value(fixture.secret(name, &format!("value: {}\n", value)))
+                    .unwrap();
+            match secret.decrypt(&identities) {
+                Ok(Some(crate::enc::age::DecryptedValue::String(s))) => assert_eq!(s, value),
+                Ok(_) => panic!("{} did not decrypt to a string", name),
+                Err(e) => panic!("{}: {:#}", name, e),
+            }
+        }
+    }
+
+    #[test]
+    fn interactive_installs_are_declined_without_a_terminal() {
+        let args = |extra: &[&str]| {
+            Cli::parse_from(
+                ["secnix", "manifest.json", "--interactive"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        let declined = |result: Result<()>| {
+            matches!(
+                result.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::NotConfirmed(_))
+            )
+        };
+        assert!(declined(confirm(&args(&[]), "yes\n".as_bytes(), false)));
+        assert!(declined(confirm(&args(&[]), "\n".as_bytes(), true)));
+        confirm(&args(&[]), "y\n".as_bytes(), true).unwrap();
+        confirm(&args(&["--yes"]), "".as_bytes(), false).unwrap();
+
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let manifest = template_manifest(&fixture, &fixture.scratch.join("etc/app.conf"));
+        run(&[&manifest, "--quiet", "--interactive", "--yes"]).unwrap();
+        assert!(active_generation(&fixture.store(), DEFAULT_ACTIVE_LINK).is_some());
+    }
+
+    #[test]
+    fn active_link_names_keep_separate_histories() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let store = fixture.store();
+        let install = |file: &str, link: Option<&str>, contents: &str| {
+            let destination = fixture.scratch.join(&format!("etc/{}", file));
+            let template = fixture.template(file, contents, &destination);
+            let manifest = fixture.manifest(json!({
+                "templates": [template],
+                "active_link_name": link,
+            }));
+            run(&[&manifest, "--quiet"]).unwrap();
+        };
+
+        install("system.conf", None, "system");
+        install("user.conf", Some("user"), "user");
+
+        assert!(store.join("metadata.json").is_file());
+        assert!(store.join("user.metadata.json").is_file());
+        let system_generation = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        let user_generation = active_generation(&store, "user").unwrap();
+        assert_ne!(system_generation, user_generation);
+        // Installing one doesn't clean up the generations of the other
+        assert!(store.join("generations").join(&system_generation).is_dir());
+        assert_eq!(fixture.read("store/secrets/rendered/system.conf"), "system");
+        assert_eq!(fixture.read("store/user/rendered/user.conf"), "user");
+    }
+
+    #[test]
+    fn only_redeploys_the_named_secrets() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let deploy = |value: &str, args: &[&str]| {
+            let secrets = ["user", "password"]
+                .map(|name| fixture.linked_secret(name, &format!("value: {} {}\n", name, value)));
+            let manifest = fixture.manifest(json!({ "secrets": secrets }));
+            let mut all = vec![manifest.as_str(), "--quiet"];
+            all.extend_from_slice(args);
+            run(&all).unwrap();
+        };
+
+        deploy("first", &[]);
+        deploy("second", &["install", "--only", "password"]);
+
+        assert_eq!(fixture.read("links/password"), "password second");
+        assert_eq!(fixture.read("links/user"), "user first");
+        let store = fixture.store();
+        let generation = active_generation(&store, DEFAULT_ACTIVE_LINK).unwrap();
+        let metadata = store
+            .join("generations")
+            .join(generation)
+            .join(".metadata.json");
+        let metadata: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(metadata).unwrap()).unwrap();
+        let mut files: Vec<&str> = metadata["secret_files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_str().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                fixture.scratch.join("links/password"),
+                fixture.scratch.join("links/user")
+            ]
+        );
+    }
+
+    #[test]
+    fn fixing_refuses_to_drop_yaml_comments() {
+        let fixture = Fixture::new();
+        let commented = "# Deployed by the web role\nversion: 1\nsecrets: []\n";
+        let path = fixture.scratch.write("manifest.yaml", commented);
+
+        let error = fix_manifest(&path).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::CommentedManifest(_))
+        ));
+        assert_eq!(fixture.read("manifest.yaml"), commented);
+
+        let quoted = "version: 1\nsecrets: []\ndefault_owner: 'root # admin'\n";
+        let path = fixture.scratch.write("manifest.yaml", quoted);
+        fix_manifest(&path).unwrap();
+        assert_eq!(
+            fixture.read("manifest.yaml"),
+            "version: 1\nsecrets: []\ndefault_owner: 'root # admin'\n"
+        );
+    }
+
+    #[test]
+    fn credentials_are_copied_read_only() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let mut password = fixture.secret("password", "value: hunter2\n");
+        password["credential"] = json!(true);
+        let manifest = fixture.manifest(json!({
+            "secrets": [password],
+            "credentials_directory": fixture.scratch.join("credstore"),
+        }));
+
+        run(&[&manifest, "--quiet"]).unwrap();
+
+        let credential = fixture.scratch.path().join("credstore/password");
+        let metadata = std::fs::symlink_metadata(&credential).unwrap();
+        assert!(metadata.file_type().is_file());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o400);
+        assert_eq!(fixture.read("credstore/password"), "hunter2");
+    }
+
+    #[test]
+    fn copy_all_copies_wildcard_directories() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let fixture = Fixture::new();
+        let password = fixture.linked_secret("password", "value: hunter2\n");
+        let mut database = fixture.linked_secret("database", "user: admin\npassword: hunter2\n");
+        database["key"] = json!("*");
+        let manifest = fixture.manifest(json!({
+            "secrets": [password, database],
+            "copy_all": true,
+        }));
+
+        run(&[&manifest, "--quiet"]).unwrap();
+        let links = fixture.scratch.path().join("links");
+        for path in ["password", "database", "database/user", "database/password"] {
+            let metadata = std::fs::symlink_metadata(links.join(path)).unwrap();
+            assert!(!metadata.file_type().is_symlink(), "{} is a symlink", path);
+        }
+        assert_eq!(fixture.read("links/database/password"), "hunter2");
+
+        // Redeploying replaces the copied directory as a whole
+        fixture.secret("database", "user: root\n");
+        run(&[&manifest, "--quiet"]).unwrap();
+        assert_eq!(fixture.read("links/database/user"), "root");
+        assert!(!links.join("database/password").exists());
     }
 }
@@ -1,6 +1,10 @@
 use std::{
+    collections::HashMap,
     fs,
-    io::{BufReader, Read},
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
 };
 
 use aes_gcm::{
@@ -8,13 +12,17 @@ use aes_gcm::{
     aes::Aes256,
     AesGcm, Key, Nonce,
 };
-use age::IdentityFileEntry;
+use age::{secrecy::SecretString, IdentityFileEntry};
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::ChaCha20Poly1305;
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::{debug, warn};
+use zeroize::{Zeroize, Zeroizing};
 
 use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -22,8 +30,28 @@ pub enum Error {
     InvalidKeyFile,
     #[error("Decryption error: {0}")]
     DecryptionError(#[from] age::DecryptError),
+    #[error("Invalid recipient: {0}")]
+    InvalidRecipient(String),
+    #[error("Invalid identity in {0} on line {1}")]
+    InvalidIdentity(String, usize),
+    #[error(
+        "Value at `{path}` is declared as {data_type:?} but its {length} byte value is not one"
+    )]
+    InvalidValue {
+        path: String,
+        data_type: Aes256GcmType,
+        /// The length of the plaintext, the plaintext itself is never included
+        length: usize,
+    },
+    #[error(
+        "The data key is protected by a passphrase but none was provided, set SECNIX_PASSPHRASE or pass --passphrase-file"
+    )]
+    PassphraseRequired,
 }
 
+/// The environment variable holding the passphrase of passphrase-wrapped data keys
+pub const PASSPHRASE_VARIABLE: &str = "SECNIX_PASSPHRASE";
+
 pub enum DecryptedValue {
     String(String),
     Int(i64),
@@ -33,108 +61,397 @@ pub enum DecryptedValue {
     Comment(()),
 }
 
-pub fn decrypt_kek(kek: &str, keyfile: &str) -> Result<Vec<u8>> {
-    let armor_reader = age::armor::ArmoredReader::new(kek.as_bytes());
+impl Zeroize for DecryptedValue {
+    fn zeroize(&mut self) {
+        match self {
+            DecryptedValue::String(s) => s.zeroize(),
+            DecryptedValue::Int(i) => i.zeroize(),
+            DecryptedValue::Float(f) => f.zeroize(),
+            DecryptedValue::Bytes(b) => b.zeroize(),
+            DecryptedValue::Bool(b) => b.zeroize(),
+            DecryptedValue::Comment(_) => {}
+        }
+    }
+}
+
+/// Serializes the value with its original type, so numbers and booleans aren't stringified
+impl Serialize for DecryptedValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DecryptedValue::String(s) => serializer.serialize_str(s),
+            DecryptedValue::Int(i) => serializer.serialize_i64(*i),
+            DecryptedValue::Float(f) => serializer.serialize_f64(*f),
+            DecryptedValue::Bytes(b) => serializer.serialize_str(&String::from_utf8_lossy(b)),
+            DecryptedValue::Bool(b) => serializer.serialize_bool(*b),
+            DecryptedValue::Comment(_) => serializer.serialize_unit(),
+        }
+    }
+}
+
+/// Options that change how the values of sops files are decrypted
+#[derive(Debug, Default, Clone)]
+pub struct DecryptOptions {
+    /// Return values that don't parse as their declared type as strings instead of failing
+    pub lenient_types: bool,
+    /// The file passphrase-wrapped data keys are unlocked with, used if `SECNIX_PASSPHRASE` isn't
+    /// set
+    pub passphrase_file: Option<PathBuf>,
+}
+
+/// Age identities held in memory, so secrets can be decrypted without a keyfile on disk, along
+/// with the options they decrypt with
+pub struct Identities {
+    identities: Vec<age::x25519::Identity>,
+    options: DecryptOptions,
+    /// The passphrase once it has been read, so it is only prompted for once
+    passphrase: Mutex<Option<SecretString>>,
+    /// Data keys that were already unwrapped, by their wrapped form
+    data_keys: Mutex<HashMap<String, Zeroizing<[u8; 32]>>>,
+}
+
+impl Identities {
+    pub fn new(identities: Vec<age::x25519::Identity>) -> Self {
+        Self::with_options(identities, DecryptOptions::default())
+    }
 
-    let decryptor = match age::Decryptor::new(armor_reader) {
-        Ok(age::Decryptor::Recipients(d)) => Ok(d),
-        Ok(_) => Err(Error::InvalidKeyFile),
-        Err(e) => Err(Error::DecryptionError(e)),
-    }?;
+    pub fn with_options(identities: Vec<age::x25519::Identity>, options: DecryptOptions) -> Self {
+        Identities {
+            identities,
+            options,
+            passphrase: Mutex::new(None),
+            data_keys: Mutex::new(HashMap::new()),
+        }
+    }
 
-    let identity = read_age_keyfile(keyfile)?;
+    /// Reads the identities in an age keyfile
+    pub fn from_keyfile(path: &str) -> Result<Self> {
+        Ok(Identities::new(
+            read_age_keyfile(path)?
+                .into_iter()
+                .map(|IdentityFileEntry::Native(identity)| identity)
+                .collect(),
+        ))
+    }
 
-    let mut decrypted = vec![];
-    let mut reader = decryptor.decrypt(
-        identity
+    /// Gets the recipients of the identities
+    pub fn public_keys(&self) -> Vec<String> {
+        self.identities
             .iter()
-            .map(|IdentityFileEntry::Native(x)| x as &dyn age::Identity),
-    )?;
-    reader.read_to_end(&mut decrypted)?;
+            .map(|i| i.to_public().to_string())
+            .collect()
+    }
+
+    pub fn options(&self) -> &DecryptOptions {
+        &self.options
+    }
+
+    /// Unwraps a data key, reusing it if the same key was unwrapped before. Unwrapping a
+    /// passphrase-wrapped key runs scrypt, which takes around a second.
+    pub fn data_key(&self, kek: &str) -> Result<Zeroizing<[u8; 32]>> {
+        let mut data_keys = self.data_keys.lock().unwrap();
+        if let Some(data_key) = data_keys.get(kek) {
+            return Ok(data_key.clone());
+        }
+        let decrypted = decrypt_kek(kek, self)?;
+        let data_key: &[u8; 32] = decrypted[..].try_into()?;
+        let data_key = Zeroizing::new(*data_key);
+        data_keys.insert(kek.to_string(), data_key.clone());
+        Ok(data_key)
+    }
+}
+
+/// Decrypts a data key wrapped for the identities, or with a passphrase. The passphrase is read
+/// from `SECNIX_PASSPHRASE` or the passphrase file, or prompted for the first time it is needed.
+pub fn decrypt_kek(kek: &str, identities: &Identities) -> Result<Zeroizing<Vec<u8>>> {
+    let armor_reader = age::armor::ArmoredReader::new(kek.as_bytes());
+
+    let mut decrypted = Zeroizing::new(vec![]);
+    match age::Decryptor::new(armor_reader).map_err(Error::DecryptionError)? {
+        age::Decryptor::Recipients(decryptor) => {
+            let mut reader = decryptor.decrypt(
+                identities
+                    .identities
+                    .iter()
+                    .map(|x| x as &dyn age::Identity),
+            )?;
+            reader.read_to_end(&mut decrypted)?;
+        }
+        age::Decryptor::Passphrase(decryptor) => {
+            let mut passphrase = identities.passphrase.lock().unwrap();
+            if passphrase.is_none() {
+                *passphrase = Some(read_passphrase(
+                    identities.options.passphrase_file.as_deref(),
+                )?);
+            }
+            let mut reader = decryptor.decrypt(passphrase.as_ref().unwrap(), None)?;
+            reader.read_to_end(&mut decrypted)?;
+        }
+    }
 
     Ok(decrypted)
 }
 
+/// Checks if a data key is wrapped with a passphrase rather than for recipients
+pub fn is_passphrase(kek: &str) -> bool {
+    let armor_reader = age::armor::ArmoredReader::new(kek.as_bytes());
+    matches!(
+        age::Decryptor::new(armor_reader),
+        Ok(age::Decryptor::Passphrase(_))
+    )
+}
+
+/// Reads the passphrase from the environment or the passphrase file, or prompts for it if stdin is
+/// a terminal
+fn read_passphrase(passphrase_file: Option<&Path>) -> Result<SecretString> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VARIABLE) {
+        return Ok(SecretString::new(passphrase));
+    }
+    if let Some(path) = passphrase_file {
+        let passphrase = crate::ssh::read_passphrase_file(path)?;
+        return Ok(SecretString::new(passphrase.to_string()));
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::PassphraseRequired.into());
+    }
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    Ok(SecretString::new(passphrase))
+}
+
+/// Wraps the data key for a single age recipient, returning the armored stanza sops stores
+pub fn encrypt_kek(kek: &[u8], recipient: &str) -> Result<String> {
+    let parsed: age::x25519::Recipient = recipient
+        .parse()
+        .map_err(|_| Error::InvalidRecipient(recipient.to_string()))?;
+    let encryptor = age::Encryptor::with_recipients(vec![Box::new(parsed) as _])
+        .ok_or_else(|| Error::InvalidRecipient(recipient.to_string()))?;
+
+    let mut encrypted = vec![];
+    let armor =
+        age::armor::ArmoredWriter::wrap_output(&mut encrypted, age::armor::Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(kek)?;
+    writer.finish()?.finish()?;
+
+    Ok(String::from_utf8(encrypted)?)
+}
+
 pub type SopsGcm = AesGcm<Aes256, cipher::consts::U32>;
 
-pub fn decrypt(data: String, key: &[u8; 32], path: Vec<String>) -> Result<DecryptedValue> {
-    let raw_data = Aes256GcmData::try_from(data)?;
-    let nonce = raw_data.iv;
+/// Decrypts a value, returning values that don't parse as their declared type as strings if
+/// `lenient` is set
+pub fn decrypt(
+    data: String,
+    key: &[u8; 32],
+    path: Vec<String>,
+    lenient: bool,
+) -> Result<DecryptedValue> {
     let aad = path.join(":") + ":";
-    let cipher = raw_data.data;
-    let tag = raw_data.tag;
+    decrypt_with_aad(data, key, aad.as_bytes(), lenient)
+}
 
-    let ciphertext_tag = [cipher, tag].concat();
+/// Decrypts a value with explicit additional data, as used by the MAC
+pub fn decrypt_with_aad(
+    data: String,
+    key: &[u8; 32],
+    aad: &[u8],
+    lenient: bool,
+) -> Result<DecryptedValue> {
+    let raw_data = Aes256GcmData::try_from(data)?;
+    let ciphertext_tag = [raw_data.data, raw_data.tag].concat();
 
-    let aad = aad.as_bytes();
     let payload = Payload {
         msg: &ciphertext_tag[..],
         aad,
     };
 
-    let nonce = Nonce::from_slice(&nonce[..]);
-    let key = Key::<SopsGcm>::from_slice(&key[..]);
-
-    let cipher = SopsGcm::new(key);
-    match cipher.decrypt(nonce, payload) {
-        Ok(raw_decrypted) => {
-            let decrypted = String::from_utf8(raw_decrypted).map_err(|e| anyhow!(e))?;
-
-            match raw_data.data_type {
-                Aes256GcmType::String => Ok(DecryptedValue::String(decrypted)),
-                Aes256GcmType::Int => Ok(DecryptedValue::Int(decrypted.parse()?)),
-                Aes256GcmType::Float => Ok(DecryptedValue::Float(decrypted.parse()?)),
-                Aes256GcmType::Bytes => Ok(DecryptedValue::Bytes(decrypted.into_bytes())),
-                Aes256GcmType::Bool => Ok(DecryptedValue::Bool(decrypted.parse()?)),
-                Aes256GcmType::Comment => Ok(DecryptedValue::Comment(())),
-                Aes256GcmType::Unknown => Err(anyhow!("Unknown data type")),
-            }
+    let raw_decrypted = raw_data.cipher.decrypt(key, &raw_data.iv, payload)?;
+    match raw_data.data_type {
+        Aes256GcmType::Bytes => Ok(DecryptedValue::Bytes(raw_decrypted)),
+        // sops writes whole binary files as strings, which may not be valid UTF-8
+        Aes256GcmType::String => match String::from_utf8(raw_decrypted) {
+            Ok(decrypted) => Ok(DecryptedValue::String(decrypted)),
+            Err(e) => Ok(DecryptedValue::Bytes(e.into_bytes())),
+        },
+        Aes256GcmType::Int => coerce(
+            raw_decrypted,
+            aad,
+            Aes256GcmType::Int,
+            DecryptedValue::Int,
+            lenient,
+        ),
+        Aes256GcmType::Float => coerce(
+            raw_decrypted,
+            aad,
+            Aes256GcmType::Float,
+            DecryptedValue::Float,
+            lenient,
+        ),
+        Aes256GcmType::Bool => {
+            // sops writes booleans as `True` and `False`, which `bool::from_str` rejects
+            let decrypted = match raw_decrypted.as_slice() {
+                b"True" => b"true".to_vec(),
+                b"False" => b"false".to_vec(),
+                _ => raw_decrypted,
+            };
+            coerce(
+                decrypted,
+                aad,
+                Aes256GcmType::Bool,
+                DecryptedValue::Bool,
+                lenient,
+            )
         }
-        Err(e) => Err(anyhow!(e)),
+        Aes256GcmType::Comment => Ok(DecryptedValue::Comment(())),
+        Aes256GcmType::Unknown => Err(anyhow!("Unknown data type")),
     }
 }
 
-fn read_age_keyfile(path: &str) -> Result<Vec<IdentityFileEntry>> {
-    let f = fs::File::open(path)?;
-    let f = BufReader::new(f);
-    Ok(age::IdentityFile::from_buffer(f)?.into_identities())
+/// Parses a decrypted value as its declared type. Values that don't parse are returned as strings
+/// if `lenient` is set, otherwise the error names the key but not the value.
+fn coerce<T: FromStr>(
+    decrypted: Vec<u8>,
+    aad: &[u8],
+    data_type: Aes256GcmType,
+    wrap: fn(T) -> DecryptedValue,
+    lenient: bool,
+) -> Result<DecryptedValue> {
+    let decrypted = Zeroizing::new(String::from_utf8(decrypted).map_err(|e| anyhow!(e))?);
+    if let Ok(value) = decrypted.parse() {
+        return Ok(wrap(value));
+    }
+
+    // The additional data of a value is its path with every segment followed by a colon
+    let path = String::from_utf8_lossy(aad);
+    let path = path.strip_suffix(':').unwrap_or(&path).replace(':', ".");
+    if lenient {
+        warn!(
+            "Value at `{}` is declared as {:?} but is not one, using it as a string",
+            path, data_type
+        );
+        return Ok(DecryptedValue::String(decrypted.to_string()));
+    }
+    Err(Error::InvalidValue {
+        path,
+        data_type,
+        length: decrypted.len(),
+    }
+    .into())
 }
 
-pub fn get_public_keys(path: &str) -> Result<Vec<String>> {
-    let identities = read_age_keyfile(path)?;
-    Ok(identities
-        .iter()
-        .map(|i| match i {
-            IdentityFileEntry::Native(n) => n.to_public().to_string(),
-        })
+/// Parses the type of an encrypted value without decrypting it
+pub fn get_data_type(data: &str) -> Result<Aes256GcmType> {
+    let raw_data = Aes256GcmData::try_from(data.to_string())?;
+    Ok(raw_data.data_type)
+}
+
+/// Reads the identities in a keyfile, ignoring comments, blank lines, surrounding whitespace and
+/// CRLF line endings
+fn read_age_keyfile(path: &str) -> Result<Vec<IdentityFileEntry>> {
+    let contents = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(parse_identities(&contents, path)?
+        .into_iter()
+        .map(IdentityFileEntry::Native)
         .collect())
 }
 
-#[derive(Debug)]
-enum Aes256GcmType {
+/// Parses the identities in the contents of an age keyfile, naming `path` in errors
+pub fn parse_identities(contents: &str, path: &str) -> Result<Vec<age::x25519::Identity>> {
+    let mut identities = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let identity = line
+            .parse::<age::x25519::Identity>()
+            .map_err(|_| Error::InvalidIdentity(path.to_string(), number + 1))?;
+        identities.push(identity);
+    }
+    Ok(identities)
+}
+
+/// Checks if key material is an age identity file, as written by `age-keygen`, rather than an ssh
+/// key
+pub fn is_identity_file(data: &[u8]) -> bool {
+    std::str::from_utf8(data).is_ok_and(|contents| {
+        contents
+            .lines()
+            .map(str::trim)
+            .any(|line| line.starts_with("AGE-SECRET-KEY-") || line.starts_with("# public key:"))
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, JsonSchema)]
+pub enum Aes256GcmType {
+    #[serde(rename = "str")]
     String,
+    #[serde(rename = "int")]
     Int,
+    #[serde(rename = "float")]
     Float,
+    #[serde(rename = "bytes")]
     Bytes,
+    #[serde(rename = "bool")]
     Bool,
+    #[serde(rename = "comment")]
     Comment,
+    #[serde(skip)]
     Unknown,
 }
 
+/// The cipher used to encrypt a value
+#[derive(Debug, PartialEq)]
+enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// The size of the iv this cipher expects
+    fn iv_size(&self) -> usize {
+        match self {
+            Cipher::Aes256Gcm => 32,
+            Cipher::ChaCha20Poly1305 => 12,
+        }
+    }
+
+    fn decrypt(&self, key: &[u8; 32], iv: &[u8], payload: Payload) -> Result<Vec<u8>> {
+        match self {
+            Cipher::Aes256Gcm => {
+                let cipher = SopsGcm::new(Key::<SopsGcm>::from_slice(&key[..]));
+                cipher
+                    .decrypt(Nonce::from_slice(iv), payload)
+                    .map_err(|e| anyhow!(e))
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key[..]));
+                cipher
+                    .decrypt(chacha20poly1305::Nonce::from_slice(iv), payload)
+                    .map_err(|e| anyhow!(e))
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Aes256GcmData {
+    cipher: Cipher,
     data: Vec<u8>,
     iv: Vec<u8>,
     tag: Vec<u8>,
     data_type: Aes256GcmType,
 }
 
-const AES256_GCM_REGEX: &str = r#"^ENC\[AES256_GCM,data:(.*),iv:(.*),tag:(.*),type:(.*)\]$"#;
+const ENCRYPTED_VALUE_REGEX: &str = r#"^ENC\[(\w+),data:(.*),iv:(.*),tag:(.*),type:(.*)\]$"#;
 
 #[derive(Debug, Error)]
 pub enum ParseError {
     #[error("Invalid data format")]
     InvalidDataFormat,
+    #[error("Unsupported cipher: {0}")]
+    UnsupportedCipher(String),
+    #[error("Invalid iv length {0}, expected {1}")]
+    InvalidIvLength(usize, usize),
     #[error("Error decoding {0}: {1}")]
     DataDecodeError(&'static str, #[source] base64::DecodeError),
 }
@@ -143,19 +460,30 @@ impl TryFrom<String> for Aes256GcmData {
     type Error = ParseError;
 
     fn try_from(value: String) -> Result<Aes256GcmData, ParseError> {
-        let re = Regex::new(AES256_GCM_REGEX).unwrap();
-        debug!("Parsing AES256_GCM data: {}", value);
+        let re = Regex::new(ENCRYPTED_VALUE_REGEX).unwrap();
+        debug!("Parsing encrypted data: {}", value);
 
-        let Some((_, [data, iv, tag, data_type])) = re.captures(&value).map(|c| c.extract()) else {
+        let Some((_, [cipher, data, iv, tag, data_type])) =
+            re.captures(&value).map(|c| c.extract())
+        else {
             return Err(ParseError::InvalidDataFormat);
         };
 
+        let cipher = match cipher {
+            "AES256_GCM" => Cipher::Aes256Gcm,
+            "CHACHA20_POLY1305" => Cipher::ChaCha20Poly1305,
+            other => return Err(ParseError::UnsupportedCipher(other.to_string())),
+        };
+
         let data = general_purpose::STANDARD
             .decode(data)
             .map_err(|e| ParseError::DataDecodeError("data", e))?;
         let iv = general_purpose::STANDARD
             .decode(iv)
             .map_err(|e| ParseError::DataDecodeError("iv", e))?;
+        if iv.len() != cipher.iv_size() {
+            return Err(ParseError::InvalidIvLength(iv.len(), cipher.iv_size()));
+        }
         let tag = general_purpose::STANDARD
             .decode(tag)
             .map_err(|e| ParseError::DataDecodeError("tag", e))?;
@@ -171,6 +499,7 @@ impl TryFrom<String> for Aes256GcmData {
         };
 
         Ok(Aes256GcmData {
+            cipher,
             data,
             iv,
             tag,
@@ -178,3 +507,149 @@ impl TryFrom<String> for Aes256GcmData {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+    use crate::sops::load_sops_file;
+    use crate::testing::{
+        encrypt_value, encrypt_with, identity, wrap_with_passphrase, Scratch, Sops, DATA_KEY,
+    };
+
+    #[test]
+    fn passphrase_wrapped_data_keys_are_decrypted_with_the_passphrase_file() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let sops = Sops {
+            recipients: vec![],
+            passphrase: Some("correct horse".into()),
+            ..Sops::new(&identity)
+        };
+        let path = scratch.write("secrets.yaml", sops.yaml("password: hunter2\n"));
+        let options = DecryptOptions {
+            passphrase_file: Some(scratch.write("passphrase", "correct horse\n").into()),
+            ..Default::default()
+        };
+
+        assert!(is_passphrase(&wrap_with_passphrase("correct horse")));
+        let file = match load_sops_file(&path, None) {
+            Ok(file) => file,
+            Err(e) => panic!("{:#}", e),
+        };
+        match file.decrypt(&["password"], &Identities::with_options(vec![], options)) {
+            Ok(DecryptedValue::String(s)) => assert_eq!(s, "hunter2"),
+            Ok(_) => panic!("password is not a string"),
+            Err(e) => panic!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn data_keys_are_unwrapped_once_per_file() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let path = scratch.write(
+            "secrets.yaml",
+            Sops::new(&identity).yaml("user: admin\npassword: hunter2\n"),
+        );
+        let identities = crate::testing::identities(&identity);
+        let file = load_sops_file(&path, None).unwrap();
+        for key in ["user", "password"] {
+            assert!(file.decrypt(&[key], &identities).is_ok());
+        }
+        let data_keys = identities.data_keys.lock().unwrap();
+        assert_eq!(data_keys.len(), 1);
+        assert!(data_keys.values().all(|key| **key == DATA_KEY));
+    }
+
+    fn decrypt_typed(value: &str, data_type: &str, lenient: bool) -> Result<DecryptedValue> {
+        let encrypted = encrypt_value(value.as_bytes(), b"settings:value:", data_type);
+        decrypt_with_aad(encrypted, &DATA_KEY, b"settings:value:", lenient)
+    }
+
+    #[test]
+    fn sops_booleans_are_titlecase() {
+        assert!(matches!(
+            decrypt_typed("True", "bool", false),
+            Ok(DecryptedValue::Bool(true))
+        ));
+        assert!(matches!(
+            decrypt_typed("False", "bool", false),
+            Ok(DecryptedValue::Bool(false))
+        ));
+    }
+
+    #[test]
+    fn chacha_values_are_decrypted() {
+        let encrypted = encrypt_with(
+            "CHACHA20_POLY1305",
+            &[2u8; 12],
+            b"hunter2",
+            b"password:",
+            "str",
+        );
+        let decrypted = decrypt_with_aad(encrypted, &DATA_KEY, b"password:", false);
+        assert!(matches!(decrypted, Ok(DecryptedValue::String(s)) if s == "hunter2"));
+    }
+
+    #[test]
+    fn ivs_of_the_wrong_length_are_rejected() {
+        // A 32 byte iv is right for AES-GCM but not for ChaCha20-Poly1305
+        let encrypted = encrypt_value(b"hunter2", b"password:", "str")
+            .replace("AES256_GCM", "CHACHA20_POLY1305");
+        let error = decrypt_with_aad(encrypted, &DATA_KEY, b"password:", false)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast::<ParseError>().unwrap(),
+            ParseError::InvalidIvLength(32, 12)
+        ));
+    }
+
+    #[test]
+    fn mismatched_types_fail_unless_lenient() {
+        for (value, data_type) in [("eighty", "int"), ("half", "float"), ("yes", "bool")] {
+            let error = decrypt_typed(value, data_type, false).err().unwrap();
+            match error.downcast::<Error>().unwrap() {
+                Error::InvalidValue { path, length, .. } => {
+                    assert_eq!(path, "settings.value");
+                    assert_eq!(length, value.len());
+                }
+                e => panic!("unexpected error: {}", e),
+            }
+
+            let decrypted = decrypt_typed(value, data_type, true);
+            assert!(matches!(decrypted, Ok(DecryptedValue::String(s)) if s == value));
+        }
+    }
+
+    #[test]
+    fn identity_files_skip_comments_and_blank_lines() {
+        let (first, second) = (identity(), identity());
+        let contents = format!(
+            "# created: 2024-01-01T00:00:00Z\n# public key: {}\n{}\r\n\n   \n  {}  \n",
+            first.to_public(),
+            first.to_string().expose_secret(),
+            second.to_string().expose_secret()
+        );
+
+        let parsed = parse_identities(&contents, "keys.txt").unwrap();
+        let recipients: Vec<String> = parsed.iter().map(|i| i.to_public().to_string()).collect();
+        assert_eq!(
+            recipients,
+            vec![
+                first.to_public().to_string(),
+                second.to_public().to_string()
+            ]
+        );
+
+        let error = parse_identities("# comment\nnot a key\n", "keys.txt")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast::<Error>().unwrap(),
+            Error::InvalidIdentity(path, 2) if path == "keys.txt"
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+use std::process::ExitCode;
+
+use crate::{cli, enc, manifest, sops, source, ssh};
+
+/// Any other failure, such as failed doctor checks or unit reloads
+pub const FAILURE: u8 = 1;
+/// Invalid usage, manifest or source file
+pub const USAGE: u8 = 2;
+/// A secret could not be decrypted, including invalid keys
+pub const DECRYPT: u8 = 3;
+/// A filesystem or network operation failed
+pub const FILESYSTEM: u8 = 4;
+/// Permission was denied
+pub const PERMISSION: u8 = 5;
+
+/// Gets the process exit code for an error, so callers like systemd units can tell classes of
+/// failures apart. The code comes from the first error in the chain that has a known class.
+pub fn exit_code(error: &anyhow::Error) -> ExitCode {
+    let code = error.chain().find_map(classify).unwrap_or(FAILURE);
+    ExitCode::from(code)
+}
+
+fn classify(error: &(dyn std::error::Error + 'static)) -> Option<u8> {
+    if let Some(error) = error.downcast_ref::<cli::Error>() {
+        return match error {
+            cli::Error::UnsupportedVersion(..)
+            | cli::Error::CheckFailed(..)
+            | cli::Error::ManifestsFailed(..)
+            | cli::Error::UnknownTargetUser(_)
+            | cli::Error::UnknownSecret(_)
+            | cli::Error::MissingKeyVariable(_)
+            | cli::Error::EmptyKeyVariable(_)
+            | cli::Error::CommentedManifest(_) => Some(USAGE),
+            cli::Error::NoActiveGeneration
+            | cli::Error::MissingRuntimeDirectory(..)
+            | cli::Error::InsufficientSpace(..) => Some(FILESYSTEM),
+            // Classified by the underlying io error
+            cli::Error::UnreadableKey(..) => None,
+            cli::Error::DoctorFailed(_)
+            | cli::Error::UnitsFailed(..)
+            | cli::Error::NotConfirmed(_) => Some(FAILURE),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<manifest::Error>() {
+        return match error {
+            manifest::Error::Decompression(..) => Some(DECRYPT),
+            manifest::Error::Unknown(_) => None,
+            _ => Some(USAGE),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<sops::Error>() {
+        return match error {
+            sops::Error::Decrypt(_) | sops::Error::MacMismatch => Some(DECRYPT),
+            // Classified by the underlying error
+            sops::Error::Read(..) => None,
+            _ => Some(USAGE),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<source::Error>() {
+        return match error {
+            source::Error::Fetch(..) | source::Error::Status(..) => Some(FILESYSTEM),
+            _ => Some(USAGE),
+        };
+    }
+    if error.is::<sops::DecryptionError>()
+        || error.is::<enc::age::Error>()
+        || error.is::<enc::age::ParseError>()
+        || error.is::<ssh::Error>()
+    {
+        return Some(DECRYPT);
+    }
+    if let Some(error) = error.downcast_ref::<std::io::Error>() {
+        return match error.kind() {
+            std::io::ErrorKind::PermissionDenied => Some(PERMISSION),
+            _ => Some(FILESYSTEM),
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    fn code(error: impl Into<anyhow::Error>) -> ExitCode {
+        exit_code(&error.into().context("Failed to install secrets"))
+    }
+
+    #[test]
+    fn error_classes_have_their_own_exit_code() {
+        assert_eq!(
+            code(manifest::Error::PathDoesNotExist),
+            ExitCode::from(USAGE)
+        );
+        let decryption = sops::DecryptionError::NoRecipients {
+            wanted: vec![],
+            ours: vec![],
+        };
+        assert_eq!(code(decryption), ExitCode::from(DECRYPT));
+        let not_found = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(code(not_found), ExitCode::from(FILESYSTEM));
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(code(denied), ExitCode::from(PERMISSION));
+        // Errors further down the chain are found through their context
+        let nested = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context("Failed to write secret")
+            .unwrap_err();
+        assert_eq!(exit_code(&nested), ExitCode::from(PERMISSION));
+    }
+}
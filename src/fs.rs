@@ -1,34 +1,95 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
+    ffi::CString,
     fs::{rename, set_permissions, Permissions},
     io::Write,
-    os::unix::fs::{chown, symlink},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{chown, symlink},
+    },
     path::Path,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use anyhow::Result;
-use tracing::{debug, info, warn};
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use tracing::{debug, info, info_span, warn};
 use ulid::Ulid;
 use users::{get_group_by_name, get_user_by_name};
+use zeroize::Zeroizing;
 
 use crate::{
-    enc::age::DecryptedValue,
-    manifest::{SecretFile, Template},
-    sops::load_sops_file,
+    enc::age::{DecryptedValue, Identities},
+    manifest::{
+        FileDefaults, FileType, OutputFormat, RetryPolicy, SecretFile, Template, TemplateEngine,
+    },
+    progress,
+    source::read_source,
 };
 
 use std::fs::OpenOptions;
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
+/// The name of the symlink to the active generation, unless the manifest sets another
+pub const DEFAULT_ACTIVE_LINK: &str = "secrets";
+
+/// The most components a link or template destination may have
+const MAX_LINK_DEPTH: usize = 64;
+
+/// The placeholder literal templates use when none is configured. `{key}` is replaced with the
+/// name of the secret.
+const DEFAULT_PLACEHOLDER: &str = "$$SECNIX::{key}::SECNIX$$";
+
+/// A decrypted secret available to templates
+pub struct TemplateSecret {
+    /// The secret as it was written to its file, used by literal templates
+    pub text: String,
+    /// The secret with its original type, used by template engines
+    pub value: DecryptedValue,
+}
+
 /// Metadata about the secrets deployed on the system
 #[derive(Debug, Serialize, Deserialize)]
 struct FileSystemMetadata {
     /// A list of generations that have been deployed
     generations: BTreeMap<u64, String>,
     active_generation: Option<String>,
+    /// The hash of the manifest and sources the active generation was deployed from
+    #[serde(default)]
+    manifest_hash: Option<String>,
+    /// The modification time and size of each source when the active generation was deployed,
+    /// used to skip reading sources that haven't changed
+    #[serde(default)]
+    source_stats: BTreeMap<String, SourceStat>,
+    /// The unix timestamp of the last successful install, including ones that found nothing to
+    /// change
+    #[serde(default)]
+    last_install: Option<u64>,
+    /// The secrets and templates that changed in each generation, by the time it was deployed.
+    /// Unlike the generations, these are kept when generations are cleaned up.
+    #[serde(default)]
+    changes: BTreeMap<u64, GenerationChanges>,
+}
+
+/// The secrets and templates whose contents changed in a generation
+#[derive(Debug, Serialize, Deserialize)]
+struct GenerationChanges {
+    generation: String,
+    changed: Vec<String>,
+}
+
+/// What a source looked like when it was last hashed
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SourceStat {
+    /// The modification time in nanoseconds since the unix epoch
+    pub modified: u128,
+    pub size: u64,
+    /// The hash of the source's contents
+    pub hash: String,
 }
 
 /// Metadata about a generation
@@ -38,33 +99,120 @@ struct DeployedSecretsMetadata {
     generation: String,
     /// The paths to the secret files that were symlinked
     secret_files: Vec<String>,
+    /// What each secret file points at, used to restore the links when the generation is
+    /// activated again. Generations deployed before this was recorded don't have it.
+    #[serde(default)]
+    links: Vec<DeployedLink>,
+    /// The processes serving the pipes of the generation, so they can be stopped once the pipes
+    /// are replaced or removed
+    #[serde(default)]
+    fifo_writers: Vec<FifoWriter>,
+}
+
+/// A process forked to write a secret to a named pipe
+#[derive(Debug, Serialize, Deserialize)]
+struct FifoWriter {
+    /// Where the pipe is
+    path: String,
+    pid: i32,
+    /// When the process started, in clock ticks since boot, to tell it apart from a later process
+    /// reusing its id
+    started: Option<u64>,
+}
+
+/// A file linked or copied out of a generation
+#[derive(Debug, Serialize, Deserialize)]
+struct DeployedLink {
+    /// Where the file is linked or copied to
+    path: String,
+    /// The file relative to the generation directory
+    target: String,
+    /// Whether the file is copied rather than symlinked
+    #[serde(default)]
+    copy: bool,
 }
 
 /// Create a new generation of secrets, returning the generation id.
 /// This will symlink the secret files on the system.
+///
+/// Links point through the `secrets` symlink rather than at a generation directory, so swapping
+/// that one symlink moves every linked secret and template to the new generation at once.
+/// Copied templates and new links are staged next to their destination first and renamed into
+/// place together once everything has been written, which keeps the window where a reader can
+/// see a mix of generations as short as possible.
+///
+/// If `only` names any secrets or templates, just those are decrypted or rendered and the rest
+/// are carried over from the active generation. Pipes that aren't named are left as they are.
+#[allow(clippy::too_many_arguments)]
 pub fn activate_new_generation(
     basedir: &Path,
+    active_link: &str,
     files: Vec<SecretFile>,
     templates: Vec<Template>,
-    identity_file: &str,
+    defaults: &FileDefaults,
+    identities: &Identities,
+    manifest_hash: &str,
+    retry: &RetryPolicy,
+    cleanup: bool,
+    only: &[String],
 ) -> Result<String> {
+    if let Some(link) = find_duplicate_link(&files, &templates) {
+        return Err(anyhow!(
+            "{} is linked by more than one secret or template",
+            link
+        ));
+    }
+
+    let carried_from = if only.is_empty() {
+        None
+    } else {
+        let Some(active_generation) = get_metadata(basedir, active_link)?.active_generation else {
+            return Err(anyhow!(
+                "There is no active generation to carry the other secrets over from"
+            ));
+        };
+        Some(get_generation_path(basedir, &active_generation))
+    };
+    // Gets the generation a secret or template is carried over from, if it isn't redeployed
+    let carried_over = |name: &str| {
+        carried_from
+            .as_deref()
+            .filter(|_| !only.iter().any(|n| n == name))
+    };
+
     let generation_id = Ulid::new().to_string();
-    debug!(
-        "Creating new generation with id: {} using identity file {}",
-        generation_id, identity_file
-    );
+    debug!("Creating new generation with id: {}", generation_id);
 
     let template_links: Vec<String> = templates.iter().map(|t| t.destination.clone()).collect();
     let file_links: Vec<String> = files.iter().filter_map(|f| f.link.clone()).collect();
 
-    let current_metadata = DeployedSecretsMetadata {
+    // Pipes are served once by the process that installed them, so they can't be restored
+    let secret_links = files
+        .iter()
+        .filter(|f| !f.fifo.unwrap_or(false))
+        .filter_map(|f| {
+            Some(DeployedLink {
+                path: f.link.clone()?,
+                target: f.name.clone(),
+                copy: f.copy.unwrap_or(false),
+            })
+        });
+    let rendered_links = templates.iter().map(|t| DeployedLink {
+        path: t.destination.clone(),
+        target: format!("rendered/{}", t.name),
+        copy: t.copy.unwrap_or(false),
+    });
+
+    let mut current_metadata = DeployedSecretsMetadata {
         generation: generation_id.clone(),
         secret_files: [template_links, file_links].concat(),
+        links: secret_links.chain(rendered_links).collect(),
+        fifo_writers: vec![],
     };
 
     let generation_directory = get_generation_path(basedir, &generation_id);
 
-    std::fs::create_dir_all(&generation_directory)?;
+    create_owned_dir_all(&generation_directory, defaults, retry)?;
 
     debug!("Writing metadata for generation: {:?}", current_metadata);
 
@@ -72,17 +220,58 @@ pub fn activate_new_generation(
     let metadata_file = std::fs::File::create(&metadata_file)?;
     serde_json::to_writer(metadata_file, &current_metadata)?;
 
-    let mut secrets: HashMap<&str, String> = HashMap::new();
+    let mut secrets: HashMap<&str, TemplateSecret> = HashMap::new();
+    let mut fifos: HashMap<&str, Zeroizing<Vec<u8>>> = HashMap::new();
     // Write the files
     for secret_file in &files {
+        let _span = info_span!("secret", name = %secret_file.name).entered();
+        progress::inc(&secret_file.name);
         let file_name = &secret_file.name;
         let file_path = generation_directory.join(file_name);
         debug!("Writing file: {}", file_path.display());
 
-        let encrypted = load_sops_file(&secret_file.source)?;
-        if let Some(key) = &secret_file.get_key() {
-            let path = key.split('.').collect::<Vec<_>>();
-            let decrypted = encrypted.decrypt(&path, identity_file)?;
+        if let Some(previous) = carried_over(file_name) {
+            if secret_file.fifo.unwrap_or(false) {
+                debug!("Leaving the pipe at {:?} in place", secret_file.link);
+                continue;
+            }
+            debug!("Carrying {} over from {}", file_name, previous.display());
+            carry_over(&previous.join(file_name), &file_path)?;
+            if secret_file.is_wildcard() {
+                continue;
+            }
+            // Templates that are rendered again see the secret as it was written
+            if secret_file.file_type != FileType::Binary {
+                if let Ok(text) = String::from_utf8(std::fs::read(&file_path)?) {
+                    let value = DecryptedValue::String(text.clone());
+                    secrets.insert(file_name, TemplateSecret { text, value });
+                }
+            }
+            if secret_file.immutable.unwrap_or(false) {
+                set_immutable(&file_path, true)?;
+            }
+            continue;
+        }
+
+        if secret_file.is_wildcard() {
+            write_wildcard_secret(secret_file, &file_path, defaults, identities)?;
+            continue;
+        }
+
+        if let Some(decrypted) = secret_file.decrypt(identities)? {
+            if secret_file.fifo.unwrap_or(false) {
+                debug!("Holding the value for the pipe at {:?}", secret_file.link);
+                let data = match decrypted {
+                    DecryptedValue::Bytes(bytes) => bytes,
+                    value => {
+                        let format = secret_file.format.as_ref().unwrap_or(&OutputFormat::Raw);
+                        format.format(&value)?.into_bytes()
+                    }
+                };
+                verify_checksum(secret_file, &data)?;
+                fifos.insert(file_name, Zeroizing::new(data));
+                continue;
+            }
 
             let mut file = OpenOptions::new()
                 .write(true)
@@ -92,35 +281,36 @@ pub fn activate_new_generation(
                 .open(&file_path)?;
 
             match decrypted {
-                DecryptedValue::String(str) => {
-                    file.write_all(str.as_bytes())?;
-                    secrets.insert(file_name, str.clone());
-                }
-                DecryptedValue::Int(int) => {
-                    file.write_all(int.to_string().as_bytes())?;
-                    secrets.insert(file_name, int.to_string());
-                }
-                DecryptedValue::Float(float) => {
-                    file.write_all(float.to_string().as_bytes())?;
-                    secrets.insert(file_name, float.to_string());
-                }
                 DecryptedValue::Bytes(bytes) => {
+                    verify_checksum(secret_file, &bytes)?;
                     file.write_all(&bytes)?;
                 }
-                DecryptedValue::Bool(bool) => {
-                    file.write_all(bool.to_string().as_bytes())?;
-                    secrets.insert(file_name, bool.to_string());
-                }
-                _ => {
+                DecryptedValue::Comment(_) => {
                     warn!("Unsupported data type for file: {}", file_name);
                 }
+                value => {
+                    let format = secret_file.format.as_ref().unwrap_or(&OutputFormat::Raw);
+                    let text = format.format(&value)?;
+                    verify_checksum(secret_file, text.as_bytes())?;
+                    file.write_all(text.as_bytes())?;
+                    secrets.insert(file_name, TemplateSecret { text, value });
+                }
             }
             file.flush()?;
             // Make the file read-only
 
-            let mode = secret_file.mode.as_deref();
-            let group = secret_file.group.as_deref();
-            let user = secret_file.owner.as_deref();
+            let mode = secret_file
+                .mode
+                .as_deref()
+                .or(defaults.default_mode.as_deref());
+            let group = secret_file
+                .group
+                .as_deref()
+                .or(defaults.default_group.as_deref());
+            let user = secret_file
+                .owner
+                .as_deref()
+                .or(defaults.default_owner.as_deref());
             if let Err(e) = set_file_permissions(&file_path, mode, group, user) {
                 warn!(
                     "Failed to set file permissions for {}: {}",
@@ -128,6 +318,9 @@ pub fn activate_new_generation(
                     e
                 );
             }
+            if secret_file.immutable.unwrap_or(false) {
+                set_immutable(&file_path, true)?;
+            }
 
             debug!("File written successfully");
         } else {
@@ -138,18 +331,26 @@ pub fn activate_new_generation(
     // Render the templates
     debug!("Rendering templates");
     let rendered_template_dir = generation_directory.join("rendered");
-    std::fs::create_dir_all(&rendered_template_dir)?;
+    create_owned_dir_all(&rendered_template_dir, defaults, retry)?;
     for template in &templates {
+        progress::inc(&template.name);
+        if let Some(previous) = carried_over(&template.name) {
+            debug!(
+                "Carrying template {} over from {}",
+                template.name,
+                previous.display()
+            );
+            carry_over(
+                &previous.join("rendered").join(&template.name),
+                &rendered_template_dir.join(&template.name),
+            )?;
+            continue;
+        }
         debug!(
             "Rendering template {} to {}",
             template.source, template.name
         );
-        let mut text = std::fs::read_to_string(&template.source)?;
-        for (key, value) in &secrets {
-            let target_key = format!("$$SECNIX::{}::SECNIX$$", key);
-            debug!("Looking for key: {}", target_key);
-            text = text.replace(&target_key, value);
-        }
+        let text = render_with_inputs(template, &mut secrets, identities)?;
         let file_name = &template.name;
         let target = rendered_template_dir.join(file_name);
         let mut file = OpenOptions::new()
@@ -160,9 +361,18 @@ pub fn activate_new_generation(
             .open(&target)?;
         file.write_all(text.as_bytes())?;
 
-        let mode = template.mode.as_deref();
-        let group = template.group.as_deref();
-        let user = template.owner.as_deref();
+        let mode = template
+            .mode
+            .as_deref()
+            .or(defaults.default_mode.as_deref());
+        let group = template
+            .group
+            .as_deref()
+            .or(defaults.default_group.as_deref());
+        let user = template
+            .owner
+            .as_deref()
+            .or(defaults.default_owner.as_deref());
         if let Err(e) = set_file_permissions(&target, mode, group, user) {
             warn!(
                 "Failed to set file permissions for {}: {}",
@@ -176,37 +386,105 @@ pub fn activate_new_generation(
 
     // Add the generation to the manifest
     debug!("Recording generation in manifest");
-    let mut metadata = get_metadata(basedir)?;
-    let time = SystemTime::now()
+    let mut metadata = get_metadata(basedir, active_link)?;
+    let mut time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs();
+    // Generations deployed within the same second would replace each other
+    while metadata.generations.contains_key(&time) {
+        time += 1;
+    }
     metadata.generations.insert(time, generation_id.clone());
 
+    // Compare against the files of the previous generation, rather than recording hashes that
+    // could be used to guess the secrets
+    let previous_directory = metadata
+        .active_generation
+        .as_ref()
+        .map(|id| get_generation_path(basedir, id));
+    let deployed = files
+        .iter()
+        .filter(|f| !f.fifo.unwrap_or(false))
+        .map(|f| (&f.name, Path::new(&f.name).to_path_buf()))
+        .chain(
+            templates
+                .iter()
+                .map(|t| (&t.name, Path::new("rendered").join(&t.name))),
+        );
+    let changed = deployed
+        .filter(|(_, path)| match &previous_directory {
+            Some(previous) => {
+                !same_contents(&generation_directory.join(path), &previous.join(path))
+            }
+            None => true,
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    metadata.changes.insert(
+        time,
+        GenerationChanges {
+            generation: generation_id.clone(),
+            changed,
+        },
+    );
+
     let previous_generation = metadata.active_generation.take();
     debug!("Previous generation: {:?}", previous_generation);
     metadata.active_generation = Some(generation_id.clone());
+    // A partial install doesn't match the manifest, so the next install always deploys
+    metadata.manifest_hash = only.is_empty().then(|| manifest_hash.to_string());
 
-    // Symlink the generation to the active generation
-    debug!("Atomically symlinking to active generation");
-    let temp_file = basedir.join(Ulid::new().to_string());
-    symlink(get_generation_path(basedir, &generation_id), &temp_file)?;
-    rename(temp_file, basedir.join("secrets"))?;
+    link_generation(basedir, active_link, &generation_id, retry)?;
+
+    // Temporary files that are renamed over their destination once everything is staged
+    let mut staged: Vec<(std::path::PathBuf, std::path::PathBuf)> = vec![];
+    // Copies that are made immutable once they are in place, as immutable files can't be renamed
+    let mut immutable_copies = vec![];
+    // Pipes that are served once everything else is in place
+    let mut pipes = vec![];
 
     // Symlink all the files
     for secret_file in &files {
+        let _span = info_span!("secret", name = %secret_file.name).entered();
         if let Some(link) = &secret_file.link {
-            let link = Path::new(&link);
+            let link = Path::new(link);
             // Create parent directories
             if let Some(parent) = link.parent() {
-                std::fs::create_dir_all(parent)?;
+                create_owned_dir_all(parent, defaults, retry)?;
+            }
+
+            if let Some(data) = fifos.remove(secret_file.name.as_str()) {
+                pipes.push((secret_file, link, data));
+                continue;
+            }
+            if secret_file.fifo.unwrap_or(false) {
+                // A pipe that wasn't named in `only` keeps serving its value
+                continue;
+            }
+            if secret_file.copy.unwrap_or(false) {
+                let source = generation_directory.join(&secret_file.name);
+                debug!("Copying {} -> {}", source.display(), link.display());
+                remove_mismatched_kind(link, false)?;
+                let temp = temp_path(link);
+                copy_out(&source, &temp, retry)?;
+                staged.push((temp, link.to_path_buf()));
+                if secret_file.immutable.unwrap_or(false) {
+                    immutable_copies.push(link.to_path_buf());
+                }
+                continue;
+            }
+            let target = basedir.join(active_link).join(&secret_file.name);
+            if std::fs::read_link(link).is_ok_and(|existing| existing == target) {
+                // Already follows the secrets symlink, so it moved with the generation swap
+                continue;
             }
-            let target = basedir.join("secrets").join(&secret_file.name);
             debug!("Symlinking {} -> {}", link.display(), target.display());
+            remove_mismatched_kind(link, true)?;
 
-            // Create a temporary file and atomically move it to the target. The temp file is adjacent to the target
-            let temp_file = link.with_extension("tmp");
-            symlink(target, &temp_file)?;
-            rename(temp_file, link)?;
+            // Create a temporary file adjacent to the target, it's moved into place below
+            let temp_file = temp_path(link);
+            with_retry(retry, || symlink(&target, &temp_file))?;
+            staged.push((temp_file, link.to_path_buf()));
         }
     }
 
@@ -216,53 +494,113 @@ pub fn activate_new_generation(
         let link = Path::new(&template.destination);
         // Create parent directories
         if let Some(parent) = link.parent() {
-            std::fs::create_dir_all(parent)?;
+            create_owned_dir_all(parent, defaults, retry)?;
         }
 
-        if template.copy.unwrap_or(false) {
-            let source =
-                rendered_template_dir.join(Path::new(&template.source).file_name().unwrap());
+        let copy = template.copy.unwrap_or(false);
+        remove_mismatched_kind(link, !copy)?;
+
+        if copy {
+            let source = rendered_template_dir.join(&template.name);
             debug!("Copying {} -> {}", source.display(), link.display());
-            let temp = link.with_extension("tmp");
-            std::fs::copy(source, &temp)?;
-            rename(temp, link)?;
+            let temp = temp_path(link);
+            with_retry(retry, || std::fs::copy(&source, &temp))?;
+            if template.preserve_xattrs.unwrap_or(false) && link.is_file() {
+                copy_xattrs(link, &temp);
+            }
+            staged.push((temp, link.to_path_buf()));
         } else {
             let target = basedir
-                .join("secrets")
+                .join(active_link)
                 .join("rendered")
                 .join(&template.name);
+            if std::fs::read_link(link).is_ok_and(|existing| existing == target) {
+                continue;
+            }
             debug!("Symlinking {} -> {}", link.display(), target.display());
 
-            let temp = link.with_extension("tmp");
-            symlink(target, &temp)?;
-            rename(temp, link)?;
+            let temp = temp_path(link);
+            with_retry(retry, || symlink(&target, &temp))?;
+            staged.push((temp, link.to_path_buf()));
         }
     }
 
+    commit_staged(&staged, retry)?;
+    for copy in &immutable_copies {
+        set_immutable_all(copy)?;
+    }
+
     // Remove previous generation files
-    if let Some(previous_generation) = previous_generation {
-        debug!("Removing stale symlinks from previous generation");
-        let previous_manifest = get_generation_metadata_path(basedir, &previous_generation);
-        let previous_manifest: DeployedSecretsMetadata =
-            serde_json::from_reader(std::fs::File::open(&previous_manifest)?)?;
-
-        let previous_files: HashSet<String> = HashSet::from_iter(previous_manifest.secret_files);
-        let current_files: HashSet<String> = HashSet::from_iter(current_metadata.secret_files);
-        debug!("Previous files: {:?}", previous_files);
-        debug!("Current files: {:?}", current_files);
-
-        let to_remove = previous_files.difference(&current_files);
-        for file in to_remove {
-            let file = Path::new(file);
-            info!("Removing stale symlink: {}", file.display());
-            if let Err(e) = std::fs::remove_file(file) {
-                warn!("Failed to remove file: {}", e);
+    if !cleanup {
+        info!("Leaving the links of the previous generation in place");
+    } else if let Some(previous_generation) = &previous_generation {
+        remove_stale_links(
+            basedir,
+            previous_generation,
+            current_metadata.secret_files.clone(),
+        )?;
+    }
+
+    // Pipes are served last, so a failed activation never leaves a process holding a secret
+    for (secret_file, link, data) in pipes {
+        let writer = match serve_fifo(link, &data) {
+            Ok(writer) => writer,
+            Err(e) => {
+                current_metadata
+                    .fifo_writers
+                    .iter()
+                    .for_each(stop_fifo_writer);
+                return Err(e);
             }
+        };
+        current_metadata.fifo_writers.push(writer);
+        let mode = secret_file
+            .mode
+            .as_deref()
+            .or(defaults.default_mode.as_deref());
+        let group = secret_file
+            .group
+            .as_deref()
+            .or(defaults.default_group.as_deref());
+        let user = secret_file
+            .owner
+            .as_deref()
+            .or(defaults.default_owner.as_deref());
+        if let Err(e) = set_file_permissions(link, mode, group, user) {
+            warn!(
+                "Failed to set file permissions for {}: {}",
+                link.display(),
+                e
+            );
+        }
+    }
+    let previous_writers = previous_generation
+        .as_deref()
+        .map(|id| read_fifo_writers(basedir, id))
+        .unwrap_or_default();
+    for writer in previous_writers {
+        let carried = !current_metadata
+            .fifo_writers
+            .iter()
+            .any(|w| w.path == writer.path)
+            && files
+                .iter()
+                .any(|f| f.fifo.unwrap_or(false) && f.link.as_ref() == Some(&writer.path));
+        if carried {
+            // A pipe that wasn't named in `only` is still served by the previous writer
+            current_metadata.fifo_writers.push(writer);
+        } else {
+            stop_fifo_writer(&writer);
         }
     }
+    if !current_metadata.fifo_writers.is_empty() {
+        let metadata_file = get_generation_metadata_path(basedir, &generation_id);
+        let metadata_file = std::fs::File::create(&metadata_file)?;
+        serde_json::to_writer(metadata_file, &current_metadata)?;
+    }
 
     debug!("Writing metadata for filesystem");
-    let metadata_file = basedir.join("metadata.json");
+    let metadata_file = get_metadata_path(basedir, active_link);
     let metadata_file = std::fs::File::create(&metadata_file)?;
     serde_json::to_writer(metadata_file, &metadata)?;
 
@@ -270,104 +608,2039 @@ pub fn activate_new_generation(
     Ok(generation_id)
 }
 
-pub fn clean_old_generations(basedir: &Path, to_keep: usize) -> Result<()> {
-    info!("Cleaning old generations");
+/// Checks that the decrypted contents of a secret match its pinned checksum, if it has one
+fn verify_checksum(secret_file: &SecretFile, data: &[u8]) -> Result<()> {
+    let Some(expected) = &secret_file.sha256 else {
+        return Ok(());
+    };
+    let actual = format!("{:x}", Sha256::digest(data));
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            secret_file.name,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Writes every value of a wildcard secret to its own file in the secret's directory. The
+/// directories can be listed by whoever can read the files.
+fn write_wildcard_secret(
+    secret_file: &SecretFile,
+    directory: &Path,
+    defaults: &FileDefaults,
+    identities: &Identities,
+) -> Result<()> {
+    let mode = secret_file
+        .mode
+        .as_deref()
+        .or(defaults.default_mode.as_deref());
+    let group = secret_file
+        .group
+        .as_deref()
+        .or(defaults.default_group.as_deref());
+    let user = secret_file
+        .owner
+        .as_deref()
+        .or(defaults.default_owner.as_deref());
+    let format = secret_file.format.as_ref().unwrap_or(&OutputFormat::Raw);
 
-    let mut metadata = get_metadata(basedir)?;
-    let active_generation = metadata.active_generation.as_ref();
+    std::fs::create_dir_all(directory)?;
+    let mut directories = vec![directory.to_path_buf()];
+    for (path, value) in secret_file.decrypt_all(identities)? {
+        let file_path = directory.join(&path);
+        for parent in path
+            .ancestors()
+            .skip(1)
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            let parent = directory.join(parent);
+            if !directories.contains(&parent) {
+                directories.push(parent);
+            }
+        }
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-    let to_remove = metadata.generations.len() - to_keep;
-    let mut removed_count = 0;
-    let mut removed_active = None;
-    while removed_count < to_remove {
-        let Some(removed) = metadata.generations.pop_first() else {
-            info!("Removed {removed_count} old generations");
-            return Ok(());
+        let contents = match value {
+            DecryptedValue::Bytes(bytes) => Zeroizing::new(bytes),
+            DecryptedValue::Comment(_) => continue,
+            value => Zeroizing::new(format.format(&value)?.into_bytes()),
         };
+        debug!("Writing file: {}", file_path.display());
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&file_path)?;
+        file.write_all(&contents)?;
+        file.flush()?;
+        if let Err(e) = set_file_permissions(&file_path, mode, group, user) {
+            warn!(
+                "Failed to set file permissions for {}: {}",
+                file_path.display(),
+                e
+            );
+        }
+        if secret_file.immutable.unwrap_or(false) {
+            set_immutable(&file_path, true)?;
+        }
+    }
 
-        if active_generation.is_some_and(|id| id == &removed.1) {
-            removed_active = Some(removed);
-            continue;
+    let directory_mode = directory_mode(mode.unwrap_or("0600"))?;
+    for directory in &directories {
+        if let Err(e) = set_file_permissions(directory, Some(&directory_mode), group, user) {
+            warn!(
+                "Failed to set directory permissions for {}: {}",
+                directory.display(),
+                e
+            );
         }
+    }
+    Ok(())
+}
 
-        removed_count += 1;
+/// Derives the mode of a directory from the mode of the files in it, so whoever can read the files
+/// can also traverse the directory
+fn directory_mode(file_mode: &str) -> Result<String> {
+    let mode = u32::from_str_radix(file_mode, 8)?;
+    let searchable = (mode & 0o444) >> 2;
+    Ok(format!("{:o}", mode | searchable))
+}
+
+/// Activates a generation that is already on disk, restoring the links recorded when it was
+/// deployed. Links of the previously active generation that the activated one doesn't have are
+/// removed unless `cleanup` is false.
+pub fn activate_generation(
+    basedir: &Path,
+    active_link: &str,
+    generation_id: &str,
+    retry: &RetryPolicy,
+    cleanup: bool,
+) -> Result<()> {
+    let mut metadata = get_metadata(basedir, active_link)?;
+    let metadata_path = get_generation_metadata_path(basedir, generation_id);
+    if !metadata.generations.values().any(|g| g == generation_id) || !metadata_path.exists() {
+        return Err(anyhow!("Generation {} does not exist", generation_id));
+    }
+    let deployed: DeployedSecretsMetadata =
+        serde_json::from_reader(std::fs::File::open(&metadata_path)?)?;
+    if deployed.links.is_empty() && !deployed.secret_files.is_empty() {
+        warn!(
+            "Generation {} does not record its links, only the secrets symlink is updated",
+            generation_id
+        );
+    }
+
+    info!("Activating generation {}", generation_id);
+    link_generation(basedir, active_link, generation_id, retry)?;
 
-        let (_ts, id) = removed;
-        info!("Removing old generation: {}", id);
+    let generation_directory = get_generation_path(basedir, generation_id);
+    let mut staged: Vec<(std::path::PathBuf, std::path::PathBuf)> = vec![];
+    for link in &deployed.links {
+        let path = Path::new(&link.path);
+        if let Some(parent) = path.parent() {
+            with_retry(retry, || std::fs::create_dir_all(parent))?;
+        }
+        remove_mismatched_kind(path, !link.copy)?;
 
-        let path = get_generation_path(basedir, &id);
-        if let Err(e) = std::fs::remove_dir_all(&path) {
-            warn!("Failed to remove file {}: {}", path.display(), e);
+        let temp = temp_path(path);
+        if link.copy {
+            let source = generation_directory.join(&link.target);
+            debug!("Copying {} -> {}", source.display(), path.display());
+            copy_out(&source, &temp, retry)?;
+        } else {
+            let target = basedir.join(active_link).join(&link.target);
+            if std::fs::read_link(path).is_ok_and(|existing| existing == target) {
+                continue;
+            }
+            debug!("Symlinking {} -> {}", path.display(), target.display());
+            with_retry(retry, || symlink(&target, &temp))?;
         }
+        staged.push((temp, path.to_path_buf()));
     }
+    commit_staged(&staged, retry)?;
 
-    if let Some((ts, id)) = removed_active {
-        metadata.generations.insert(ts, id);
+    let previous_generation = metadata
+        .active_generation
+        .replace(generation_id.to_string());
+    // Pipes can't be restored, so the processes serving the previous generation's are stopped
+    if let Some(previous) = previous_generation
+        .as_deref()
+        .filter(|previous| *previous != generation_id)
+    {
+        for writer in read_fifo_writers(basedir, previous) {
+            if !deployed.fifo_writers.iter().any(|w| w.pid == writer.pid) {
+                stop_fifo_writer(&writer);
+            }
+        }
+    }
+    if !cleanup {
+        info!("Leaving the links of the previous generation in place");
+    } else if let Some(previous_generation) =
+        previous_generation.filter(|previous| previous != generation_id)
+    {
+        remove_stale_links(basedir, &previous_generation, deployed.secret_files)?;
     }
 
-    let metadata_file = basedir.join("metadata.json");
+    // The manifest the generation was deployed from isn't known, so the next install redeploys
+    metadata.manifest_hash = None;
+    metadata.source_stats.clear();
+    let metadata_file = get_metadata_path(basedir, active_link);
     let metadata_file = std::fs::File::create(&metadata_file)?;
     serde_json::to_writer(metadata_file, &metadata)?;
 
     Ok(())
 }
 
-fn get_generation_path(basedir: &Path, generation_id: &str) -> std::path::PathBuf {
-    basedir.join("generations").join(generation_id)
+/// Copies a file out of a generation, keeping the owner and group it was given there
+fn copy_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::copy(source, destination)?;
+    let metadata = std::fs::metadata(source)?;
+    chown(destination, Some(metadata.uid()), Some(metadata.gid()))
 }
 
-fn get_generation_metadata_path(basedir: &Path, generation_id: &str) -> std::path::PathBuf {
-    get_generation_path(basedir, generation_id).join(".metadata.json")
+/// Copies a secret out of a generation to its link, or the directory of a wildcard secret
+fn copy_out(source: &Path, destination: &Path, retry: &RetryPolicy) -> Result<()> {
+    if source.is_dir() {
+        return carry_over(source, destination);
+    }
+    Ok(with_retry(retry, || copy_file(source, destination))?)
 }
 
-fn get_metadata(basedir: &Path) -> Result<FileSystemMetadata> {
-    let metadata_file = basedir.join("metadata.json");
-    if !metadata_file.exists() {
-        Ok(FileSystemMetadata {
-            generations: BTreeMap::new(),
-            active_generation: None,
-        })
-    } else {
-        let metadata_file = std::fs::File::open(&metadata_file)?;
-        Ok(serde_json::from_reader(metadata_file)?)
+/// Renames staged files over their links back to back. A directory can't be renamed over, so a
+/// copied directory being replaced is moved aside first and removed once everything is in place.
+fn commit_staged(
+    staged: &[(std::path::PathBuf, std::path::PathBuf)],
+    retry: &RetryPolicy,
+) -> Result<()> {
+    debug!("Moving {} staged files into place", staged.len());
+    let mut replaced = vec![];
+    for (temp, link) in staged {
+        if link.is_file() {
+            // An immutable copy from the previous generation can't be replaced until it is cleared
+            let _ = set_immutable(link, false);
+        }
+        if std::fs::symlink_metadata(link).is_ok_and(|m| m.is_dir()) {
+            let aside = temp_path(link);
+            with_retry(retry, || rename(link, &aside))?;
+            replaced.push(aside);
+        }
+        with_retry(retry, || rename(temp, link))?;
+    }
+    for directory in &replaced {
+        clear_immutable(directory);
+        std::fs::remove_dir_all(directory)?;
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-enum FilePermission {
-    Decimal(u32),
+/// Checks if two files, or directories of files, have the same contents
+fn same_contents(a: &Path, b: &Path) -> bool {
+    if a.is_dir() && b.is_dir() {
+        let entries = |path: &Path| -> std::io::Result<Vec<std::ffi::OsString>> {
+            let mut names = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|e| e.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names)
+        };
+        return match (entries(a), entries(b)) {
+            (Ok(a_names), Ok(b_names)) => {
+                a_names == b_names
+                    && a_names
+                        .iter()
+                        .all(|name| same_contents(&a.join(name), &b.join(name)))
+            }
+            _ => false,
+        };
+    }
+    match (std::fs::read(a), std::fs::read(b)) {
+        (Ok(a), Ok(b)) => Zeroizing::new(a) == Zeroizing::new(b),
+        _ => false,
+    }
 }
 
-fn set_file_permissions(
-    path: &Path,
-    permissions: Option<&str>,
-    group: Option<&str>,
-    user: Option<&str>,
+/// Copies a secret, or a directory of secrets, carried over from a previous generation
+fn carry_over(source: &Path, destination: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(source)
+        .map_err(|e| anyhow!("Could not carry {} over: {}", source.display(), e))?;
+    if !metadata.is_dir() {
+        copy_file(source, destination)?;
+        return Ok(());
+    }
+    std::fs::create_dir_all(destination)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        carry_over(&entry.path(), &destination.join(entry.file_name()))?;
+    }
+    // The permissions are copied last, as they may not allow writing the entries
+    chown(destination, Some(metadata.uid()), Some(metadata.gid()))?;
+    set_permissions(destination, metadata.permissions())?;
+    Ok(())
+}
+
+/// Atomically points the `secrets` symlink at a generation
+fn link_generation(
+    basedir: &Path,
+    active_link: &str,
+    generation_id: &str,
+    retry: &RetryPolicy,
 ) -> Result<()> {
-    debug!(
-        "Setting permissions for file: {} to {:?} {:?} {:?}",
-        path.display(),
-        permissions,
-        group,
-        user
-    );
+    debug!("Atomically symlinking to active generation");
+    let temp_file = basedir.join(Ulid::new().to_string());
+    let generation_path = get_generation_path(basedir, generation_id);
+    with_retry(retry, || symlink(&generation_path, &temp_file))?;
+    with_retry(retry, || rename(&temp_file, basedir.join(active_link)))?;
+    Ok(())
+}
 
-    let file_permissions = permissions
-        .map(|p| u32::from_str_radix(p, 8))
-        .transpose()?
-        .unwrap_or(0o600);
+/// Removes the files linked by a previous generation that aren't in `current_files`
+fn remove_stale_links(
+    basedir: &Path,
+    previous_generation: &str,
+    current_files: Vec<String>,
+) -> Result<()> {
+    debug!("Removing stale symlinks from previous generation");
+    let previous_manifest = get_generation_metadata_path(basedir, previous_generation);
+    let previous_manifest: DeployedSecretsMetadata =
+        serde_json::from_reader(std::fs::File::open(&previous_manifest)?)?;
 
-    let user = user.and_then(get_user_by_name);
-    let group = group.and_then(get_group_by_name);
+    let previous_files: HashSet<String> = HashSet::from_iter(previous_manifest.secret_files);
+    let current_files: HashSet<String> = HashSet::from_iter(current_files);
+    debug!("Previous files: {:?}", previous_files);
+    debug!("Current files: {:?}", current_files);
 
-    set_permissions(path, Permissions::from_mode(file_permissions))?;
+    let to_remove = previous_files.difference(&current_files);
+    for file in to_remove {
+        let file = Path::new(file);
+        info!("Removing stale symlink: {}", file.display());
+        if file.is_file() {
+            let _ = set_immutable(file, false);
+        }
+        let removed = if std::fs::symlink_metadata(file).is_ok_and(|m| m.is_dir()) {
+            // A wildcard secret that was copied
+            clear_immutable(file);
+            std::fs::remove_dir_all(file)
+        } else {
+            std::fs::remove_file(file)
+        };
+        if let Err(e) = removed {
+            warn!("Failed to remove file: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Removes all but the `to_keep` most recent inactive generations. The active generation is never
+/// removed, so keeping 0 leaves only the active generation.
+pub fn clean_old_generations(basedir: &Path, active_link: &str, to_keep: usize) -> Result<()> {
+    info!("Cleaning old generations");
+
+    let mut metadata = get_metadata(basedir, active_link)?;
 
-    if let Some(user) = user {
-        chown(path, Some(user.uid()), None)?;
+    // Never remove the generation the secrets are linked to, even if the metadata disagrees
+    let linked_generation = std::fs::read_link(basedir.join(active_link))
+        .ok()
+        .and_then(|target| Some(target.file_name()?.to_string_lossy().into_owned()));
+    if linked_generation.is_some() && linked_generation != metadata.active_generation {
+        warn!(
+            "The secrets are linked to generation {:?} but the active generation is {:?}",
+            linked_generation, metadata.active_generation
+        );
     }
-    if let Some(group) = group {
-        chown(path, None, Some(group.gid()))?;
-    };
+
+    // Generations are ordered oldest first, so the oldest inactive ones are removed first
+    let inactive: Vec<(u64, String)> = metadata
+        .generations
+        .iter()
+        .filter(|(_, id)| {
+            metadata.active_generation.as_ref() != Some(id)
+                && linked_generation.as_ref() != Some(id)
+        })
+        .map(|(ts, id)| (*ts, id.clone()))
+        .collect();
+    let to_remove = inactive.len().saturating_sub(to_keep);
+    let removed = &inactive[..to_remove];
+
+    // Writers carried over to the active generation still serve its pipes
+    let active_writers = metadata
+        .active_generation
+        .as_deref()
+        .map(|id| read_fifo_writers(basedir, id))
+        .unwrap_or_default();
+    for (_, id) in removed {
+        for writer in read_fifo_writers(basedir, id) {
+            if !active_writers.iter().any(|w| w.pid == writer.pid) {
+                stop_fifo_writer(&writer);
+            }
+        }
+    }
+
+    // Generations are independent directory trees, so they can be removed in parallel
+    let failed: Vec<&String> = removed
+        .par_iter()
+        .filter_map(|(_, id)| {
+            info!("Removing old generation: {}", id);
+            let path = get_generation_path(basedir, id);
+            // Immutable files can't be removed until the flag is cleared
+            clear_immutable(&path);
+            match std::fs::remove_dir_all(&path) {
+                Ok(()) => None,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!("Failed to remove file {}: {}", path.display(), e);
+                    Some(id)
+                }
+            }
+        })
+        .collect();
+    // Generations that couldn't be removed stay recorded, so the next cleanup tries again
+    for (ts, id) in removed {
+        if !failed.contains(&id) {
+            metadata.generations.remove(ts);
+        }
+    }
+    info!("Removed {} old generations", to_remove - failed.len());
+
+    let metadata_file = get_metadata_path(basedir, active_link);
+    let metadata_file = std::fs::File::create(&metadata_file)?;
+    serde_json::to_writer(metadata_file, &metadata)?;
 
     Ok(())
 }
+
+/// Renders a template from its source, decrypting its inputs. Inputs are only visible to the
+/// template that declares them.
+fn render_with_inputs<'a>(
+    template: &'a Template,
+    secrets: &mut HashMap<&'a str, TemplateSecret>,
+    identities: &Identities,
+) -> Result<String> {
+    let text = read_source(&template.source)?;
+
+    let mut shadowed = vec![];
+    for input in template.inputs.iter().flatten() {
+        debug!(
+            "Decrypting input {} of template {}",
+            input.name, template.name
+        );
+        let value = input.decrypt(identities)?;
+        let text = match &value {
+            DecryptedValue::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            value => OutputFormat::Raw.format(value)?,
+        };
+        let previous = secrets.insert(&input.name, TemplateSecret { text, value });
+        shadowed.push((input.name.as_str(), previous));
+    }
+    let text = render_template(template, text, secrets);
+    for (name, previous) in shadowed.into_iter().rev() {
+        match previous {
+            Some(previous) => secrets.insert(name, previous),
+            None => secrets.remove(name),
+        };
+    }
+    text
+}
+
+/// A file that would be deployed by a generation
+pub struct PlannedFile {
+    /// Where the file would be linked or copied to
+    pub path: String,
+    pub contents: Zeroizing<Vec<u8>>,
+}
+
+/// Decrypts the secrets and renders the templates of a generation without writing anything,
+/// returning the files that would be linked or copied
+pub fn plan_generation(
+    files: &[SecretFile],
+    templates: &[Template],
+    identities: &Identities,
+) -> Result<Vec<PlannedFile>> {
+    let mut planned = vec![];
+    let mut secrets: HashMap<&str, TemplateSecret> = HashMap::new();
+    for secret_file in files {
+        if secret_file.is_wildcard() {
+            let Some(link) = &secret_file.link else {
+                continue;
+            };
+            let format = secret_file.format.as_ref().unwrap_or(&OutputFormat::Raw);
+            for (path, value) in secret_file.decrypt_all(identities)? {
+                let contents = match value {
+                    DecryptedValue::Bytes(bytes) => bytes,
+                    DecryptedValue::Comment(_) => continue,
+                    value => format.format(&value)?.into_bytes(),
+                };
+                planned.push(PlannedFile {
+                    path: Path::new(link).join(path).to_string_lossy().into_owned(),
+                    contents: Zeroizing::new(contents),
+                });
+            }
+            continue;
+        }
+        let Some(decrypted) = secret_file.decrypt(identities)? else {
+            continue;
+        };
+        let contents = match decrypted {
+            DecryptedValue::Bytes(bytes) => bytes,
+            DecryptedValue::Comment(_) => continue,
+            value => {
+                let format = secret_file.format.as_ref().unwrap_or(&OutputFormat::Raw);
+                let text = format.format(&value)?;
+                let contents = text.clone().into_bytes();
+                secrets.insert(&secret_file.name, TemplateSecret { text, value });
+                contents
+            }
+        };
+        if let Some(link) = &secret_file.link {
+            planned.push(PlannedFile {
+                path: link.clone(),
+                contents: Zeroizing::new(contents),
+            });
+        }
+    }
+    for template in templates {
+        let text = render_with_inputs(template, &mut secrets, identities)?;
+        planned.push(PlannedFile {
+            path: template.destination.clone(),
+            contents: Zeroizing::new(text.into_bytes()),
+        });
+    }
+    Ok(planned)
+}
+
+pub fn render_template(
+    template: &Template,
+    text: String,
+    secrets: &HashMap<&str, TemplateSecret>,
+) -> Result<String> {
+    // Template engines get the secrets with their original types
+    let values: HashMap<&str, &DecryptedValue> =
+        secrets.iter().map(|(k, v)| (*k, &v.value)).collect();
+    match template.engine.as_ref().unwrap_or(&TemplateEngine::Literal) {
+        TemplateEngine::Literal => {
+            let pattern = literal_pattern(template)?;
+            let rendered = pattern.replace_all(&text, |captures: &Captures| {
+                let key = &captures[1];
+                match secrets.get(key) {
+                    Some(secret) => secret.text.clone(),
+                    None => {
+                        warn!(
+                            "Template {} references unknown secret {}",
+                            template.name, key
+                        );
+                        captures[0].to_string()
+                    }
+                }
+            });
+            Ok(rendered.into_owned())
+        }
+        TemplateEngine::Tera => {
+            let context = tera::Context::from_serialize(&values)?;
+            tera::Tera::one_off(&text, &context, false).map_err(|e| {
+                anyhow!(
+                    "Failed to render template {}: {:#}",
+                    template.name,
+                    anyhow!(e)
+                )
+            })
+        }
+        TemplateEngine::Handlebars => handlebars::Handlebars::new()
+            .render_template(&text, &values)
+            .map_err(|e| anyhow!("Failed to render template {}: {}", template.name, e)),
+    }
+}
+
+/// Builds the pattern matching the placeholders of a literal template, capturing the secret name
+fn literal_pattern(template: &Template) -> Result<Regex> {
+    let placeholder = template
+        .placeholder
+        .as_deref()
+        .unwrap_or(DEFAULT_PLACEHOLDER);
+    let Some((prefix, suffix)) = placeholder.split_once("{key}") else {
+        return Err(anyhow!(
+            "Placeholder {:?} of template {} does not contain {{key}}",
+            placeholder,
+            template.name
+        ));
+    };
+    let pattern = format!(
+        r"{}([\w.-]+){}",
+        regex::escape(prefix),
+        regex::escape(suffix)
+    );
+    Ok(Regex::new(&pattern)?)
+}
+
+/// Checks that a template can be rendered without decrypting anything. The source is parsed by
+/// the configured engine, and for literal templates every placeholder is checked against the
+/// names in `known`. Returns the names that don't resolve to a secret.
+pub fn validate_template(template: &Template, known: &HashSet<&str>) -> Result<Vec<String>> {
+    let text = read_source(&template.source)?;
+    let mut unresolved = vec![];
+    match template.engine.as_ref().unwrap_or(&TemplateEngine::Literal) {
+        TemplateEngine::Literal => {
+            let pattern = literal_pattern(template)?;
+            for captures in pattern.captures_iter(&text) {
+                let key = &captures[1];
+                if !known.contains(key) && !unresolved.iter().any(|u| u == key) {
+                    unresolved.push(key.to_string());
+                }
+            }
+        }
+        TemplateEngine::Tera => {
+            tera::Tera::default()
+                .add_raw_template(&template.name, &text)
+                .map_err(|e| anyhow!("Failed to parse template: {:#}", anyhow!(e)))?;
+        }
+        TemplateEngine::Handlebars => {
+            handlebars::Template::compile(&text)
+                .map_err(|e| anyhow!("Failed to parse template: {}", e))?;
+        }
+    }
+    Ok(unresolved)
+}
+
+/// Runs a filesystem operation, retrying with exponential backoff if it fails with a transient
+/// error. Logical errors such as `AlreadyExists` or `PermissionDenied` are returned immediately.
+fn with_retry<T>(
+    policy: &RetryPolicy,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(e) if is_transient(&e) && attempt < policy.attempts => {
+                let backoff = policy.backoff_ms.saturating_mul(1 << (attempt - 1).min(16));
+                warn!(
+                    "Transient filesystem error (attempt {}/{}), retrying in {}ms: {}",
+                    attempt, policy.attempts, backoff, e
+                );
+                std::thread::sleep(Duration::from_millis(backoff));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Creates a directory and its missing parents, giving the directories it creates to the default
+/// owner and group so the files deployed for a target user can be reached by them. Directories
+/// that already exist are left as they are.
+fn create_owned_dir_all(path: &Path, defaults: &FileDefaults, retry: &RetryPolicy) -> Result<()> {
+    let missing: Vec<&Path> = path.ancestors().take_while(|p| !p.exists()).collect();
+    with_retry(retry, || std::fs::create_dir_all(path))?;
+
+    let (user, group) = split_owner(
+        defaults.default_owner.as_deref(),
+        defaults.default_group.as_deref(),
+    );
+    let uid = user.and_then(resolve_uid);
+    let gid = group.and_then(resolve_gid);
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    for directory in missing.iter().rev() {
+        debug!("Giving {} to {:?}:{:?}", directory.display(), uid, gid);
+        chown(directory, uid, gid)?;
+    }
+    Ok(())
+}
+
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+    ) || error.raw_os_error() == Some(libc::EBUSY)
+}
+
+/// Removes the file at the provided path if its kind (symlink or regular file) differs from the
+/// kind that is about to be written there, such as when a template's copy mode was toggled
+fn remove_mismatched_kind(path: &Path, expect_symlink: bool) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    let is_symlink = metadata.file_type().is_symlink();
+    if is_symlink != expect_symlink {
+        let kind = |is_link| if is_link { "symlink" } else { "file" };
+        warn!(
+            "{} is a {} but a {} is expected, replacing it",
+            path.display(),
+            kind(is_symlink),
+            kind(expect_symlink)
+        );
+        if metadata.is_dir() {
+            // A wildcard secret that was copied
+            clear_immutable(path);
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Archives a generation's directory, including its metadata, into a tarball
+pub fn export_generation(basedir: &Path, generation_id: &str, output: &Path) -> Result<()> {
+    let generation_directory = get_generation_path(basedir, generation_id);
+    if !get_generation_metadata_path(basedir, generation_id).exists() {
+        return Err(anyhow!("Generation {} does not exist", generation_id));
+    }
+
+    let mut builder = tar::Builder::new(std::fs::File::create(output)?);
+    builder.mode(tar::HeaderMode::Complete);
+    builder.append_dir_all(generation_id, &generation_directory)?;
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+/// Unpacks a generation exported by [export_generation] into the store and registers it, returning
+/// its id. The generation is not activated.
+pub fn import_generation(basedir: &Path, active_link: &str, archive: &Path) -> Result<String> {
+    let generations_directory = basedir.join("generations");
+    std::fs::create_dir_all(&generations_directory)?;
+
+    // Unpack next to the other generations so the final move is a rename on the same filesystem
+    let staging = generations_directory.join(format!(".import-{}", Ulid::new()));
+    let result = unpack_generation(basedir, active_link, archive, &staging);
+    if let Err(e) = std::fs::remove_dir_all(&staging) {
+        debug!(
+            "Failed to remove staging directory {}: {}",
+            staging.display(),
+            e
+        );
+    }
+    result
+}
+
+fn unpack_generation(
+    basedir: &Path,
+    active_link: &str,
+    archive: &Path,
+    staging: &Path,
+) -> Result<String> {
+    let mut tarball = tar::Archive::new(std::fs::File::open(archive)?);
+    tarball.set_preserve_permissions(true);
+    tarball.unpack(staging)?;
+
+    let entries = std::fs::read_dir(staging)?.collect::<Result<Vec<_>, _>>()?;
+    let [entry] = &entries[..] else {
+        return Err(anyhow!(
+            "{} does not contain exactly one generation",
+            archive.display()
+        ));
+    };
+    let generation_id = entry.file_name().to_string_lossy().into_owned();
+    // Generations are ordered by creation time, which is embedded in the ULID
+    let ulid = Ulid::from_string(&generation_id)
+        .map_err(|e| anyhow!("{} is not a generation id: {}", generation_id, e))?;
+
+    let deployed: DeployedSecretsMetadata =
+        serde_json::from_reader(std::fs::File::open(entry.path().join(".metadata.json"))?)?;
+    if deployed.generation != generation_id {
+        return Err(anyhow!(
+            "Generation id {} in the metadata does not match the archived directory {}",
+            deployed.generation,
+            generation_id
+        ));
+    }
+
+    let mut metadata = get_metadata(basedir, active_link)?;
+    let target = get_generation_path(basedir, &generation_id);
+    if target.exists() || metadata.generations.values().any(|g| g == &generation_id) {
+        return Err(anyhow!("Generation {} already exists", generation_id));
+    }
+
+    let mut time = ulid.timestamp_ms() / 1000;
+    while metadata.generations.contains_key(&time) {
+        time += 1;
+    }
+    metadata.generations.insert(time, generation_id.clone());
+
+    rename(entry.path(), &target)?;
+    let metadata_file = get_metadata_path(basedir, active_link);
+    let written = std::fs::File::create(&metadata_file)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| Ok(serde_json::to_writer(file, &metadata)?));
+    if let Err(e) = written {
+        // Move the generation back so it is removed with the staging directory
+        if let Err(e) = rename(&target, entry.path()) {
+            warn!(
+                "Failed to remove the unregistered generation {}: {}",
+                target.display(),
+                e
+            );
+        }
+        return Err(e);
+    }
+
+    Ok(generation_id)
+}
+
+/// A generation that has been deployed
+#[derive(Debug, Serialize)]
+pub struct GenerationInfo {
+    pub id: String,
+    /// The unix timestamp the generation was deployed at
+    pub deployed_at: u64,
+    pub active: bool,
+}
+
+/// Records that an install finished successfully just now
+pub fn record_install(basedir: &Path, active_link: &str) -> Result<()> {
+    let mut metadata = get_metadata(basedir, active_link)?;
+    metadata.last_install = Some(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+    );
+    let metadata_file = get_metadata_path(basedir, active_link);
+    let metadata_file = std::fs::File::create(&metadata_file)?;
+    serde_json::to_writer(metadata_file, &metadata)?;
+    Ok(())
+}
+
+/// Gets the unix timestamp of the last successful install, or None if there hasn't been one
+pub fn get_last_install(basedir: &Path, active_link: &str) -> Result<Option<u64>> {
+    Ok(get_metadata(basedir, active_link)?.last_install)
+}
+
+/// Lists the deployed generations oldest first, optionally only those deployed at or after
+/// `since` and at most the most recent `limit`
+pub fn list_generations(
+    basedir: &Path,
+    active_link: &str,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> Result<Vec<GenerationInfo>> {
+    let metadata = get_metadata(basedir, active_link)?;
+    let mut generations: Vec<GenerationInfo> = metadata
+        .generations
+        .range(since.unwrap_or(0)..)
+        .map(|(time, id)| GenerationInfo {
+            id: id.clone(),
+            deployed_at: *time,
+            active: metadata.active_generation.as_ref() == Some(id),
+        })
+        .collect();
+    if let Some(limit) = limit {
+        generations.drain(..generations.len().saturating_sub(limit));
+    }
+    Ok(generations)
+}
+
+/// A generation in which a secret or template changed
+#[derive(Debug, Serialize)]
+pub struct SecretChange {
+    pub generation: String,
+    /// The unix timestamp the generation was deployed at
+    pub deployed_at: u64,
+}
+
+/// Lists the generations in which the contents of a secret or template changed, oldest first.
+/// The first generation it was deployed in counts as a change.
+pub fn secret_history(basedir: &Path, active_link: &str, name: &str) -> Result<Vec<SecretChange>> {
+    let metadata = get_metadata(basedir, active_link)?;
+    Ok(metadata
+        .changes
+        .into_iter()
+        .filter(|(_, changes)| changes.changed.iter().any(|n| n == name))
+        .map(|(time, changes)| SecretChange {
+            generation: changes.generation,
+            deployed_at: time,
+        })
+        .collect())
+}
+
+/// Gets the paths of the files linked by the active generation, or None if there is no active
+/// generation
+pub fn get_active_secret_files(basedir: &Path, active_link: &str) -> Result<Option<Vec<String>>> {
+    let metadata = get_metadata(basedir, active_link)?;
+    let Some(active_generation) = metadata.active_generation else {
+        return Ok(None);
+    };
+    let metadata_file = get_generation_metadata_path(basedir, &active_generation);
+    let deployed: DeployedSecretsMetadata =
+        serde_json::from_reader(std::fs::File::open(&metadata_file)?)?;
+    Ok(Some(deployed.secret_files))
+}
+
+/// Gets the source stats recorded for the active generation
+pub fn get_source_stats(basedir: &Path, active_link: &str) -> Result<BTreeMap<String, SourceStat>> {
+    Ok(get_metadata(basedir, active_link)?.source_stats)
+}
+
+/// Records the source stats of the active generation, replacing the previous ones
+pub fn record_source_stats(
+    basedir: &Path,
+    active_link: &str,
+    stats: BTreeMap<String, SourceStat>,
+) -> Result<()> {
+    let mut metadata = get_metadata(basedir, active_link)?;
+    metadata.source_stats = stats;
+    let metadata_file = get_metadata_path(basedir, active_link);
+    let metadata_file = std::fs::File::create(&metadata_file)?;
+    serde_json::to_writer(metadata_file, &metadata)?;
+    Ok(())
+}
+
+/// Checks if the active generation was deployed from a manifest with the provided hash
+pub fn is_generation_current(
+    basedir: &Path,
+    active_link: &str,
+    manifest_hash: &str,
+) -> Result<bool> {
+    let metadata = get_metadata(basedir, active_link)?;
+    let Some(active_generation) = metadata.active_generation else {
+        return Ok(false);
+    };
+    Ok(metadata.manifest_hash.as_deref() == Some(manifest_hash)
+        && get_generation_path(basedir, &active_generation).exists())
+}
+
+/// How long a pipe waits for a reader before the process serving it gives up
+const FIFO_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How often the process serving a pipe checks for a reader
+const FIFO_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Creates a named pipe at the path and forks a process that writes the data to the first reader
+/// and exits. The process gives up if nothing reads the pipe within [FIFO_TIMEOUT], or once the
+/// pipe is replaced or removed.
+fn serve_fifo(path: &Path, data: &[u8]) -> Result<FifoWriter> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut fifo: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::stat(c_path.as_ptr(), &mut fifo) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => {
+            // Only async-signal-safe calls are made in the child, as other threads may hold locks
+            unsafe {
+                // Opening the write end without blocking fails with ENXIO until there is a reader
+                let mut waited = Duration::ZERO;
+                let fd = loop {
+                    let fd = libc::open(c_path.as_ptr(), libc::O_WRONLY | libc::O_NONBLOCK);
+                    if fd >= 0
+                        || waited >= FIFO_TIMEOUT
+                        || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENXIO)
+                    {
+                        break fd;
+                    }
+                    std::thread::sleep(FIFO_POLL_INTERVAL);
+                    waited += FIFO_POLL_INTERVAL;
+                };
+                let mut stat: libc::stat = std::mem::zeroed();
+                // Whatever replaced the pipe while waiting isn't written to
+                if fd >= 0
+                    && libc::fstat(fd, &mut stat) == 0
+                    && stat.st_dev == fifo.st_dev
+                    && stat.st_ino == fifo.st_ino
+                {
+                    let flags = libc::fcntl(fd, libc::F_GETFL);
+                    libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+                    let mut written = 0;
+                    while written < data.len() {
+                        let n = libc::write(
+                            fd,
+                            data[written..].as_ptr() as *const libc::c_void,
+                            data.len() - written,
+                        );
+                        if n <= 0 {
+                            break;
+                        }
+                        written += n as usize;
+                    }
+                }
+                if fd >= 0 {
+                    libc::close(fd);
+                }
+                libc::_exit(0)
+            }
+        }
+        pid => {
+            debug!("Serving {} from process {}", path.display(), pid);
+            Ok(FifoWriter {
+                path: path.to_string_lossy().into_owned(),
+                pid,
+                started: process_start_time(pid),
+            })
+        }
+    }
+}
+
+/// Gets the processes serving the pipes of a generation
+fn read_fifo_writers(basedir: &Path, generation_id: &str) -> Vec<FifoWriter> {
+    std::fs::File::open(get_generation_metadata_path(basedir, generation_id))
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, DeployedSecretsMetadata>(file).ok())
+        .map(|deployed| deployed.fifo_writers)
+        .unwrap_or_default()
+}
+
+/// Stops a process serving a pipe if it is still running. A process that reused its id is left
+/// alone, which is told apart by when it started.
+fn stop_fifo_writer(writer: &FifoWriter) {
+    if writer.started.is_none() || process_start_time(writer.pid) != writer.started {
+        return;
+    }
+    debug!("Stopping process {} serving {}", writer.pid, writer.path);
+    unsafe {
+        libc::kill(writer.pid, libc::SIGTERM);
+        // Reaps the process if this run forked it, otherwise this fails right away
+        libc::waitpid(writer.pid, std::ptr::null_mut(), 0);
+    }
+}
+
+/// Gets when a process started, in clock ticks since boot
+fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command may contain spaces and parentheses, so the fields are counted from its end.
+    // The start time is the 22nd field and the state, the first after the command, is the 3rd.
+    let fields = stat.rsplit_once(')')?.1;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The inode flag making a file immutable, from `linux/fs.h`
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// Sets or clears the immutable flag of a file, like `chattr +i`
+#[cfg(target_os = "linux")]
+fn set_immutable(path: &Path, immutable: bool) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    debug!("Setting immutable to {} on {}", immutable, path.display());
+    let file = std::fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let updated = if immutable {
+        flags | FS_IMMUTABLE_FL
+    } else {
+        flags & !FS_IMMUTABLE_FL
+    };
+    if updated == flags {
+        return Ok(());
+    }
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &updated) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(anyhow!(
+            "Failed to change the immutable flag of {}, which requires CAP_LINUX_IMMUTABLE: {}",
+            path.display(),
+            e
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_immutable(path: &Path, _immutable: bool) -> Result<()> {
+    Err(anyhow!(
+        "Can't make {} immutable, immutable files are only supported on Linux",
+        path.display()
+    ))
+}
+
+/// Makes a copied secret immutable, or every file of a copied wildcard secret
+fn set_immutable_all(path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return set_immutable(path, true);
+    }
+    for entry in std::fs::read_dir(path)? {
+        set_immutable_all(&entry?.path())?;
+    }
+    Ok(())
+}
+
+/// Clears the immutable flag of every file in a generation so it can be removed
+fn clear_immutable(directory: &Path) {
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => clear_immutable(&path),
+            Ok(file_type) if file_type.is_file() => {
+                if let Err(e) = set_immutable(&path, false) {
+                    debug!(
+                        "Failed to clear immutable flag of {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Copies the extended attributes of a file, which include its POSIX ACLs. Attributes that can't be
+/// copied are skipped with a warning.
+fn copy_xattrs(from: &Path, to: &Path) {
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Failed to list attributes of {}: {}", from.display(), e);
+            return;
+        }
+    };
+    for name in names {
+        debug!("Copying attribute {:?} to {}", name, to.display());
+        let result = xattr::get(from, &name)
+            .and_then(|value| xattr::set(to, &name, &value.unwrap_or_default()));
+        if let Err(e) = result {
+            warn!(
+                "Failed to copy attribute {:?} to {}: {}",
+                name,
+                to.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Checks that a link or template destination can be created safely. It can't be nested more than
+/// `MAX_LINK_DEPTH` deep or have a parent that is reached through a symlink loop, and with a root
+/// it has to resolve inside the root, following any symlinks that already exist.
+pub fn validate_link_path(link: &Path, root: Option<&Path>) -> Result<()> {
+    use std::path::Component;
+
+    let depth = link.components().count();
+    if depth > MAX_LINK_DEPTH {
+        return Err(anyhow!(
+            "{} is {} components deep, more than the maximum of {}",
+            link.display(),
+            depth,
+            MAX_LINK_DEPTH
+        ));
+    }
+    if !matches!(link.components().next_back(), Some(Component::Normal(_))) {
+        return Err(anyhow!("{} does not name a file", link.display()));
+    }
+
+    // Resolve the deepest parent that exists, the rest is created as plain directories
+    let mut existing = link.parent();
+    let resolved = loop {
+        let Some(ancestor) = existing else {
+            break None;
+        };
+        match std::fs::canonicalize(ancestor) {
+            Ok(resolved) => break Some((ancestor, resolved)),
+            Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                return Err(anyhow!(
+                    "{} is reached through a symlink loop at {}",
+                    link.display(),
+                    ancestor.display()
+                ));
+            }
+            Err(_) => existing = ancestor.parent(),
+        }
+    };
+
+    let Some(root) = root else {
+        return Ok(());
+    };
+    let root = std::fs::canonicalize(root)
+        .map_err(|e| anyhow!("Could not resolve the root {}: {}", root.display(), e))?;
+    let Some((ancestor, resolved)) = resolved else {
+        return Err(anyhow!(
+            "{} is outside of {}",
+            link.display(),
+            root.display()
+        ));
+    };
+    let rest = link.strip_prefix(ancestor)?;
+    if !resolved.starts_with(&root)
+        || rest
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "{} is outside of {}",
+            link.display(),
+            root.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Finds a path that is the link of more than one secret or the destination of more than one
+/// template
+pub fn find_duplicate_link<'a>(
+    files: &'a [SecretFile],
+    templates: &'a [Template],
+) -> Option<&'a str> {
+    let mut seen = HashSet::new();
+    files
+        .iter()
+        .filter_map(|f| f.link.as_deref())
+        .chain(templates.iter().map(|t| t.destination.as_str()))
+        .find(|link| !seen.insert(*link))
+}
+
+/// Gets a unique temporary path next to the link, so links sharing a stem don't collide
+pub(crate) fn temp_path(link: &Path) -> std::path::PathBuf {
+    let file_name = link.file_name().unwrap_or_default().to_string_lossy();
+    link.with_file_name(format!(".{}.{}.tmp", file_name, Ulid::new()))
+}
+
+fn get_generation_path(basedir: &Path, generation_id: &str) -> std::path::PathBuf {
+    basedir.join("generations").join(generation_id)
+}
+
+fn get_generation_metadata_path(basedir: &Path, generation_id: &str) -> std::path::PathBuf {
+    get_generation_path(basedir, generation_id).join(".metadata.json")
+}
+
+/// Gets the metadata file of the store whose active generation is linked by `active_link`. Stores
+/// sharing a base directory each have their own metadata, so they don't clean up each other's
+/// generations.
+fn get_metadata_path(basedir: &Path, active_link: &str) -> std::path::PathBuf {
+    if active_link == DEFAULT_ACTIVE_LINK {
+        basedir.join("metadata.json")
+    } else {
+        basedir.join(format!("{}.metadata.json", active_link))
+    }
+}
+
+fn get_metadata(basedir: &Path, active_link: &str) -> Result<FileSystemMetadata> {
+    let metadata_file = get_metadata_path(basedir, active_link);
+    if !metadata_file.exists() {
+        Ok(FileSystemMetadata {
+            generations: BTreeMap::new(),
+            active_generation: None,
+            manifest_hash: None,
+            source_stats: BTreeMap::new(),
+            last_install: None,
+            changes: BTreeMap::new(),
+        })
+    } else {
+        let metadata_file = std::fs::File::open(&metadata_file)?;
+        Ok(serde_json::from_reader(metadata_file)?)
+    }
+}
+
+/// Splits an owner given as `user:group` into its parts. An explicit group takes precedence over
+/// the group in the owner.
+pub fn split_owner<'a>(
+    user: Option<&'a str>,
+    group: Option<&'a str>,
+) -> (Option<&'a str>, Option<&'a str>) {
+    match user.and_then(|u| u.split_once(':')) {
+        Some((user, owner_group)) => (
+            Some(user).filter(|u| !u.is_empty()),
+            group.or(Some(owner_group).filter(|g| !g.is_empty())),
+        ),
+        None => (user, group),
+    }
+}
+
+/// Looks up the id of a name in a file in the format of `/etc/passwd` or `/etc/group`
+pub fn lookup_id(file: &Path, name: &str) -> Result<Option<u32>> {
+    let contents = std::fs::read_to_string(file)?;
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&name))
+        .and_then(|fields| fields.get(2)?.parse().ok()))
+}
+
+/// Resolves a user name or numeric id to a uid
+pub fn resolve_uid(user: &str) -> Option<u32> {
+    get_user_by_name(user)
+        .map(|u| u.uid())
+        .or_else(|| user.parse().ok())
+}
+
+/// Resolves a group name or numeric id to a gid
+pub fn resolve_gid(group: &str) -> Option<u32> {
+    get_group_by_name(group)
+        .map(|g| g.gid())
+        .or_else(|| group.parse().ok())
+}
+
+fn set_file_permissions(
+    path: &Path,
+    permissions: Option<&str>,
+    group: Option<&str>,
+    user: Option<&str>,
+) -> Result<()> {
+    debug!(
+        "Setting permissions for file: {} to {:?} {:?} {:?}",
+        path.display(),
+        permissions,
+        group,
+        user
+    );
+
+    let file_permissions = permissions
+        .map(|p| u32::from_str_radix(p, 8))
+        .transpose()?
+        .unwrap_or(0o600);
+
+    let (user, group) = split_owner(user, group);
+
+    let uid = user.and_then(|u| {
+        let uid = resolve_uid(u);
+        if uid.is_none() {
+            warn!("Unknown user: {}", u);
+        }
+        uid
+    });
+    let gid = group.and_then(|g| {
+        let gid = resolve_gid(g);
+        if gid.is_none() {
+            warn!("Unknown group: {}", g);
+        }
+        gid
+    });
+
+    set_permissions(path, Permissions::from_mode(file_permissions))?;
+
+    if uid.is_some() || gid.is_some() {
+        chown(path, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::MetadataExt, path::PathBuf};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{identities, identity, Scratch, Sops};
+
+    /// Deploys a new generation with the default policies
+    fn deploy(basedir: &Path, files: Vec<SecretFile>, templates: Vec<Template>) -> String {
+        activate_new_generation(
+            basedir,
+            DEFAULT_ACTIVE_LINK,
+            files,
+            templates,
+            &FileDefaults::default(),
+            &Identities::new(vec![]),
+            "hash",
+            &RetryPolicy::default(),
+            true,
+            &[],
+        )
+        .unwrap()
+    }
+
+    /// Deploys a new generation of secrets decrypted with the identities
+    fn deploy_secrets(
+        basedir: &Path,
+        files: Vec<SecretFile>,
+        defaults: &FileDefaults,
+        identities: &Identities,
+    ) -> String {
+        activate_new_generation(
+            basedir,
+            DEFAULT_ACTIVE_LINK,
+            files,
+            vec![],
+            defaults,
+            identities,
+            "hash",
+            &RetryPolicy::default(),
+            true,
+            &[],
+        )
+        .unwrap()
+    }
+
+    /// Lists the generation directories on disk, oldest first
+    fn generations(basedir: &Path) -> Vec<String> {
+        let mut generations = std::fs::read_dir(basedir.join("generations"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        generations.sort();
+        generations
+    }
+
+    #[test]
+    fn copied_templates_survive_cleanup() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        // The rendered file is named after the template, not its source
+        let source = scratch.write("templates/app.conf.in", "first");
+        let destination = scratch.join("etc/app.conf");
+        let template = || {
+            serde_json::from_value::<Template>(json!({
+                "name": "app.conf",
+                "source": source,
+                "destination": destination,
+                "copy": true,
+            }))
+            .unwrap()
+        };
+
+        deploy(&basedir, vec![], vec![template()]);
+        std::fs::write(&source, "second").unwrap();
+        let active = deploy(&basedir, vec![], vec![template()]);
+        clean_old_generations(&basedir, DEFAULT_ACTIVE_LINK, 0).unwrap();
+
+        assert_eq!(generations(&basedir), vec![active]);
+        assert!(std::fs::symlink_metadata(&destination).unwrap().is_file());
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "second");
+    }
+
+    fn template(scratch: &Scratch, name: &str, contents: &str) -> Template {
+        serde_json::from_value(json!({
+            "name": name,
+            "source": scratch.write(&format!("templates/{}", name), contents),
+            "destination": scratch.join(&format!("etc/{}", name)),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn exported_generation_imports_into_another_store() {
+        let scratch = Scratch::new();
+        let source = scratch.path().join("source");
+        let destination = scratch.path().join("destination");
+        let archive = scratch.path().join("generation.tar");
+        let id = deploy(
+            &source,
+            vec![],
+            vec![template(&scratch, "app.conf", "value")],
+        );
+
+        export_generation(&source, &id, &archive).unwrap();
+        assert_eq!(
+            import_generation(&destination, DEFAULT_ACTIVE_LINK, &archive).unwrap(),
+            id
+        );
+
+        let imported = list_generations(&destination, DEFAULT_ACTIVE_LINK, None, None).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].id, id);
+        assert!(!imported[0].active);
+        let rendered = get_generation_path(&destination, &id).join("rendered/app.conf");
+        assert_eq!(std::fs::read_to_string(rendered).unwrap(), "value");
+
+        // Importing it again collides with the imported generation and leaves nothing behind
+        let error = import_generation(&destination, DEFAULT_ACTIVE_LINK, &archive).unwrap_err();
+        assert!(error.to_string().contains("already exists"));
+        assert_eq!(generations(&destination), vec![id]);
+    }
+
+    #[test]
+    fn import_rejects_directories_that_are_not_generation_ids() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let directory = scratch.path().join("export/not-a-ulid");
+        std::fs::create_dir_all(&directory).unwrap();
+        let metadata = DeployedSecretsMetadata {
+            generation: "not-a-ulid".to_string(),
+            secret_files: vec![],
+            links: vec![],
+            fifo_writers: vec![],
+        };
+        std::fs::write(
+            directory.join(".metadata.json"),
+            serde_json::to_string(&metadata).unwrap(),
+        )
+        .unwrap();
+        let archive = scratch.path().join("generation.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+        builder.append_dir_all("not-a-ulid", &directory).unwrap();
+        builder.finish().unwrap();
+
+        let error = import_generation(&basedir, DEFAULT_ACTIVE_LINK, &archive).unwrap_err();
+        assert!(error.to_string().contains("not a generation id"));
+        assert!(generations(&basedir).is_empty());
+        assert!(list_generations(&basedir, DEFAULT_ACTIVE_LINK, None, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Deploys `count` generations of a template, returning their ids oldest first
+    fn deploy_generations(scratch: &Scratch, basedir: &Path, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| {
+                let contents = format!("value {}", i);
+                deploy(
+                    basedir,
+                    vec![],
+                    vec![template(scratch, "app.conf", &contents)],
+                )
+            })
+            .collect()
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn cleanup_keeps_the_requested_generations() {
+        for (keep, kept) in [(0, 1), (1, 2), (5, 3)] {
+            let scratch = Scratch::new();
+            let basedir = scratch.path().join("secrets");
+            let ids = deploy_generations(&scratch, &basedir, 3);
+
+            clean_old_generations(&basedir, DEFAULT_ACTIVE_LINK, keep).unwrap();
+
+            let expected = ids[ids.len() - kept..].to_vec();
+            let listed = list_generations(&basedir, DEFAULT_ACTIVE_LINK, None, None).unwrap();
+            let listed: Vec<String> = listed.into_iter().map(|g| g.id).collect();
+            assert_eq!(listed, expected, "keeping {}", keep);
+            assert_eq!(generations(&basedir), sorted(expected), "keeping {}", keep);
+        }
+    }
+
+    #[test]
+    fn cleanup_removes_several_generations() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let ids = deploy_generations(&scratch, &basedir, 5);
+
+        clean_old_generations(&basedir, DEFAULT_ACTIVE_LINK, 0).unwrap();
+
+        assert_eq!(generations(&basedir), vec![ids[4].clone()]);
+        let listed = list_generations(&basedir, DEFAULT_ACTIVE_LINK, None, None).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].active);
+    }
+
+    #[test]
+    fn generations_that_fail_to_be_removed_stay_recorded() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let ids = deploy_generations(&scratch, &basedir, 4);
+        // A file where the generation's directory should be can't be removed as a directory
+        let broken = get_generation_path(&basedir, &ids[1]);
+        std::fs::remove_dir_all(&broken).unwrap();
+        std::fs::write(&broken, "").unwrap();
+
+        clean_old_generations(&basedir, DEFAULT_ACTIVE_LINK, 0).unwrap();
+
+        let listed = list_generations(&basedir, DEFAULT_ACTIVE_LINK, None, None).unwrap();
+        let listed: Vec<String> = listed.into_iter().map(|g| g.id).collect();
+        assert_eq!(listed, vec![ids[1].clone(), ids[3].clone()]);
+        assert_eq!(
+            generations(&basedir),
+            sorted(vec![ids[1].clone(), ids[3].clone()])
+        );
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn created_directories_belong_to_the_default_owner() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let template: Template = serde_json::from_value(json!({
+            "name": "app.conf",
+            "source": scratch.write("app.conf.in", "value"),
+            "destination": scratch.join("home/app/.config/app.conf"),
+        }))
+        .unwrap();
+        // The fallback a target user without a passwd entry on this system would get
+        let defaults = FileDefaults {
+            default_mode: None,
+            default_owner: Some("65534".to_string()),
+            default_group: Some("65534".to_string()),
+        };
+
+        let id = activate_new_generation(
+            &basedir,
+            DEFAULT_ACTIVE_LINK,
+            vec![],
+            vec![template],
+            &defaults,
+            &Identities::new(vec![]),
+            "hash",
+            &RetryPolicy::default(),
+            true,
+            &[],
+        )
+        .unwrap();
+
+        let generation = get_generation_path(&basedir, &id);
+        for path in [
+            generation.clone(),
+            generation.join("rendered"),
+            generation.join("rendered/app.conf"),
+            scratch.path().join("home/app/.config"),
+            scratch.path().join("home"),
+        ] {
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(
+                (metadata.uid(), metadata.gid()),
+                (65534, 65534),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn binary_files_are_written_byte_for_byte() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let identity = identity();
+        let payload = b"\0key\0\0with\nnuls\0";
+        let plaintext = serde_yaml::to_string(&serde_yaml::Mapping::from_iter([(
+            "data".into(),
+            std::str::from_utf8(payload).unwrap().into(),
+        )]))
+        .unwrap();
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "binary",
+            "name": "tls.key",
+            "source": scratch.write("tls.key.json", Sops::new(&identity).yaml(&plaintext)),
+        }))
+        .unwrap();
+
+        let id = deploy_secrets(
+            &basedir,
+            vec![secret],
+            &FileDefaults::default(),
+            &identities(&identity),
+        );
+
+        let written = std::fs::read(get_generation_path(&basedir, &id).join("tls.key")).unwrap();
+        assert_eq!(written, payload);
+    }
+
+    #[test]
+    fn checksums_are_verified() {
+        let secret = |sha256: &str| -> SecretFile {
+            serde_json::from_value(json!({
+                "type": "yaml",
+                "name": "greeting",
+                "source": "greeting.yaml",
+                "key": "greeting",
+                "sha256": sha256,
+            }))
+            .unwrap()
+        };
+        let hello = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+
+        verify_checksum(&secret(hello), b"hello").unwrap();
+        let error = verify_checksum(&secret(hello), b"goodbye").unwrap_err();
+        assert!(error.to_string().contains("Checksum mismatch for greeting"));
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff_ms: 1,
+        };
+
+        let mut attempts = 0;
+        let result = with_retry(&policy, || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+            } else {
+                Ok("written")
+            }
+        });
+        assert_eq!(result.unwrap(), "written");
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let result: std::io::Result<()> = with_retry(&policy, || {
+            attempts += 1;
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn secret_modes_fall_back_to_the_default_mode() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let identity = identity();
+        let source = scratch.write(
+            "secrets.yaml",
+            Sops::new(&identity).yaml("password: hunter2\n"),
+        );
+        let secret = |name: &str, mode: Option<&str>| -> SecretFile {
+            serde_json::from_value(json!({
+                "type": "yaml",
+                "name": name,
+                "source": source,
+                "key": "password",
+                "mode": mode,
+            }))
+            .unwrap()
+        };
+        let mode = |id: &str, name: &str| {
+            let path = get_generation_path(&basedir, id).join(name);
+            std::fs::metadata(path).unwrap().mode() & 0o7777
+        };
+
+        let defaults = FileDefaults {
+            default_mode: Some("0640".to_string()),
+            ..FileDefaults::default()
+        };
+        let files = vec![secret("own", Some("0400")), secret("default", None)];
+        let id = deploy_secrets(&basedir, files, &defaults, &identities(&identity));
+        assert_eq!(mode(&id, "own"), 0o400);
+        assert_eq!(mode(&id, "default"), 0o640);
+
+        let files = vec![secret("default", None)];
+        let id = deploy_secrets(
+            &basedir,
+            files,
+            &FileDefaults::default(),
+            &identities(&identity),
+        );
+        assert_eq!(mode(&id, "default"), 0o600);
+    }
+
+    #[test]
+    fn owners_can_name_their_group() {
+        assert_eq!(
+            split_owner(Some("alice:wheel"), None),
+            (Some("alice"), Some("wheel"))
+        );
+        assert_eq!(split_owner(Some(":wheel"), None), (None, Some("wheel")));
+        assert_eq!(split_owner(Some("alice"), None), (Some("alice"), None));
+        assert_eq!(
+            split_owner(Some("1000:100"), None),
+            (Some("1000"), Some("100"))
+        );
+        // An explicit group wins over the one in the owner
+        assert_eq!(
+            split_owner(Some("alice:wheel"), Some("users")),
+            (Some("alice"), Some("users"))
+        );
+        assert_eq!(resolve_uid("1000"), Some(1000));
+        assert_eq!(resolve_gid("100"), Some(100));
+    }
+
+    #[test]
+    fn ids_are_looked_up_in_passwd_files() {
+        let scratch = Scratch::new();
+        let passwd = scratch.write(
+            "etc/passwd",
+            "root:x:0:0:root:/root:/bin/sh\nalice:x:1000:100::/home/alice:/bin/sh\n",
+        );
+        let passwd = Path::new(&passwd);
+
+        assert_eq!(lookup_id(passwd, "root").unwrap(), Some(0));
+        assert_eq!(lookup_id(passwd, "alice").unwrap(), Some(1000));
+        // Names only match whole fields
+        assert_eq!(lookup_id(passwd, "alic").unwrap(), None);
+        assert_eq!(lookup_id(passwd, "bob").unwrap(), None);
+    }
+
+    fn literal_template(placeholder: Option<&str>) -> Template {
+        serde_json::from_value(json!({
+            "name": "app.conf",
+            "source": "app.conf.in",
+            "destination": "app.conf",
+            "placeholder": placeholder,
+        }))
+        .unwrap()
+    }
+
+    fn template_secrets() -> HashMap<&'static str, TemplateSecret> {
+        [("db", "alice"), ("db_password", "hunter2")]
+            .into_iter()
+            .map(|(name, text)| {
+                let value = DecryptedValue::String(text.to_string());
+                let text = text.to_string();
+                (name, TemplateSecret { text, value })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn literal_placeholders_are_replaced() {
+        let secrets = template_secrets();
+
+        let text = "user=$$SECNIX::db::SECNIX$$ password=$$SECNIX::db_password::SECNIX$$".into();
+        let rendered = render_template(&literal_template(None), text, &secrets).unwrap();
+        assert_eq!(rendered, "user=alice password=hunter2");
+
+        // Regex metacharacters in a custom placeholder are matched literally
+        let template = literal_template(Some("(*{key}*)"));
+        let text = "user=(*db*) password=(*db_password*) plain=db_password (db)".into();
+        let rendered = render_template(&template, text, &secrets).unwrap();
+        assert_eq!(
+            rendered,
+            "user=alice password=hunter2 plain=db_password (db)"
+        );
+    }
+
+    #[test]
+    fn placeholders_must_contain_the_key() {
+        let error = literal_pattern(&literal_template(Some("@@secret@@"))).unwrap_err();
+        assert!(error.to_string().contains("does not contain {key}"));
+    }
+
+    #[test]
+    fn links_sharing_a_stem_get_their_own_temporary_path() {
+        let conf = temp_path(Path::new("/etc/app.conf"));
+        let json = temp_path(Path::new("/etc/app.json"));
+        assert_eq!(conf.parent(), Some(Path::new("/etc")));
+        assert!(conf.to_string_lossy().starts_with("/etc/.app.conf."));
+        assert!(json.to_string_lossy().starts_with("/etc/.app.json."));
+        assert_ne!(conf, temp_path(Path::new("/etc/app.conf")));
+    }
+
+    #[test]
+    fn duplicate_links_are_found() {
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "yaml",
+            "name": "password",
+            "source": "secrets.yaml",
+            "key": "password",
+            "link": "/etc/app.conf",
+        }))
+        .unwrap();
+        let template = |destination: &str| -> Template {
+            serde_json::from_value(json!({
+                "name": "app.conf",
+                "source": "app.conf.in",
+                "destination": destination,
+            }))
+            .unwrap()
+        };
+
+        let files = [secret];
+        assert_eq!(
+            find_duplicate_link(&files, &[template("/etc/app.json")]),
+            None
+        );
+        assert_eq!(
+            find_duplicate_link(&files, &[template("/etc/app.conf")]),
+            Some("/etc/app.conf")
+        );
+    }
+
+    /// An access ACL granting uid 1000 read access, in the format the kernel stores it
+    fn acl() -> Vec<u8> {
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 6, u32::MAX), // owner
+            (0x02, 4, 1000),     // uid 1000
+            (0x04, 4, u32::MAX), // group
+            (0x10, 4, u32::MAX), // mask
+            (0x20, 0, u32::MAX), // other
+        ];
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[test]
+    #[ignore = "needs a filesystem with POSIX ACLs"]
+    fn acls_survive_copying_attributes() {
+        let scratch = Scratch::new();
+        let from = PathBuf::from(scratch.write("from", "value"));
+        let to = PathBuf::from(scratch.write("to", "value"));
+        xattr::set(&from, "system.posix_acl_access", &acl()).unwrap();
+
+        copy_xattrs(&from, &to);
+
+        let acl = xattr::get(&to, "system.posix_acl_access").unwrap();
+        assert!(acl.is_some());
+        assert_eq!(acl, xattr::get(&from, "system.posix_acl_access").unwrap());
+    }
+
+    #[test]
+    fn cleanup_keeps_the_linked_generation() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let ids = deploy_generations(&scratch, &basedir, 3);
+        // The link was left at an older generation than the one the metadata calls active
+        let link = basedir.join(DEFAULT_ACTIVE_LINK);
+        std::fs::remove_file(&link).unwrap();
+        symlink(get_generation_path(&basedir, &ids[0]), &link).unwrap();
+
+        clean_old_generations(&basedir, DEFAULT_ACTIVE_LINK, 0).unwrap();
+
+        assert_eq!(
+            generations(&basedir),
+            sorted(vec![ids[0].clone(), ids[2].clone()])
+        );
+    }
+
+    #[test]
+    fn template_inputs_are_rendered_without_being_written() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let identity = identity();
+        let template: Template = serde_json::from_value(json!({
+            "name": "app.conf",
+            "source": scratch.write("app.conf.in", "password=$$SECNIX::password::SECNIX$$"),
+            "destination": scratch.join("etc/app.conf"),
+            "inputs": [{
+                "name": "password",
+                "source": scratch.write(
+                    "secrets.yaml",
+                    Sops::new(&identity).yaml("password: hunter2\n"),
+                ),
+                "key": "password",
+            }],
+        }))
+        .unwrap();
+
+        let id = activate_new_generation(
+            &basedir,
+            DEFAULT_ACTIVE_LINK,
+            vec![],
+            vec![template],
+            &FileDefaults::default(),
+            &identities(&identity),
+            "hash",
+            &RetryPolicy::default(),
+            true,
+            &[],
+        )
+        .unwrap();
+
+        let generation = get_generation_path(&basedir, &id);
+        assert_eq!(
+            std::fs::read_to_string(generation.join("rendered/app.conf")).unwrap(),
+            "password=hunter2"
+        );
+        let mut files = std::fs::read_dir(&generation)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec![".metadata.json", "rendered"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_LINUX_IMMUTABLE"]
+    fn immutable_files_can_be_cleared_for_removal() {
+        let scratch = Scratch::new();
+        let generation = scratch.path().join("generation");
+        let path = PathBuf::from(scratch.write("generation/nested/password", "hunter2"));
+        set_immutable(&path, true).unwrap();
+
+        let write = std::fs::OpenOptions::new().write(true).open(&path);
+        let removed = std::fs::remove_file(&path);
+        // Clear the flag before checking anything, so the scratch directory can be removed
+        clear_immutable(&generation);
+
+        assert!(write.is_err());
+        assert!(removed.is_err());
+        std::fs::remove_dir_all(&generation).unwrap();
+    }
+
+    #[test]
+    fn links_through_symlink_loops_are_rejected() {
+        let scratch = Scratch::new();
+        symlink(scratch.path().join("b"), scratch.path().join("a")).unwrap();
+        symlink(scratch.path().join("a"), scratch.path().join("b")).unwrap();
+
+        let error = validate_link_path(&scratch.path().join("a/app.conf"), None).unwrap_err();
+        assert!(error.to_string().contains("symlink loop"));
+    }
+
+    #[test]
+    fn links_escaping_the_root_through_a_symlink_are_rejected() {
+        let scratch = Scratch::new();
+        let root = scratch.path().join("root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::create_dir_all(scratch.path().join("outside")).unwrap();
+        symlink(scratch.path().join("outside"), root.join("escape")).unwrap();
+
+        validate_link_path(&root.join("etc/app.conf"), Some(&root)).unwrap();
+        let error = validate_link_path(&root.join("escape/app.conf"), Some(&root)).unwrap_err();
+        assert!(error.to_string().contains("is outside of"));
+    }
+
+    #[test]
+    fn toggling_copy_replaces_the_destination() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let destination = scratch.join("etc/app.conf");
+
+        for (i, copy) in [false, true, false].into_iter().enumerate() {
+            let mut template = template(&scratch, "app.conf", &format!("value {}", i));
+            template.copy = Some(copy);
+            deploy(&basedir, vec![], vec![template]);
+
+            let metadata = std::fs::symlink_metadata(&destination).unwrap();
+            assert_eq!(metadata.file_type().is_symlink(), !copy, "generation {}", i);
+            assert_eq!(
+                std::fs::read_to_string(&destination).unwrap(),
+                format!("value {}", i)
+            );
+        }
+    }
+
+    #[test]
+    fn replaced_pipes_stop_their_writer() {
+        let scratch = Scratch::new();
+        let basedir = scratch.path().join("secrets");
+        let identity = identity();
+        let link = scratch.join("run/password");
+        let pipe = |password: &str| -> SecretFile {
+            let plaintext = format!("password: {}\n", password);
+            serde_json::from_value(json!({
+                "type": "yaml",
+                "name": "password",
+                "source": scratch.write("secrets.yaml", Sops::new(&identity).yaml(&plaintext)),
+                "key": "password",
+                "link": link,
+                "fifo": true,
+            }))
+            .unwrap()
+        };
+        let deploy_pipe = |password: &str| {
+            deploy_secrets(
+                &basedir,
+                vec![pipe(password)],
+                &FileDefaults::default(),
+                &identities(&identity),
+            )
+        };
+
+        let replaced = read_fifo_writers(&basedir, &deploy_pipe("hunter2"));
+        assert_eq!(replaced.len(), 1);
+        assert!(replaced[0].started.is_some());
+        let active = deploy_pipe("letmein");
+
+        assert_ne!(process_start_time(replaced[0].pid), replaced[0].started);
+        assert_eq!(read_fifo_writers(&basedir, &active).len(), 1);
+        assert_eq!(std::fs::read_to_string(&link).unwrap(), "letmein");
+    }
+}
@@ -0,0 +1,12 @@
+pub mod cli;
+pub mod enc;
+pub mod exit;
+pub mod fs;
+pub mod manifest;
+pub mod progress;
+pub mod sops;
+pub mod source;
+pub mod ssh;
+
+#[cfg(test)]
+pub(crate) mod testing;
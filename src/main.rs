@@ -1,22 +1,76 @@
-mod cli;
-mod enc;
-mod fs;
-mod manifest;
-mod sops;
-mod ssh;
-
 use clap::Parser;
 
+use std::process::ExitCode;
+
 use anyhow::Result;
-use cli::{Cli, Commands};
+use secnix::cli::{self, Cli, Commands};
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(secnix::progress::writer)
+        .init();
     let cli = Cli::parse();
 
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            secnix::exit::exit_code(&e)
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    if cli.mlock {
+        cli::lock_memory();
+    }
+
     match cli.command {
-        Some(Commands::Check) => cli::check(cli),
-        Some(Commands::Install) => cli::install(cli),
+        Some(Commands::Check { .. }) => cli::check(cli),
+        Some(Commands::Install { .. }) => cli::install(cli),
+        Some(Commands::Doctor) => cli::doctor(cli),
+        Some(Commands::Reload) => cli::reload(cli),
+        Some(Commands::Activate { ref generation }) => cli::activate(&cli, generation),
+        Some(Commands::Export { generation, output }) => cli::export(
+            &cli.manifest,
+            cli.target_user.as_deref(),
+            cli.basedir.as_deref(),
+            &generation,
+            &output,
+        ),
+        Some(Commands::Import { archive }) => cli::import(
+            &cli.manifest,
+            cli.target_user.as_deref(),
+            cli.basedir.as_deref(),
+            &archive,
+        ),
+        Some(Commands::Rekey {
+            ref source,
+            ref add_recipient,
+            ref remove_recipient,
+        }) => cli::rekey(&cli, source, add_recipient, remove_recipient),
+        Some(Commands::WhichKey { ref source }) => cli::which_key(&cli, source),
+        Some(Commands::ConvertKey {
+            key,
+            sops_rule,
+            path_regex,
+        }) => cli::convert_key(&key, sops_rule, path_regex),
+        Some(Commands::History { ref name, json }) => cli::history(
+            &cli.manifest,
+            cli.target_user.as_deref(),
+            cli.basedir.as_deref(),
+            name,
+            json,
+        ),
+        Some(Commands::Schema) => cli::schema(),
+        Some(Commands::List { since, limit, json }) => cli::list(
+            &cli.manifest,
+            cli.target_user.as_deref(),
+            cli.basedir.as_deref(),
+            since,
+            limit,
+            json,
+        ),
         None => cli::install(cli),
     }
 }
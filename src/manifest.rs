@@ -1,25 +1,133 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
+use flate2::read::GzDecoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
-#[derive(Debug, Deserialize)]
+use zeroize::Zeroizing;
+
+use crate::{
+    enc::age::{Aes256GcmType, DecryptedValue, Identities},
+    sops::{load_large_sops_file, load_sops_file, plaintext_value, SopsFile},
+};
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct SecnixManifest {
-    /// The version of the manifest file.
+    /// The version of the manifest file. A numeric string like `"1"` is accepted too.
+    #[serde(deserialize_with = "deserialize_version")]
+    #[schemars(with = "u64")]
     pub version: u64,
     /// Any secrets that should be installed
     pub secrets: Vec<SecretFile>,
-    /// Any SSH keys that will be used to decrypt the secrets
+    /// Any SSH keys that will be used to decrypt the secrets. Defaults to the system host key.
+    #[serde(default = "default_ssh_keys")]
     pub ssh_keys: Vec<String>,
     /// The directory where the secrets will be installed
     pub secret_directory: String,
 
     /// A list of templates that should be rendered
     pub templates: Vec<Template>,
+
+    /// The permissions used for secrets and templates that don't specify their own
+    #[serde(flatten)]
+    pub defaults: FileDefaults,
+
+    /// How filesystem operations are retried when they fail with a transient error
+    #[serde(default)]
+    pub retry: RetryPolicy,
+
+    /// The umask, in octal, used while creating files and directories
+    pub umask: Option<String>,
+
+    /// Copy every secret and template to its link or destination instead of symlinking it, for
+    /// systems that don't allow symlinks
+    pub copy_all: Option<bool>,
+
+    /// The user whose secrets are deployed when running as root. Specifiers are resolved for this
+    /// user and they own files that don't specify an owner.
+    pub target_user: Option<String>,
+
+    /// Skip reading sources whose modification time and size haven't changed since the active
+    /// generation was deployed. This speeds up installs with many large sources, but a source
+    /// rewritten with the same size and a preserved modification time isn't noticed until the
+    /// next install with `--force`.
+    pub trust_mtime: Option<bool>,
+
+    /// The directory systemd credentials are written to, such as `/run/credstore`, where
+    /// services can load them with `ImportCredential=` or `LoadCredential=`
+    pub credentials_directory: Option<String>,
+
+    /// How many inactive generations are kept after installing, so they can be activated again.
+    /// Defaults to 0, which only keeps the active generation.
+    pub keep_generations: Option<usize>,
+
+    /// The name of the symlink in the base directory that points at the active generation.
+    /// Defaults to `secrets`. Manifests sharing a base directory with different names keep
+    /// separate generation histories.
+    pub active_link_name: Option<String>,
+}
+
+/// The ed25519 host key generated by sshd
+const HOST_KEY: &str = "/etc/ssh/ssh_host_ed25519_key";
+
+/// Reads the manifest version from either an integer or a numeric string
+fn deserialize_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Version {
+        Int(u64),
+        String(String),
+    }
+
+    match Version::deserialize(deserializer)? {
+        Version::Int(version) => Ok(version),
+        Version::String(version) => version.trim().parse().map_err(|_| {
+            serde::de::Error::custom(format!(
+                "invalid version {:?}, expected a number like 1",
+                version
+            ))
+        }),
+    }
+}
+
+fn default_ssh_keys() -> Vec<String> {
+    vec![HOST_KEY.to_string()]
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first
+    pub attempts: u32,
+    /// The delay before the first retry in milliseconds, doubled after each attempt
+    pub backoff_ms: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff_ms: 50,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, JsonSchema)]
+pub struct FileDefaults {
+    /// The mode of files that don't specify one
+    pub default_mode: Option<String>,
+    /// The owner of files that don't specify one, and of the directories created for them
+    pub default_owner: Option<String>,
+    /// The group of files that don't specify one, and of the directories created for them
+    pub default_group: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct SecretFile {
     /// The type of file
     #[serde(rename = "type")]
@@ -30,21 +138,102 @@ pub struct SecretFile {
     /// The source of the file
     pub source: String,
 
-    /// The key in the file
+    /// The key in the file. `*`, or no key for JSON and YAML files, writes every value in the
+    /// file to its own file in a directory, named after its key. Nested keys become
+    /// subdirectories.
     key: Option<String>,
 
+    /// The most files a secret with the `*` key may write. Defaults to 256.
+    pub max_files: Option<usize>,
+
+    /// The type the encrypted value is expected to be stored as
+    pub expected_type: Option<Aes256GcmType>,
+
+    /// The exact set of age recipients the source is expected to be encrypted for
+    pub expected_recipients: Option<Vec<String>>,
+
+    /// The compression applied to the secret's value before it was encrypted
+    pub decompress: Option<Compression>,
+
+    /// How scalar values are represented when written
+    pub format: Option<OutputFormat>,
+
+    /// If the key refers to a subtree that should be decrypted and serialized as a whole
+    pub subtree: Option<bool>,
+
+    /// The dotted path of the sops metadata in the source, if it isn't the top level `sops` key
+    pub sops_path: Option<String>,
+
+    /// If the MAC of the source should be verified before decrypting. Defaults to true, set it to
+    /// false for sources whose MAC is known not to match.
+    pub verify_mac: Option<bool>,
+
+    /// The hex SHA-256 the decrypted file is expected to have. Installing fails before anything
+    /// is written if it doesn't match. Not supported for secrets with the `*` key.
+    pub sha256: Option<String>,
+
     /// The location where the file will be symlinked
     pub link: Option<String>,
 
+    /// Copy the file to its link instead of symlinking it. Secrets with the `*` key copy their
+    /// whole directory.
+    pub copy: Option<bool>,
+
+    /// Write the secret as a systemd credential instead of linking it. It is copied to
+    /// `credentials_directory` with mode `0400`, named after the secret, so the secret's name is
+    /// the credential id. The link is ignored.
+    pub credential: Option<bool>,
+
+    /// Serve the secret through a named pipe at the link instead of writing it to a file. The pipe
+    /// is created on install and a background process writes the value to the first reader, after
+    /// which it is gone until the next install. The value is not part of the generation, so it
+    /// can't be exported or referenced by templates.
+    pub fifo: Option<bool>,
+
     /// The mode of the file
     pub mode: Option<String>,
     /// The owner of the file
     pub owner: Option<String>,
     /// The group of the file
     pub group: Option<String>,
+
+    /// Make the file immutable, like `chattr +i`, so not even its owner can change it. Only
+    /// supported on Linux and requires `CAP_LINUX_IMMUTABLE`.
+    pub immutable: Option<bool>,
+
+    /// Systemd units that should be restarted when the secret changes
+    pub restart_units: Option<Vec<String>>,
+    /// Systemd units that should be reloaded when the secret changes
+    pub reload_units: Option<Vec<String>>,
+}
+
+/// A secret that is decrypted to render a template without being deployed as a file
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+pub struct SecretRef {
+    /// The name the template refers to the secret by
+    pub name: String,
+    /// The source of the secret
+    pub source: String,
+    /// The key in the source
+    pub key: String,
+    /// The dotted path of the sops metadata in the source, if it isn't the top level `sops` key
+    pub sops_path: Option<String>,
+    /// If the MAC of the source should be verified before decrypting. Defaults to true.
+    pub verify_mac: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+impl SecretRef {
+    pub fn decrypt(&self, identities: &Identities) -> Result<DecryptedValue> {
+        let encrypted = load_sops_file(&self.source, self.sops_path.as_deref())?;
+        if self.verify_mac.unwrap_or(true) {
+            encrypted.verify_mac(identities)?;
+        }
+        let path = self.key.split('.').collect::<Vec<_>>();
+        encrypted.decrypt(&path, identities)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, JsonSchema)]
 pub enum FileType {
     #[serde(rename = "json")]
     Json,
@@ -52,9 +241,37 @@ pub enum FileType {
     Yaml,
     #[serde(rename = "binary")]
     Binary,
+    #[serde(rename = "dotenv")]
+    Dotenv,
+    #[serde(rename = "ini")]
+    Ini,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, JsonSchema)]
+pub enum OutputFormat {
+    /// The value as-is
+    #[serde(rename = "raw")]
+    Raw,
+    /// The value as a JSON scalar, quoting and escaping strings
+    #[serde(rename = "json")]
+    Json,
+    /// The value wrapped in double quotes
+    #[serde(rename = "quoted")]
+    Quoted,
+    /// Booleans as `yes` or `no`, other values as-is
+    #[serde(rename = "yes-no")]
+    YesNo,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, JsonSchema)]
+pub enum Compression {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Template {
     /// The name of the template file
     pub name: String,
@@ -64,17 +281,56 @@ pub struct Template {
     pub destination: String,
     /// If the template should be symlinked or copied
     pub copy: Option<bool>,
+    /// If a copied template should keep the extended attributes, including POSIX ACLs, of the file
+    /// it replaces
+    pub preserve_xattrs: Option<bool>,
+    /// The engine used to render the template
+    pub engine: Option<TemplateEngine>,
+    /// The placeholder replaced by secrets in literal templates, where `{key}` is the secret's name
+    pub placeholder: Option<String>,
+    /// Secrets that are only decrypted for rendering this template
+    pub inputs: Option<Vec<SecretRef>>,
     pub mode: Option<String>,
     pub owner: Option<String>,
     pub group: Option<String>,
+    /// Systemd units that should be restarted when the template changes
+    pub restart_units: Option<Vec<String>>,
+    /// Systemd units that should be reloaded when the template changes
+    pub reload_units: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, JsonSchema)]
+pub enum TemplateEngine {
+    /// Replaces `$$SECNIX::<name>::SECNIX$$` placeholders with the secret's value
+    #[serde(rename = "literal")]
+    Literal,
+    #[serde(rename = "tera")]
+    Tera,
+    #[serde(rename = "handlebars")]
+    Handlebars,
 }
 
 #[derive(Error, Debug)]
-enum Error {
+pub(crate) enum Error {
     #[error("Path does not exist")]
     PathDoesNotExist,
     #[error("Invalid manifest: {0}")]
     InvalidManifest(serde_json::Error),
+    #[error("Invalid manifest: {0}")]
+    InvalidYamlManifest(serde_yaml::Error),
+
+    #[error("Could not decompress {0:?} data: {1}")]
+    Decompression(Compression, std::io::Error),
+    #[error("Only string or byte values can be decompressed")]
+    NotCompressible,
+    #[error("Binary file {0} has no `data` key, set `key` to the key holding its payload")]
+    MissingBinaryData(String),
+    #[error(
+        "{0} would write {1} files, more than the maximum of {2}; raise max_files to allow it"
+    )]
+    TooManyFiles(String, usize, usize),
+    #[error("Key {0:?} can't be used as a file name")]
+    InvalidFileName(String),
 
     #[error("Unknown error: {0}")]
     Unknown(#[from] anyhow::Error),
@@ -87,13 +343,30 @@ impl SecnixManifest {
             return Err(Error::PathDoesNotExist.into());
         }
         let manifest = std::fs::read_to_string(path).map_err(|e| Error::Unknown(anyhow!(e)))?;
-        let manifest: SecnixManifest =
-            serde_json::from_str(&manifest).map_err(Error::InvalidManifest)?;
+        let is_yaml = path.extension().is_some_and(|e| e == "yaml" || e == "yml");
+        let manifest: SecnixManifest = if is_yaml {
+            serde_yaml::from_str(&manifest).map_err(Error::InvalidYamlManifest)?
+        } else {
+            serde_json::from_str(&manifest).map_err(Error::InvalidManifest)?
+        };
 
         Ok(manifest)
     }
+
+    /// Gets the name of the symlink to the active generation
+    pub fn active_link_name(&self) -> &str {
+        self.active_link_name
+            .as_deref()
+            .unwrap_or(crate::fs::DEFAULT_ACTIVE_LINK)
+    }
 }
 
+/// The key that writes every value of a source to its own file
+const WILDCARD_KEY: &str = "*";
+
+/// The most files a wildcard secret writes unless `max_files` is set
+const DEFAULT_MAX_FILES: usize = 256;
+
 impl SecretFile {
     pub fn get_key(&self) -> Option<String> {
         if let Some(key) = &self.key {
@@ -104,4 +377,527 @@ impl SecretFile {
             None
         }
     }
+
+    /// Checks if every value of the source is written to its own file
+    pub fn is_wildcard(&self) -> bool {
+        match self.key.as_deref() {
+            Some(key) => key == WILDCARD_KEY,
+            None => self.file_type != FileType::Binary,
+        }
+    }
+
+    /// Counts the files a wildcard secret would write without decrypting anything, failing if it
+    /// is more than `max_files`
+    pub fn count_files(&self) -> Result<usize> {
+        let encrypted = self.load()?;
+        let count = count_leaves(encrypted.data());
+        let max_files = self.max_files.unwrap_or(DEFAULT_MAX_FILES);
+        if count > max_files {
+            return Err(Error::TooManyFiles(self.name.clone(), count, max_files).into());
+        }
+        Ok(count)
+    }
+
+    /// Decrypts every value of a wildcard secret, returning each with its path relative to the
+    /// secret's directory
+    pub fn decrypt_all(&self, identities: &Identities) -> Result<Vec<(PathBuf, DecryptedValue)>> {
+        self.count_files()?;
+        let encrypted = self.load()?;
+        if self.verify_mac.unwrap_or(true) {
+            encrypted.verify_mac(identities)?;
+        }
+        let tree = encrypted.decrypt_all(identities)?;
+        let mut files = vec![];
+        self.collect_files(&tree, &PathBuf::new(), &mut files)?;
+        Ok(files)
+    }
+
+    fn collect_files(
+        &self,
+        tree: &serde_yaml::Mapping,
+        directory: &Path,
+        files: &mut Vec<(PathBuf, DecryptedValue)>,
+    ) -> Result<()> {
+        for (key, value) in tree {
+            let name = match key {
+                serde_yaml::Value::String(s) => s.clone(),
+                serde_yaml::Value::Number(n) => n.to_string(),
+                serde_yaml::Value::Bool(b) => b.to_string(),
+                other => return Err(Error::InvalidFileName(format!("{:?}", other)).into()),
+            };
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                return Err(Error::InvalidFileName(name).into());
+            }
+            let path = directory.join(&name);
+            match value {
+                serde_yaml::Value::Mapping(m) => self.collect_files(m, &path, files)?,
+                serde_yaml::Value::Sequence(_) => {
+                    let text = self.file_type.serialize(value)?;
+                    files.push((path, DecryptedValue::String(text)));
+                }
+                value => files.push((path, plaintext_value(&[&name], value)?)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the secret's source. Binary sources hold a whole file, so they aren't limited to the
+    /// maximum source size.
+    pub fn load(&self) -> Result<Box<dyn SopsFile>> {
+        if self.file_type == FileType::Binary {
+            load_large_sops_file(&self.source, self.sops_path.as_deref())
+        } else {
+            load_sops_file(&self.source, self.sops_path.as_deref())
+        }
+    }
+
+    /// Checks if the secret is a binary file relying on the conventional `data` key
+    pub fn uses_default_binary_key(&self) -> bool {
+        self.key.is_none() && self.file_type == FileType::Binary
+    }
+
+    /// Estimates the size of the secret once written without decrypting it. The encrypted value is
+    /// always larger than the plaintext, so this is an upper bound unless the value is compressed.
+    pub fn estimated_size(&self) -> Result<u64> {
+        if self.is_wildcard() {
+            let encrypted = self.load()?;
+            return Ok(serde_yaml::to_string(encrypted.data())?.len() as u64);
+        }
+        let Some(key) = self.get_key() else {
+            return Ok(0);
+        };
+        let encrypted = self.load()?;
+        let path = key.split('.').collect::<Vec<_>>();
+        let size = if self.subtree.unwrap_or(false) {
+            encrypted
+                .get_value(&path)
+                .map(serde_yaml::to_string)
+                .transpose()?
+                .map_or(0, |s| s.len())
+        } else {
+            encrypted.get_key(&path).map_or(0, |s| s.len())
+        };
+        Ok(size as u64)
+    }
+
+    /// Decrypt the secret's value from its source, returning None if the secret has no key or is
+    /// a wildcard, which is decrypted with [SecretFile::decrypt_all]
+    pub fn decrypt(&self, identities: &Identities) -> Result<Option<DecryptedValue>> {
+        let encrypted = self.load()?;
+        let Some(key) = self.get_key().filter(|_| !self.is_wildcard()) else {
+            return Ok(None);
+        };
+        if self.verify_mac.unwrap_or(true) {
+            encrypted.verify_mac(identities)?;
+        }
+        let path = key.split('.').collect::<Vec<_>>();
+        if self.uses_default_binary_key() && encrypted.get_value(&path).is_none() {
+            return Err(Error::MissingBinaryData(self.source.clone()).into());
+        }
+        let decrypted = if self.subtree.unwrap_or(false) {
+            let tree = encrypted.decrypt_subtree(&path, identities)?;
+            DecryptedValue::String(self.file_type.serialize(&tree)?)
+        } else {
+            match encrypted.decrypt(&path, identities)? {
+                // Binary files are written exactly as they were encrypted, never formatted
+                DecryptedValue::String(s) if self.file_type == FileType::Binary => {
+                    DecryptedValue::Bytes(s.into_bytes())
+                }
+                decrypted => decrypted,
+            }
+        };
+        let decrypted = match &self.decompress {
+            Some(compression) => match decrypted {
+                DecryptedValue::Bytes(bytes) => {
+                    DecryptedValue::Bytes(compression.decompress(&bytes)?)
+                }
+                DecryptedValue::String(str) => {
+                    DecryptedValue::Bytes(compression.decompress(str.as_bytes())?)
+                }
+                _ => return Err(Error::NotCompressible.into()),
+            },
+            None => decrypted,
+        };
+        Ok(Some(decrypted))
+    }
+}
+
+/// Counts the values that aren't mappings, which are the files a wildcard secret writes
+fn count_leaves(tree: &serde_yaml::Mapping) -> usize {
+    tree.values()
+        .map(|value| match value {
+            serde_yaml::Value::Mapping(m) => count_leaves(m),
+            _ => 1,
+        })
+        .sum()
+}
+
+impl FileType {
+    /// Serializes a decrypted subtree in this format
+    fn serialize(&self, tree: &serde_yaml::Value) -> Result<String> {
+        match self {
+            FileType::Json => Ok(serde_json::to_string_pretty(tree)?),
+            FileType::Yaml => Ok(serde_yaml::to_string(tree)?),
+            FileType::Binary => Err(anyhow!(
+                "Subtree extraction is not supported for binary files"
+            )),
+            FileType::Dotenv => Err(anyhow!(
+                "Subtree extraction is not supported for dotenv files"
+            )),
+            FileType::Ini => Err(anyhow!("Subtree extraction is not supported for INI files")),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Formats a scalar value for writing
+    pub fn format(&self, value: &DecryptedValue) -> Result<String> {
+        let raw = match value {
+            DecryptedValue::String(s) => s.clone(),
+            DecryptedValue::Int(i) => i.to_string(),
+            DecryptedValue::Float(f) => f.to_string(),
+            DecryptedValue::Bool(b) => b.to_string(),
+            DecryptedValue::Bytes(_) | DecryptedValue::Comment(_) => {
+                return Err(anyhow!("Only scalar values can be formatted"))
+            }
+        };
+        match (self, value) {
+            (OutputFormat::Raw, _) => Ok(raw),
+            (OutputFormat::Json, DecryptedValue::String(s)) => Ok(serde_json::to_string(s)?),
+            (OutputFormat::Json, _) => Ok(raw),
+            (OutputFormat::Quoted, _) => Ok(format!(
+                "\"{}\"",
+                raw.replace('\\', "\\\\").replace('"', "\\\"")
+            )),
+            (OutputFormat::YesNo, DecryptedValue::Bool(true)) => Ok("yes".to_string()),
+            (OutputFormat::YesNo, DecryptedValue::Bool(false)) => Ok("no".to_string()),
+            (OutputFormat::YesNo, _) => Ok(raw),
+        }
+    }
+}
+
+impl Compression {
+    /// Decompresses the provided data, failing if it is truncated or invalid
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = vec![];
+        match self {
+            Compression::Gzip => GzDecoder::new(data).read_to_end(&mut decompressed),
+            Compression::Zstd => zstd::stream::read::Decoder::new(data)
+                .and_then(|mut decoder| decoder.read_to_end(&mut decompressed)),
+        }
+        .map_err(|e| Error::Decompression(self.clone(), e))?;
+        Ok(decompressed)
+    }
+}
+
+/// Decrypt every secret in the manifest into memory, keyed by secret name. Nothing is written
+/// to the filesystem.
+pub fn decrypt_manifest(
+    manifest: &SecnixManifest,
+    identities: &Identities,
+) -> Result<HashMap<String, Zeroizing<DecryptedValue>>> {
+    let mut secrets = HashMap::new();
+    for secret_file in &manifest.secrets {
+        if let Some(decrypted) = secret_file.decrypt(identities)? {
+            secrets.insert(secret_file.name.clone(), Zeroizing::new(decrypted));
+        }
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{encrypt_value, identities, identity, Scratch, Sops};
+
+    /// Writes a YAML source whose `password` is swapped for the one of a file with a different
+    /// value, which decrypts fine but no longer matches the MAC
+    fn tampered_source(scratch: &Scratch, sops: &Sops) -> String {
+        let original = sops.yaml("password: hunter2\n");
+        let (swapped, _) = sops.encrypt("password: letmein\n");
+        let mut document: serde_yaml::Mapping = serde_yaml::from_str(&original).unwrap();
+        document.insert("password".into(), swapped["password"].clone());
+        scratch.write("tampered.yaml", serde_yaml::to_string(&document).unwrap())
+    }
+
+    #[test]
+    fn tampered_value_fails_mac_verification() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let source = tampered_source(&scratch, &Sops::new(&identity));
+
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "yaml",
+            "name": "password",
+            "source": source,
+            "key": "password",
+        }))
+        .unwrap();
+        let error = secret.decrypt(&identities(&identity)).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<crate::sops::Error>(),
+            Some(crate::sops::Error::MacMismatch)
+        ));
+
+        let input: SecretRef = serde_json::from_value(json!({
+            "name": "password",
+            "source": source,
+            "key": "password",
+        }))
+        .unwrap();
+        let error = input.decrypt(&identities(&identity)).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<crate::sops::Error>(),
+            Some(crate::sops::Error::MacMismatch)
+        ));
+    }
+
+    #[test]
+    fn tampered_value_decrypts_without_mac_verification() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let source = tampered_source(&scratch, &Sops::new(&identity));
+
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "yaml",
+            "name": "password",
+            "source": source,
+            "key": "password",
+            "verify_mac": false,
+        }))
+        .unwrap();
+        let decrypted = secret.decrypt(&identities(&identity)).unwrap();
+        assert!(matches!(decrypted, Some(DecryptedValue::String(s)) if s == "letmein"));
+    }
+
+    #[test]
+    fn binary_sources_are_not_limited_in_size() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let payload = "x".repeat(5 * 1024 * 1024);
+        let source = scratch.write(
+            "large.json",
+            Sops::new(&identity).yaml(&format!("data: {}\n", payload)),
+        );
+
+        let binary: SecretFile = serde_json::from_value(json!({
+            "type": "binary",
+            "name": "large",
+            "source": source,
+        }))
+        .unwrap();
+        match binary.decrypt(&identities(&identity)).unwrap() {
+            Some(DecryptedValue::Bytes(bytes)) => assert_eq!(bytes, payload.as_bytes()),
+            _ => panic!("binary secret did not decrypt to bytes"),
+        }
+
+        let yaml: SecretFile = serde_json::from_value(json!({
+            "type": "yaml",
+            "name": "large",
+            "source": source,
+            "key": "data",
+        }))
+        .unwrap();
+        let error = yaml.decrypt(&identities(&identity)).err().unwrap();
+        assert!(format!("{:#}", error).contains("larger than the maximum source size"));
+    }
+
+    /// A payload with embedded NULs, encrypted the way `sops -e` encrypts a binary file
+    const BINARY_PAYLOAD: &[u8] = b"\0key\0\0with\nnuls\0";
+
+    #[test]
+    fn binary_files_decrypt_byte_for_byte() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let plaintext = serde_yaml::to_string(&serde_yaml::Mapping::from_iter([(
+            "data".into(),
+            std::str::from_utf8(BINARY_PAYLOAD).unwrap().into(),
+        )]))
+        .unwrap();
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "binary",
+            "name": "tls.key",
+            "source": scratch.write("tls.key.json", Sops::new(&identity).yaml(&plaintext)),
+        }))
+        .unwrap();
+
+        match secret.decrypt(&identities(&identity)).unwrap() {
+            Some(DecryptedValue::Bytes(bytes)) => assert_eq!(bytes, BINARY_PAYLOAD),
+            _ => panic!("binary secret did not decrypt to bytes"),
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_compressed_secrets_are_decompressed() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let sops = Sops::new(&identity);
+        // Compressed data isn't valid UTF-8, so it is encrypted by hand rather than from YAML
+        let (mut document, metadata) = sops.encrypt("data: placeholder\n");
+        document.insert(
+            "data".into(),
+            encrypt_value(&gzip(b"compressed value"), b"data:", "str").into(),
+        );
+        document.insert("sops".into(), metadata.into());
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "binary",
+            "name": "value",
+            "source": scratch.write("value.yaml", serde_yaml::to_string(&document).unwrap()),
+            "decompress": "gzip",
+            "verify_mac": false,
+        }))
+        .unwrap();
+
+        match secret.decrypt(&identities(&identity)).unwrap() {
+            Some(DecryptedValue::Bytes(bytes)) => assert_eq!(bytes, b"compressed value"),
+            _ => panic!("compressed secret did not decrypt to bytes"),
+        }
+    }
+
+    #[test]
+    fn truncated_compressed_data_fails() {
+        let compressed = gzip(b"compressed value");
+        let error = Compression::Gzip
+            .decompress(&compressed[..compressed.len() / 2])
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Decompression(Compression::Gzip, _))
+        ));
+    }
+
+    #[test]
+    fn output_formats_of_scalars() {
+        let cases = [
+            (OutputFormat::Raw, ["8080", "true", "1.5"]),
+            (OutputFormat::Json, ["8080", "true", "1.5"]),
+            (OutputFormat::Quoted, ["\"8080\"", "\"true\"", "\"1.5\""]),
+            (OutputFormat::YesNo, ["8080", "yes", "1.5"]),
+        ];
+        for (format, [int, bool, float]) in cases {
+            assert_eq!(format.format(&DecryptedValue::Int(8080)).unwrap(), int);
+            assert_eq!(format.format(&DecryptedValue::Bool(true)).unwrap(), bool);
+            assert_eq!(format.format(&DecryptedValue::Float(1.5)).unwrap(), float);
+        }
+        assert_eq!(
+            OutputFormat::YesNo
+                .format(&DecryptedValue::Bool(false))
+                .unwrap(),
+            "no"
+        );
+    }
+
+    fn manifest(version: serde_json::Value) -> serde_json::Result<SecnixManifest> {
+        serde_json::from_value(json!({
+            "version": version,
+            "secrets": [],
+            "secret_directory": "/run/secrets",
+            "templates": [],
+        }))
+    }
+
+    #[test]
+    fn version_is_a_number_or_numeric_string() {
+        assert_eq!(manifest(json!(1)).unwrap().version, 1);
+        assert_eq!(manifest(json!("1")).unwrap().version, 1);
+        let error = manifest(json!("one")).unwrap_err();
+        assert!(error.to_string().contains("invalid version \"one\""));
+    }
+
+    fn wildcard(scratch: &Scratch, sops: &Sops, plaintext: &str, max_files: usize) -> SecretFile {
+        serde_json::from_value(json!({
+            "type": "yaml",
+            "name": "app",
+            "source": scratch.write("app.yaml", sops.yaml(plaintext)),
+            "key": "*",
+            "max_files": max_files,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn wildcard_secrets_write_every_value() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let plaintext = "user: admin\ndb:\n  password: hunter2\n  port: 5432\n";
+        let secret = wildcard(&scratch, &Sops::new(&identity), plaintext, 3);
+
+        let files = secret.decrypt_all(&identities(&identity)).unwrap();
+        let files: Vec<(PathBuf, String)> = files
+            .iter()
+            .map(|(path, value)| (path.clone(), OutputFormat::Raw.format(value).unwrap()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("user"), "admin".to_string()),
+                (PathBuf::from("db/password"), "hunter2".to_string()),
+                (PathBuf::from("db/port"), "5432".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_secrets_are_limited_to_max_files() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let plaintext = "a: 1\nb: 2\nc: 3\n";
+        let secret = wildcard(&scratch, &Sops::new(&identity), plaintext, 2);
+
+        let error = secret.decrypt_all(&identities(&identity)).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::TooManyFiles(name, 3, 2)) if name == "app"
+        ));
+    }
+
+    #[test]
+    fn wildcard_keys_must_be_file_names() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        for (plaintext, name) in [("\"..\": value\n", ".."), ("\"a/b\": value\n", "a/b")] {
+            let secret = wildcard(&scratch, &Sops::new(&identity), plaintext, 256);
+            let error = secret.decrypt_all(&identities(&identity)).err().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<Error>(),
+                Some(Error::InvalidFileName(invalid)) if invalid == name
+            ));
+        }
+    }
+
+    #[test]
+    fn binary_files_without_data_are_rejected() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let source = scratch.write(
+            "tls.key.json",
+            Sops::new(&identity).yaml("payload: value\n"),
+        );
+        let secret: SecretFile = serde_json::from_value(json!({
+            "type": "binary",
+            "name": "tls.key",
+            "source": source,
+        }))
+        .unwrap();
+
+        let error = secret.decrypt(&identities(&identity)).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::MissingBinaryData(missing)) if *missing == source
+        ));
+    }
+
+    #[test]
+    fn ssh_keys_default_to_the_host_key() {
+        let manifest = manifest(json!(1)).unwrap();
+        assert_eq!(manifest.ssh_keys, vec![HOST_KEY.to_string()]);
+    }
 }
@@ -0,0 +1,62 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::Mutex,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// The progress bar of the running install, if one is shown
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+fn current() -> Option<ProgressBar> {
+    BAR.lock().unwrap().clone()
+}
+
+/// Shows a progress bar over `len` steps on stderr, if stderr is a terminal
+pub fn start(len: u64) {
+    if !std::io::stderr().is_terminal() {
+        return;
+    }
+    let bar = ProgressBar::new(len);
+    if let Ok(style) = ProgressStyle::with_template("{bar:40} {pos}/{len} {msg}") {
+        bar.set_style(style);
+    }
+    *BAR.lock().unwrap() = Some(bar);
+}
+
+/// Advances the progress bar by one step, showing what was just deployed
+pub fn inc(message: &str) {
+    if let Some(bar) = current() {
+        bar.set_message(message.to_string());
+        bar.inc(1);
+    }
+}
+
+/// Removes the progress bar
+pub fn finish() {
+    if let Some(bar) = BAR.lock().unwrap().take() {
+        bar.finish_and_clear();
+    }
+}
+
+/// Writes log lines to stdout, hiding the progress bar while they are written so they don't
+/// corrupt it
+pub struct LogWriter;
+
+/// Creates the writer used for log lines
+pub fn writer() -> LogWriter {
+    LogWriter
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match current() {
+            Some(bar) => bar.suspend(|| std::io::stdout().write(buf)),
+            None => std::io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
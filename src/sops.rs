@@ -1,13 +1,22 @@
-use std::collections::HashMap;
+use std::{path::Path, sync::OnceLock};
 
 use anyhow::{anyhow, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
 
-use crate::enc::{self, age::DecryptedValue};
+use crate::{
+    enc::{
+        self,
+        age::{Aes256GcmType, DecryptedValue, Identities},
+    },
+    source::{read_large_source, read_source},
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Age {
     pub recipient: String,
     pub enc: String,
@@ -19,8 +28,47 @@ pub struct SopsData {
     #[serde(rename = "lastmodified")]
     pub last_modified: String,
     mac: String,
-    unencrypted_suffix: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unencrypted_suffix: Option<String>,
+    /// Only values whose own key ends with this suffix have to be encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_suffix: Option<String>,
+    /// Only values whose own key matches this regex have to be encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_regex: Option<String>,
+    /// `encrypted_regex` once it has been compiled
+    #[serde(skip)]
+    compiled_regex: OnceLock<Result<Regex, regex::Error>>,
     version: String,
+    /// If the MAC only covers encrypted values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_only_encrypted: Option<bool>,
+}
+
+impl SopsData {
+    /// Checks if the value at the provided key is expected to be encrypted according to
+    /// `encrypted_suffix`, `unencrypted_suffix` or `encrypted_regex`. The encrypted rules are
+    /// matched against the last segment of the key only, so a parent that happens to match doesn't
+    /// make its plaintext children an error. Everything below a key with the unencrypted suffix is
+    /// left in plaintext by sops, so every segment is checked against it.
+    fn is_encrypted(&self, key: &[&str]) -> Result<bool, Error> {
+        if let Some(pattern) = &self.encrypted_regex {
+            if self.encrypted_suffix.is_some() || self.unencrypted_suffix.is_some() {
+                return Err(Error::ConflictingSuffixes);
+            }
+            let regex = match self.compiled_regex.get_or_init(|| Regex::new(pattern)) {
+                Ok(regex) => regex,
+                Err(e) => return Err(Error::InvalidRegex(pattern.clone(), e.clone())),
+            };
+            return Ok(key.last().is_some_and(|k| regex.is_match(k)));
+        }
+        match (&self.encrypted_suffix, &self.unencrypted_suffix) {
+            (Some(_), Some(_)) => Err(Error::ConflictingSuffixes),
+            (Some(suffix), None) => Ok(key.last().is_some_and(|k| k.ends_with(suffix.as_str()))),
+            (None, Some(suffix)) => Ok(!key.iter().any(|k| k.ends_with(suffix.as_str()))),
+            (None, None) => Ok(true),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -31,12 +79,33 @@ pub enum Error {
     Decrypt(#[from] DecryptionError),
     #[error("Missing data: {0}")]
     MissingData(String),
+    #[error("Value at `{0}` is not encrypted; check unencrypted_suffix/encrypted_regex")]
+    NotEncrypted(String),
+    #[error("Refusing to remove every recipient from {0}")]
+    NoRecipientsLeft(String),
+    #[error("MAC mismatch, the file may have been tampered with")]
+    MacMismatch,
+    #[error("Only one of unencrypted_suffix, encrypted_suffix and encrypted_regex may be set")]
+    ConflictingSuffixes,
+    #[error("Invalid encrypted_regex {0:?}: {1}")]
+    InvalidRegex(String, regex::Error),
+    #[error("{0} is not a sops file: {1}")]
+    NotASopsFile(String, &'static str),
+    #[error("Could not read {0}")]
+    Read(String, #[source] anyhow::Error),
+    #[error("{0} is not valid {1}: {2}")]
+    Syntax(String, &'static str, String),
 }
 
 #[derive(Error, Debug)]
 pub enum DecryptionError {
-    #[error("No recipients found")]
-    NoRecipients,
+    #[error("No recipients found: the file wants {wanted:?} but our keys are {ours:?}")]
+    NoRecipients {
+        /// The recipients the file is encrypted for
+        wanted: Vec<String>,
+        /// The recipients derived from our keys
+        ours: Vec<String>,
+    },
     #[error("Error decrypting KEK: {0}")]
     KekDecryption(#[from] anyhow::Error),
     #[error("No key found")]
@@ -44,16 +113,99 @@ pub enum DecryptionError {
 }
 
 pub trait SopsFile {
-    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&String>;
+    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&'a String>;
+
+    /// Gets the raw value at the provided key, which may be a leaf or a subtree
+    fn get_value(&self, key: &[&str]) -> Option<&serde_yaml::Value>;
+
+    /// Decrypts the value at the provided key. Encrypted values are always decrypted, the
+    /// encryption rules of the file only decide if a plaintext value is expected or an error.
+    fn decrypt(&self, key: &[&str], identities: &Identities) -> Result<DecryptedValue> {
+        match self.get_key(key) {
+            Some(d) if d.starts_with("ENC[") => decrypt(key, d, identities, self.sops_metadata()),
+            _ if !self.sops_metadata().is_encrypted(key)? => {
+                debug!("{} is stored in plaintext", key.join("."));
+                match self.get_value(key) {
+                    Some(value) => plaintext_value(key, value),
+                    None => Err(anyhow!(Error::MissingData(key.join(".")))),
+                }
+            }
+            Some(d) if d.is_empty() => Ok(DecryptedValue::String(String::new())),
+            Some(_) => Err(anyhow!(Error::NotEncrypted(key.join(".")))),
+            None if self.is_null(key) => Ok(DecryptedValue::String(String::new())),
+            None => Err(anyhow!(Error::MissingData(key.join(".")))),
+        }
+    }
+
+    /// Checks if the key is present in the file but explicitly set to null
+    fn is_null(&self, key: &[&str]) -> bool {
+        matches!(self.get_value(key), Some(serde_yaml::Value::Null))
+    }
 
-    fn decrypt(&self, key: &[&str], keyfile: &str) -> Result<DecryptedValue> {
-        let data = self.get_key(key);
-        match data {
-            Some(d) => decrypt(key, d, keyfile, self.sops_metadata()),
+    /// Gets the declared type of the encrypted value at the provided key without decrypting it
+    fn get_type<'a>(&'a self, key: &[&'a str]) -> Result<Aes256GcmType> {
+        match self.get_key(key) {
+            Some(d) if d.is_empty() => Ok(Aes256GcmType::String),
+            Some(d) => enc::age::get_data_type(d),
+            None if self.is_null(key) => Ok(Aes256GcmType::String),
             None => Err(anyhow!(Error::MissingData(key.join(".")))),
         }
     }
 
+    /// Decrypts every leaf under the provided key, reassembling the original structure
+    fn decrypt_subtree(&self, key: &[&str], identities: &Identities) -> Result<serde_yaml::Value> {
+        let data = match self.get_value(key) {
+            Some(d) => d,
+            None => return Err(anyhow!(Error::MissingData(key.join(".")))),
+        };
+        let kek = decrypt_data_key(identities, self.sops_metadata())?;
+        let path: Vec<String> = key.iter().map(|k| k.to_string()).collect();
+        let lenient = identities.options().lenient_types;
+        let tree = decrypt_tree(data, &kek, &path, self.sops_metadata(), lenient)?;
+        Ok(tree.unwrap_or(serde_yaml::Value::Null))
+    }
+
+    /// Decrypts every value in the file, reassembling the original structure
+    fn decrypt_all(&self, identities: &Identities) -> Result<serde_yaml::Mapping> {
+        let kek = decrypt_data_key(identities, self.sops_metadata())?;
+        let lenient = identities.options().lenient_types;
+        let mut decrypted = serde_yaml::Mapping::new();
+        for (key, value) in self.data() {
+            let path = vec![mapping_key(&[], key)?];
+            if let Some(value) = decrypt_tree(value, &kek, &path, self.sops_metadata(), lenient)? {
+                decrypted.insert(key.clone(), value);
+            }
+        }
+        Ok(decrypted)
+    }
+
+    /// Verifies the MAC of the file, which covers the plaintext of its values in document order.
+    /// Values in comments aren't visible to the parser, so files with encrypted comments fail
+    /// verification.
+    fn verify_mac(&self, identities: &Identities) -> Result<()> {
+        let sops = self.sops_metadata();
+        let kek = decrypt_data_key(identities, sops)?;
+        let only_encrypted = sops.mac_only_encrypted.unwrap_or(false);
+        let lenient = identities.options().lenient_types;
+
+        let mut hasher = Sha512::new();
+        for (key, value) in self.data() {
+            let path = vec![mapping_key(&[], key)?];
+            hash_tree(value, &kek, &path, only_encrypted, lenient, &mut hasher)?;
+        }
+        let computed = format!("{:X}", hasher.finalize());
+
+        let mac = sops.mac.clone();
+        let expected = enc::age::decrypt_with_aad(mac, &kek, sops.last_modified.as_bytes(), false)?;
+        match expected {
+            DecryptedValue::String(expected) if expected == computed => Ok(()),
+            _ => Err(anyhow!(Error::MacMismatch)),
+        }
+    }
+
+    /// Gets every value in the file other than the sops metadata, in document order
+    fn data(&self) -> &serde_yaml::Mapping;
+
     fn sops_metadata(&self) -> &SopsData;
 }
 
@@ -61,12 +213,12 @@ pub trait SopsFile {
 pub struct YamlSopsFile {
     pub sops: SopsData,
     #[serde(flatten)]
-    other: HashMap<String, serde_yaml::Value>,
+    other: serde_yaml::Mapping,
 }
 
 impl SopsFile for YamlSopsFile {
-    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&String> {
-        let first = self.other.get(key[0]);
+    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&'a String> {
+        let first = get_segment(&self.other, key.first()?);
         match first {
             Some(serde_yaml::Value::String(s)) => {
                 debug!("Found string: {:?}", s);
@@ -80,57 +232,812 @@ impl SopsFile for YamlSopsFile {
         }
     }
 
+    fn get_value(&self, key: &[&str]) -> Option<&serde_yaml::Value> {
+        let first = get_segment(&self.other, key.first()?)?;
+        first.get_nested_value(&key[1..])
+    }
+
+    fn data(&self) -> &serde_yaml::Mapping {
+        &self.other
+    }
+
     fn sops_metadata(&self) -> &SopsData {
         &self.sops
     }
 }
 
-pub fn load_sops_file(path: &str) -> Result<Box<dyn SopsFile>> {
-    debug!("Loading file from path: {}", path);
-    let data = std::fs::read_to_string(path)?;
+/// A dotenv file encrypted by sops, where every line is `KEY=value` and the metadata is flattened
+/// into `sops_` prefixed keys
+#[derive(Debug)]
+pub struct DotenvSopsFile {
+    pub sops: SopsData,
+    values: serde_yaml::Mapping,
+}
+
+/// The prefix of the keys holding the sops metadata in dotenv files
+const DOTENV_METADATA_PREFIX: &str = "sops_";
+
+impl DotenvSopsFile {
+    /// Parses a dotenv file, returning None if it has no sops metadata
+    fn parse(path: &str, data: &str) -> Result<Option<Self>> {
+        let mut values = serde_yaml::Mapping::new();
+        let mut metadata = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+        for (number, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                let message = format!("line {} is not KEY=value", number + 1);
+                return Err(Error::Syntax(path.to_string(), "dotenv", message).into());
+            };
+            match key.strip_prefix(DOTENV_METADATA_PREFIX) {
+                Some(key) => insert_flattened(&mut metadata, key, value.replace("\\n", "\n")),
+                None => {
+                    values.insert(key.into(), value.into());
+                }
+            }
+        }
+        if metadata.as_mapping().is_some_and(|m| m.is_empty()) {
+            return Ok(None);
+        }
+
+        let sops: SopsData = serde_yaml::from_value(metadata).map_err(|_| {
+            Error::NotASopsFile(path.to_string(), "the sops_ keys don't hold sops metadata")
+        })?;
+        Ok(Some(DotenvSopsFile { sops, values }))
+    }
+}
+
+/// Inserts a metadata value flattened the way sops writes it to dotenv files, where `__` separates
+/// the levels, `list_<n>` is a sequence item and `map_<key>` a mapping key. Flattening loses the
+/// types of the values, so `true` and `false` are read back as booleans for fields like
+/// `mac_only_encrypted`.
+fn insert_flattened(tree: &mut serde_yaml::Value, key: &str, value: String) {
+    let mut node = tree;
+    for part in key.split("__") {
+        node = if let Some(index) = part.strip_prefix("list_").and_then(|i| i.parse().ok()) {
+            if !node.is_sequence() {
+                *node = serde_yaml::Value::Sequence(vec![]);
+            }
+            let sequence = node.as_sequence_mut().unwrap();
+            if sequence.len() <= index {
+                sequence.resize(index + 1, serde_yaml::Value::Null);
+            }
+            &mut sequence[index]
+        } else {
+            let part = part.strip_prefix("map_").unwrap_or(part);
+            if !node.is_mapping() {
+                *node = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+            }
+            node.as_mapping_mut()
+                .unwrap()
+                .entry(part.into())
+                .or_insert(serde_yaml::Value::Null)
+        };
+    }
+    *node = match value.as_str() {
+        "true" => serde_yaml::Value::Bool(true),
+        "false" => serde_yaml::Value::Bool(false),
+        _ => serde_yaml::Value::String(value),
+    };
+}
+
+impl SopsFile for DotenvSopsFile {
+    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&'a String> {
+        // Variables are never nested, so only a single element path can match
+        match key {
+            [name] => match self.values.get(*name) {
+                Some(serde_yaml::Value::String(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn get_value(&self, key: &[&str]) -> Option<&serde_yaml::Value> {
+        match key {
+            [name] => self.values.get(*name),
+            _ => None,
+        }
+    }
+
+    fn data(&self) -> &serde_yaml::Mapping {
+        &self.values
+    }
+
+    fn sops_metadata(&self) -> &SopsData {
+        &self.sops
+    }
+}
+
+/// An INI file encrypted by sops, where the values are keyed by section and the metadata is
+/// flattened into the `[sops]` section
+#[derive(Debug)]
+pub struct IniSopsFile {
+    pub sops: SopsData,
+    sections: serde_yaml::Mapping,
+}
+
+/// The section holding the sops metadata in INI files
+const INI_METADATA_SECTION: &str = "sops";
+
+/// The section holding the keys that come before the first section header
+const INI_DEFAULT_SECTION: &str = "DEFAULT";
+
+impl IniSopsFile {
+    /// Parses an INI file, returning None if it has no sops metadata
+    fn parse(path: &str, data: &str) -> Result<Option<Self>> {
+        let mut sections = serde_yaml::Mapping::new();
+        let mut metadata = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+        let mut section = INI_DEFAULT_SECTION.to_string();
+        for (number, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                let message = format!("line {} is not key = value", number + 1);
+                return Err(Error::Syntax(path.to_string(), "INI", message).into());
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if section == INI_METADATA_SECTION {
+                insert_flattened(&mut metadata, key, value.replace("\\n", "\n"));
+                continue;
+            }
+            let values = sections
+                .entry(section.as_str().into())
+                .or_insert(serde_yaml::Value::Mapping(serde_yaml::Mapping::new()));
+            if let serde_yaml::Value::Mapping(values) = values {
+                values.insert(key.into(), value.into());
+            }
+        }
+        if metadata.as_mapping().is_some_and(|m| m.is_empty()) {
+            return Ok(None);
+        }
+
+        let sops: SopsData = serde_yaml::from_value(metadata).map_err(|_| {
+            Error::NotASopsFile(
+                path.to_string(),
+                "the [sops] section doesn't hold sops metadata",
+            )
+        })?;
+        Ok(Some(IniSopsFile { sops, sections }))
+    }
+}
+
+impl SopsFile for IniSopsFile {
+    fn get_key<'a>(&'a self, key: &[&'a str]) -> Option<&'a String> {
+        // Sections only hold plain values, so the path is always `section.key`
+        match key {
+            [_, _] => match self.get_value(key) {
+                Some(serde_yaml::Value::String(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn get_value(&self, key: &[&str]) -> Option<&serde_yaml::Value> {
+        match key {
+            [section] => self.sections.get(*section),
+            [section, name] => self.sections.get(*section)?.get(*name),
+            _ => None,
+        }
+    }
 
-    let try_yaml: Result<YamlSopsFile, serde_yaml::Error> = serde_yaml::from_str(&data);
-    if let Ok(yaml) = try_yaml {
-        debug!("Loaded");
-        return Ok(Box::new(yaml));
+    fn data(&self) -> &serde_yaml::Mapping {
+        &self.sections
+    }
+
+    fn sops_metadata(&self) -> &SopsData {
+        &self.sops
     }
+}
+
+/// Loads a sops file, reading its metadata from the dotted `sops_path` if one is provided rather
+/// than the top level `sops` key
+pub fn load_sops_file(path: &str, sops_path: Option<&str>) -> Result<Box<dyn SopsFile>> {
+    debug!("Loading file from path: {}", path);
+    let data = read_source(path).map_err(|e| Error::Read(path.to_string(), e))?;
+    parse_sops_file(path, sops_path, data)
+}
 
-    Err(anyhow!(Error::Parse))
+/// Loads a sops file without limiting the size of its source, for binary files that hold a whole
+/// file rather than a few values
+pub fn load_large_sops_file(path: &str, sops_path: Option<&str>) -> Result<Box<dyn SopsFile>> {
+    debug!("Loading file from path: {}", path);
+    let data = read_large_source(path).map_err(|e| Error::Read(path.to_string(), e))?;
+    parse_sops_file(path, sops_path, data)
 }
 
-fn decrypt(path: &[&str], data: &str, keyfile: &str, sops: &SopsData) -> Result<DecryptedValue> {
-    debug!("Decrypting {} with keyfile {}", data, keyfile);
-    let identities = match enc::age::get_public_keys(keyfile) {
-        Ok(i) => i,
-        Err(_) => return Err(anyhow!(DecryptionError::NoKey)),
+fn parse_sops_file(path: &str, sops_path: Option<&str>, data: String) -> Result<Box<dyn SopsFile>> {
+    // Dotenv files aren't YAML, their metadata is in `sops_` prefixed variables
+    if sops_path.is_none() && is_dotenv(&data) {
+        if let Some(file) = DotenvSopsFile::parse(path, &data)? {
+            debug!("Loaded dotenv file");
+            if file.sops.mac.is_empty() {
+                return Err(
+                    Error::NotASopsFile(path.to_string(), "the sops metadata has no mac").into(),
+                );
+            }
+            return Ok(Box::new(file));
+        }
+    }
+
+    // INI files aren't YAML either, their metadata is in the `[sops]` section
+    if sops_path.is_none() && is_ini(&data) {
+        if let Some(file) = IniSopsFile::parse(path, &data)? {
+            debug!("Loaded INI file");
+            if file.sops.mac.is_empty() {
+                return Err(
+                    Error::NotASopsFile(path.to_string(), "the sops metadata has no mac").into(),
+                );
+            }
+            return Ok(Box::new(file));
+        }
+    }
+
+    // JSON is a subset of YAML, so both are read as YAML
+    let mut document: serde_yaml::Value =
+        serde_yaml::from_str(&data).map_err(|e| syntax_error(path, &data, e))?;
+    let sops = if let Some(sops_path) = sops_path {
+        debug!("Reading sops metadata from {}", sops_path);
+        let key = sops_path.split('.').collect::<Vec<_>>();
+        remove_nested(&mut document, &key)
+            .ok_or_else(|| Error::MissingData(sops_path.to_string()))?
+    } else {
+        // Removing the metadata must keep the order of the data, the MAC depends on it
+        document
+            .as_mapping_mut()
+            .ok_or(Error::NotASopsFile(path.to_string(), "it is not a mapping"))?
+            .shift_remove("sops")
+            .ok_or(Error::NotASopsFile(
+                path.to_string(),
+                "it has no `sops` key",
+            ))?
     };
-    debug!("Identities: {:?}", identities);
-    let candidiates: Vec<&Age> = sops
+
+    let sops: SopsData = serde_yaml::from_value(sops).map_err(|_| {
+        Error::NotASopsFile(
+            path.to_string(),
+            "the `sops` key doesn't hold sops metadata",
+        )
+    })?;
+    if sops.mac.is_empty() {
+        return Err(Error::NotASopsFile(path.to_string(), "the sops metadata has no mac").into());
+    }
+    debug!("Loaded");
+    Ok(Box::new(YamlSopsFile {
+        sops,
+        other: serde_yaml::from_value(document).map_err(|_| Error::Parse)?,
+    }))
+}
+
+/// Checks if a file looks like a dotenv file encrypted by sops
+fn is_dotenv(data: &str) -> bool {
+    data.lines()
+        .any(|line| line.starts_with("sops_mac=") || line.starts_with("sops_version="))
+}
+
+/// Checks if a file looks like an INI file encrypted by sops
+fn is_ini(data: &str) -> bool {
+    data.lines()
+        .any(|line| line.trim() == format!("[{}]", INI_METADATA_SECTION))
+}
+
+/// Describes why a file isn't valid YAML, or JSON if the JSON parser got further into the file
+fn syntax_error(path: &str, data: &str, yaml: serde_yaml::Error) -> Error {
+    let yaml_position = yaml.location().map(|l| (l.line(), l.column()));
+    if let Err(json) = serde_json::from_str::<serde_json::Value>(data) {
+        if Some((json.line(), json.column())) > yaml_position {
+            return Error::Syntax(path.to_string(), "JSON", json.to_string());
+        }
+    }
+    Error::Syntax(path.to_string(), "YAML", yaml.to_string())
+}
+
+/// Re-wraps the data key of a sops file for an updated set of age recipients, leaving the data and
+/// the rest of the metadata untouched. This mirrors `sops updatekeys`.
+pub fn rekey_sops_file(
+    path: &str,
+    sops_path: Option<&str>,
+    identities: &Identities,
+    add: &[String],
+    remove: &[String],
+) -> Result<()> {
+    let data = std::fs::read_to_string(path)?;
+    let file = parse_sops_file(path, sops_path, data.clone())?;
+    let sops = file.sops_metadata();
+    let kek = decrypt_data_key(identities, sops)?;
+
+    let mut age: Vec<Age> = sops
         .age
         .iter()
-        .filter(|a| identities.contains(&a.recipient))
+        .filter(|a| !remove.contains(&a.recipient))
+        .cloned()
         .collect();
-    debug!("Found {} candidates", candidiates.len());
-    if candidiates.is_empty() {
-        return Err(anyhow!(DecryptionError::NoRecipients));
+    for recipient in add {
+        if age.iter().any(|a| &a.recipient == recipient) {
+            debug!("{} is already a recipient", recipient);
+            continue;
+        }
+        debug!("Adding recipient {}", recipient);
+        age.push(Age {
+            recipient: recipient.clone(),
+            enc: enc::age::encrypt_kek(&kek[..], recipient)?,
+        });
+    }
+    if age.is_empty() {
+        return Err(anyhow!(Error::NoRecipientsLeft(path.to_string())));
     }
 
-    let candidate = candidiates[0];
-    debug!("Candidate: {:?}", candidate);
+    // Keep the format the file was written in
+    let output = if is_dotenv(&data) {
+        replace_flattened_age(
+            &data,
+            &age,
+            |_, key| key.starts_with("sops_age__"),
+            |key, value| format!("sops_{}={}", key, value),
+        )?
+    } else if is_ini(&data) {
+        replace_flattened_age(
+            &data,
+            &age,
+            |section, key| section == INI_METADATA_SECTION && key.starts_with("age__"),
+            |key, value| format!("{} = {}", key, value),
+        )?
+    } else {
+        replace_age_block(&data, sops_path, &age)?
+    };
+
+    // The file is only replaced once the rekeyed copy is known to decrypt, so a failed rekey never
+    // loses the original
+    let temp = crate::fs::temp_path(Path::new(path));
+    let result = std::fs::write(&temp, output)
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            std::fs::set_permissions(&temp, std::fs::metadata(path)?.permissions())?;
+            let rekeyed = parse_sops_file(path, sops_path, std::fs::read_to_string(&temp)?)?;
+            decrypt_data_key(identities, rekeyed.sops_metadata())?;
+            Ok(std::fs::rename(&temp, path)?)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Replaces the `age` entries of a dotenv or INI file, which sops flattens into one line per
+/// field with newlines escaped. `is_age` decides from the section and key if a line is one of the
+/// entries and `write_line` writes one from a key and value.
+fn replace_flattened_age(
+    data: &str,
+    age: &[Age],
+    is_age: impl Fn(&str, &str) -> bool,
+    write_line: impl Fn(&str, &str) -> String,
+) -> Result<String> {
+    let mut lines = vec![];
+    let mut position = None;
+    let mut section = INI_DEFAULT_SECTION.to_string();
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+        }
+        match trimmed.split_once('=') {
+            Some((key, _)) if is_age(&section, key.trim()) => {
+                position.get_or_insert(lines.len());
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    let position = position.ok_or_else(|| Error::MissingData("sops.age".to_string()))?;
+
+    let entries = age.iter().enumerate().flat_map(|(i, entry)| {
+        let recipient = format!("age__list_{}__map_recipient", i);
+        let enc = format!("age__list_{}__map_enc", i);
+        [
+            write_line(&recipient, &entry.recipient),
+            write_line(&enc, &entry.enc.replace('\n', "\\n")),
+        ]
+    });
+    let rest = lines.split_off(position);
+    lines.extend(entries);
+    lines.extend(rest);
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Replaces the `age` list of a YAML or JSON file as text, so every other byte of the file,
+/// comments included, stays as it was
+fn replace_age_block(data: &str, sops_path: Option<&str>, age: &[Age]) -> Result<String> {
+    let mut key: Vec<&str> = sops_path.unwrap_or("sops").split('.').collect();
+    key.push("age");
+    let missing = || Error::MissingData(key.join("."));
+
+    if data.trim_start().starts_with('{') {
+        let (start, end) = json_span(data.as_bytes(), 0, &key).ok_or_else(missing)?;
+        // The list is indented like the key it belongs to, with the indent the file uses per level
+        let line = &data[data[..start].rfind('\n').map_or(0, |i| i + 1)..];
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let list = if indent.is_empty() {
+            serde_json::to_string(age)?
+        } else {
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(
+                &indent.as_bytes()[..indent.len() / key.len()],
+            );
+            let mut list = vec![];
+            age.serialize(&mut serde_json::Serializer::with_formatter(
+                &mut list, formatter,
+            ))?;
+            String::from_utf8(list)?.replace('\n', &format!("\n{}", indent))
+        };
+        return Ok(format!("{}{}{}", &data[..start], list, &data[end..]));
+    }
 
-    let kek =
-        enc::age::decrypt_kek(&candidate.enc, keyfile).map_err(DecryptionError::KekDecryption)?;
-    let kek: &[u8; 32] = kek[..].try_into()?;
+    let lines: Vec<&str> = data.split_inclusive('\n').collect();
+    // Each key is looked for in the lines of the value of the one before it
+    let (mut start, mut end) = (0, lines.len());
+    let mut within = 0..lines.len();
+    for segment in &key {
+        start = within.start + find_yaml_key(&lines[within], segment).ok_or_else(missing)?;
+        end = yaml_block_end(&lines, start);
+        within = start + 1..end;
+    }
+    let indent = " ".repeat(yaml_indent(lines[start]));
+    let mut block = serde_yaml::Mapping::new();
+    block.insert("age".into(), serde_yaml::to_value(age)?);
+    let block: String = serde_yaml::to_string(&block)?
+        .lines()
+        .map(|line| format!("{}{}\n", indent, line))
+        .collect();
+    Ok(lines[..start].concat() + &block + &lines[end..].concat())
+}
+
+fn yaml_indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Finds the line of a key in a block of YAML lines, at the indent of the block's first entry
+fn find_yaml_key(lines: &[&str], key: &str) -> Option<usize> {
+    let indent = lines
+        .iter()
+        .find(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        .map(|l| yaml_indent(l))?;
+    lines.iter().position(|l| {
+        let trimmed = l.trim();
+        yaml_indent(l) == indent
+            && (trimmed == format!("{}:", key) || trimmed.starts_with(&format!("{}: ", key)))
+    })
+}
+
+/// Finds the end of the value of the key on line `start`, which is every following line that is
+/// indented further or is a sequence item at the same indent. Trailing blank lines are left out.
+fn yaml_block_end(lines: &[&str], start: usize) -> usize {
+    let indent = yaml_indent(lines[start]);
+    let mut end = start + 1;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if yaml_indent(line) > indent || (yaml_indent(line) == indent && trimmed.starts_with("- "))
+        {
+            end = i + 1;
+        } else {
+            break;
+        }
+    }
+    end
+}
+
+/// Finds the byte range of the value at `key` in the JSON object starting at `i`
+fn json_span(data: &[u8], mut i: usize, key: &[&str]) -> Option<(usize, usize)> {
+    let skip_whitespace = |i: &mut usize| {
+        while data.get(*i).is_some_and(u8::is_ascii_whitespace) {
+            *i += 1;
+        }
+    };
+    skip_whitespace(&mut i);
+    let Some((first, rest)) = key.split_first() else {
+        return Some((i, json_value_end(data, i)?));
+    };
+    if data.get(i) != Some(&b'{') {
+        return None;
+    }
+    i += 1;
+    loop {
+        skip_whitespace(&mut i);
+        let end = json_value_end(data, i)?;
+        let name: String = serde_json::from_slice(&data[i..end]).ok()?;
+        i = end;
+        skip_whitespace(&mut i);
+        if data.get(i) != Some(&b':') {
+            return None;
+        }
+        i += 1;
+        if name == *first {
+            return json_span(data, i, rest);
+        }
+        skip_whitespace(&mut i);
+        i = json_value_end(data, i)?;
+        skip_whitespace(&mut i);
+        if data.get(i) != Some(&b',') {
+            return None;
+        }
+        i += 1;
+    }
+}
+
+/// Finds the end of the JSON value starting at `i`
+fn json_value_end(data: &[u8], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    while let Some(&c) = data.get(i) {
+        i += 1;
+        match c {
+            b'\\' if in_string => i += 1,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            // A scalar ends at the next separator, or the end of the object or array holding it
+            b',' | b':' | b'}' | b']' if depth == 0 => return Some(i - 1),
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth -= 1,
+            c if depth == 0 && c.is_ascii_whitespace() => return Some(i - 1),
+            _ => {}
+        }
+        if depth == 0 && !in_string && matches!(c, b'"' | b'}' | b']') {
+            return Some(i);
+        }
+    }
+    (depth == 0 && !in_string).then_some(i)
+}
+
+fn decrypt(
+    path: &[&str],
+    data: &str,
+    identities: &Identities,
+    sops: &SopsData,
+) -> Result<DecryptedValue> {
+    debug!("Decrypting {}", data);
+    if !data.starts_with("ENC[") {
+        return Err(anyhow!(Error::NotEncrypted(path.join("."))));
+    }
+    let kek = decrypt_data_key(identities, sops)?;
 
     enc::age::decrypt(
         data.to_string(),
-        kek,
+        &kek,
         path.iter().map(|f| f.to_string()).collect(),
+        identities.options().lenient_types,
     )
 }
 
+/// Finds the first recipient of the file that one of the provided recipients matches, which is the
+/// one used to decrypt the data key. Recipients are compared after normalizing them, as ones
+/// copied from hand-edited files can carry stray whitespace or casing.
+pub fn find_candidate<'a>(sops: &'a SopsData, recipients: &[String]) -> Option<&'a Age> {
+    let recipients: Vec<String> = recipients.iter().map(|r| normalize_recipient(r)).collect();
+    let mut candidate = None;
+    for entry in &sops.age {
+        let recipient = normalize_recipient(&entry.recipient);
+        if recipient.parse::<age::x25519::Recipient>().is_err()
+            && !enc::age::is_passphrase(&entry.enc)
+        {
+            warn!("{:?} is not a valid age recipient", entry.recipient);
+        }
+        if candidate.is_none() && recipients.contains(&recipient) {
+            candidate = Some(entry);
+        }
+    }
+    candidate
+}
+
+/// Normalizes an age recipient for comparison. Bech32 is case insensitive, but age only parses the
+/// lowercase form.
+pub fn normalize_recipient(recipient: &str) -> String {
+    recipient.trim().to_lowercase()
+}
+
+/// Decrypts the data key of the sops file using the first matching identity
+fn decrypt_data_key(identities: &Identities, sops: &SopsData) -> Result<Zeroizing<[u8; 32]>> {
+    let recipients = identities.public_keys();
+    debug!("Identities: {:?}", recipients);
+    // Passphrase-wrapped data keys are a fallback for when none of the identities match
+    let candidate = find_candidate(sops, &recipients)
+        .or_else(|| sops.age.iter().find(|a| enc::age::is_passphrase(&a.enc)));
+    let Some(candidate) = candidate else {
+        if recipients.is_empty() {
+            return Err(anyhow!(DecryptionError::NoKey));
+        }
+        return Err(anyhow!(DecryptionError::NoRecipients {
+            wanted: sops.age.iter().map(|a| a.recipient.clone()).collect(),
+            ours: recipients,
+        }));
+    };
+    debug!("Candidate: {:?}", candidate);
+
+    let kek = identities
+        .data_key(&candidate.enc)
+        .map_err(DecryptionError::KekDecryption)?;
+    Ok(kek)
+}
+
+/// Recursively decrypts every leaf of the provided value, returning None for comments. Sequence
+/// items share their parent's path, matching how sops computes the additional data.
+fn decrypt_tree(
+    value: &serde_yaml::Value,
+    kek: &[u8; 32],
+    path: &[String],
+    sops: &SopsData,
+    lenient: bool,
+) -> Result<Option<serde_yaml::Value>> {
+    match value {
+        serde_yaml::Value::String(s) if s.is_empty() => Ok(Some(value.clone())),
+        serde_yaml::Value::String(s) if !s.starts_with("ENC[") => {
+            let key: Vec<&str> = path.iter().map(String::as_str).collect();
+            if sops.is_encrypted(&key)? {
+                Err(anyhow!(Error::NotEncrypted(path.join("."))))
+            } else {
+                Ok(Some(value.clone()))
+            }
+        }
+        serde_yaml::Value::String(s) => {
+            match enc::age::decrypt(s.clone(), kek, path.to_vec(), lenient)? {
+                DecryptedValue::Comment(_) => Ok(None),
+                decrypted => Ok(Some(decrypted.into())),
+            }
+        }
+        serde_yaml::Value::Mapping(m) => {
+            let mut decrypted = serde_yaml::Mapping::new();
+            for (key, value) in m {
+                let mut child = path.to_vec();
+                child.push(mapping_key(path, key)?);
+                if let Some(value) = decrypt_tree(value, kek, &child, sops, lenient)? {
+                    decrypted.insert(key.clone(), value);
+                }
+            }
+            Ok(Some(serde_yaml::Value::Mapping(decrypted)))
+        }
+        serde_yaml::Value::Sequence(s) => {
+            let mut decrypted = vec![];
+            for value in s {
+                if let Some(value) = decrypt_tree(value, kek, path, sops, lenient)? {
+                    decrypted.push(value);
+                }
+            }
+            Ok(Some(serde_yaml::Value::Sequence(decrypted)))
+        }
+        other => Ok(Some(other.clone())),
+    }
+}
+
+/// Converts a scalar that isn't encrypted into the value it would have decrypted to
+pub(crate) fn plaintext_value(key: &[&str], value: &serde_yaml::Value) -> Result<DecryptedValue> {
+    match value {
+        serde_yaml::Value::String(s) => Ok(DecryptedValue::String(s.clone())),
+        serde_yaml::Value::Bool(b) => Ok(DecryptedValue::Bool(*b)),
+        serde_yaml::Value::Number(n) => match n.as_i64() {
+            Some(i) => Ok(DecryptedValue::Int(i)),
+            None => Ok(DecryptedValue::Float(n.as_f64().unwrap_or_default())),
+        },
+        serde_yaml::Value::Null => Ok(DecryptedValue::String(String::new())),
+        _ => Err(anyhow!(Error::MissingData(key.join(".")))),
+    }
+}
+
+/// Gets the name of a mapping key as it appears in the additional data of its values
+fn mapping_key(path: &[String], key: &serde_yaml::Value) -> Result<String> {
+    match key {
+        serde_yaml::Value::String(s) => Ok(s.clone()),
+        serde_yaml::Value::Number(n) => Ok(n.to_string()),
+        _ => Err(anyhow!(Error::MissingData(format!(
+            "{}.{:?}",
+            path.join("."),
+            key
+        )))),
+    }
+}
+
+/// Formats a float the way sops does with Go's `FormatFloat(f, 'f', -1, 64)`, which is the
+/// shortest text that reads back as the same float, so `1.0` is written as `1`
+pub(crate) fn format_float(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+    // Rust also writes the shortest text and never uses an exponent
+    f.to_string()
+}
+
+/// Feeds the plaintext of every leaf into the MAC, matching how sops formats each type. Values
+/// that aren't encrypted are skipped if the MAC only covers encrypted values.
+fn hash_tree(
+    value: &serde_yaml::Value,
+    kek: &[u8; 32],
+    path: &[String],
+    only_encrypted: bool,
+    lenient: bool,
+    hasher: &mut Sha512,
+) -> Result<()> {
+    match value {
+        serde_yaml::Value::String(s) if s.starts_with("ENC[") => {
+            match enc::age::decrypt(s.clone(), kek, path.to_vec(), lenient)? {
+                DecryptedValue::String(s) => hasher.update(s.as_bytes()),
+                DecryptedValue::Int(i) => hasher.update(i.to_string()),
+                DecryptedValue::Float(f) => hasher.update(format_float(f)),
+                DecryptedValue::Bytes(b) => hasher.update(&b),
+                DecryptedValue::Bool(b) => hasher.update(if b { "True" } else { "False" }),
+                DecryptedValue::Comment(_) => {}
+            }
+        }
+        serde_yaml::Value::Mapping(m) => {
+            for (key, value) in m {
+                let mut child = path.to_vec();
+                child.push(mapping_key(path, key)?);
+                hash_tree(value, kek, &child, only_encrypted, lenient, hasher)?;
+            }
+        }
+        serde_yaml::Value::Sequence(s) => {
+            for value in s {
+                hash_tree(value, kek, path, only_encrypted, lenient, hasher)?;
+            }
+        }
+        _ if only_encrypted => {}
+        serde_yaml::Value::String(s) => hasher.update(s.as_bytes()),
+        serde_yaml::Value::Number(n) => match n.as_f64().filter(|_| n.is_f64()) {
+            Some(f) => hasher.update(format_float(f)),
+            None => hasher.update(n.to_string()),
+        },
+        serde_yaml::Value::Bool(b) => hasher.update(if *b { "True" } else { "False" }),
+        _ => {}
+    }
+    Ok(())
+}
+
+impl From<DecryptedValue> for serde_yaml::Value {
+    fn from(value: DecryptedValue) -> Self {
+        match value {
+            DecryptedValue::String(s) => serde_yaml::Value::String(s),
+            DecryptedValue::Int(i) => serde_yaml::Value::Number(i.into()),
+            DecryptedValue::Float(f) => serde_yaml::Value::Number(f.into()),
+            DecryptedValue::Bytes(b) => {
+                serde_yaml::Value::String(String::from_utf8_lossy(&b).into_owned())
+            }
+            DecryptedValue::Bool(b) => serde_yaml::Value::Bool(b),
+            DecryptedValue::Comment(_) => serde_yaml::Value::Null,
+        }
+    }
+}
+
+/// Removes the value at the provided key, returning it if it exists
+fn remove_nested(value: &mut serde_yaml::Value, key: &[&str]) -> Option<serde_yaml::Value> {
+    match key {
+        [] => None,
+        [last] => value.as_mapping_mut()?.remove(*last),
+        [first, rest @ ..] => remove_nested(value.get_mut(*first)?, rest),
+    }
+}
+
+/// Gets the value under a path segment, falling back to an integer key if the segment is numeric
+fn get_segment<'a>(m: &'a serde_yaml::Mapping, key: &str) -> Option<&'a serde_yaml::Value> {
+    m.get(key).or_else(|| {
+        let number: serde_yaml::Number = if let Ok(n) = key.parse::<i64>() {
+            n.into()
+        } else {
+            key.parse::<u64>().ok()?.into()
+        };
+        m.get(serde_yaml::Value::Number(number))
+    })
+}
+
 trait Nested {
     fn get_nested(&self, key: &[&str]) -> Option<&String>;
+
+    fn get_nested_value(&self, key: &[&str]) -> Option<&Self>;
 }
 
 impl Nested for serde_yaml::Value {
@@ -146,7 +1053,7 @@ impl Nested for serde_yaml::Value {
             serde_yaml::Value::Mapping(m) => {
                 let current_key = key.first();
                 let current = match current_key {
-                    Some(k) => m.get(k),
+                    Some(k) => get_segment(m, k),
                     None => None,
                 };
                 if let Some(value) = current {
@@ -158,4 +1065,624 @@ impl Nested for serde_yaml::Value {
             _ => None,
         }
     }
+
+    fn get_nested_value(&self, key: &[&str]) -> Option<&Self> {
+        let Some(current_key) = key.first() else {
+            return Some(self);
+        };
+        match self {
+            serde_yaml::Value::Mapping(m) => {
+                get_segment(m, current_key)?.get_nested_value(&key[1..])
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{encrypt_value, identities, identity, Scratch, Sops, LAST_MODIFIED};
+
+    fn load(scratch: &Scratch, name: &str, contents: &str) -> Box<dyn SopsFile> {
+        match load_sops_file(&scratch.write(name, contents), None) {
+            Ok(file) => file,
+            Err(e) => panic!("{:#}", e),
+        }
+    }
+
+    fn decrypt_string(file: &dyn SopsFile, key: &[&str], identities: &Identities) -> String {
+        match file.decrypt(key, identities) {
+            Ok(DecryptedValue::String(s)) => s,
+            Ok(_) => panic!("{} is not a string", key.join(".")),
+            Err(e) => panic!("{:#}", e),
+        }
+    }
+
+    fn decrypt_error(file: &dyn SopsFile, key: &[&str], identities: &Identities) -> Error {
+        let error = file.decrypt(key, identities).err().unwrap();
+        error.downcast::<Error>().unwrap()
+    }
+
+    #[test]
+    fn encrypted_regex_matches_the_last_key_only() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |path| path.last().is_some_and(|k| k == "password"),
+            metadata: vec![("encrypted_regex", "^(data|password)$")],
+            ..Sops::new(&identity)
+        };
+        let plaintext = "data:\n  user: admin\n  password: hunter2\n";
+        let file = load(&scratch, "secrets.yaml", &sops.yaml(plaintext));
+
+        // `data` matches, but its children only have to be encrypted if they match themselves
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["data", "user"], &identities),
+            "admin"
+        );
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["data", "password"], &identities),
+            "hunter2"
+        );
+        assert_eq!(
+            file.decrypt_all(&identities).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Mapping>(plaintext).unwrap()
+        );
+    }
+
+    #[test]
+    fn plaintext_values_matching_encrypted_regex_are_rejected() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |_| false,
+            metadata: vec![("encrypted_regex", "^password$")],
+            ..Sops::new(&identity)
+        };
+        let file = load(&scratch, "secrets.yaml", &sops.yaml("password: hunter2\n"));
+
+        assert!(matches!(
+            decrypt_error(file.as_ref(), &["password"], &identities),
+            Error::NotEncrypted(key) if key == "password"
+        ));
+    }
+
+    #[test]
+    fn encrypted_suffix_matches_the_last_key_only() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |path| path.last().is_some_and(|k| k.ends_with("_secret")),
+            metadata: vec![("encrypted_suffix", "_secret")],
+            ..Sops::new(&identity)
+        };
+        let plaintext = "db_secret:\n  host: localhost\n  password_secret: hunter2\n";
+        let file = load(&scratch, "secrets.yaml", &sops.yaml(plaintext));
+
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["db_secret", "host"], &identities),
+            "localhost"
+        );
+        assert_eq!(
+            decrypt_string(
+                file.as_ref(),
+                &["db_secret", "password_secret"],
+                &identities
+            ),
+            "hunter2"
+        );
+        assert_eq!(
+            file.decrypt_all(&identities).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Mapping>(plaintext).unwrap()
+        );
+    }
+
+    #[test]
+    fn plaintext_values_with_encrypted_suffix_are_rejected() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |_| false,
+            metadata: vec![("encrypted_suffix", "_secret")],
+            ..Sops::new(&identity)
+        };
+        let file = load(
+            &scratch,
+            "secrets.yaml",
+            &sops.yaml("password_secret: hunter2\n"),
+        );
+
+        assert!(matches!(
+            decrypt_error(file.as_ref(), &["password_secret"], &identities),
+            Error::NotEncrypted(key) if key == "password_secret"
+        ));
+    }
+
+    #[test]
+    fn both_suffixes_conflict() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |_| false,
+            metadata: vec![
+                ("encrypted_suffix", "_secret"),
+                ("unencrypted_suffix", "_unencrypted"),
+            ],
+            ..Sops::new(&identity)
+        };
+        let file = load(&scratch, "secrets.yaml", &sops.yaml("user: admin\n"));
+
+        assert!(matches!(
+            decrypt_error(file.as_ref(), &["user"], &identities),
+            Error::ConflictingSuffixes
+        ));
+        let error = file.decrypt_all(&identities).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::ConflictingSuffixes)
+        ));
+    }
+
+    #[test]
+    fn dotenv_files_are_decrypted() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops::new(&identity);
+        let file = load(
+            &scratch,
+            "app.env",
+            &sops.dotenv("USER: admin\nPASSWORD: hunter2\n"),
+        );
+
+        file.verify_mac(&identities).unwrap();
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["PASSWORD"], &identities),
+            "hunter2"
+        );
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["USER"], &identities),
+            "admin"
+        );
+    }
+
+    #[test]
+    fn dotenv_metadata_keeps_its_booleans() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let sops = Sops {
+            encrypted: |path| path.last().is_some_and(|k| !k.ends_with("_unencrypted")),
+            mac_only_encrypted: true,
+            metadata: vec![("unencrypted_suffix", "_unencrypted")],
+            ..Sops::new(&identity)
+        };
+        let plaintext = "HOST_unencrypted: localhost\nPASSWORD: hunter2\n";
+        let file = load(&scratch, "app.env", &sops.dotenv(plaintext));
+
+        assert_eq!(file.sops_metadata().mac_only_encrypted, Some(true));
+        file.verify_mac(&identities).unwrap();
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["HOST_unencrypted"], &identities),
+            "localhost"
+        );
+    }
+
+    #[test]
+    fn ini_files_are_decrypted_by_section() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let plaintext =
+            "database:\n  user: admin\n  password: hunter2\ncache:\n  password: letmein\n";
+        let file = load(&scratch, "app.ini", &Sops::new(&identity).ini(plaintext));
+
+        file.verify_mac(&identities).unwrap();
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["database", "password"], &identities),
+            "hunter2"
+        );
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["cache", "password"], &identities),
+            "letmein"
+        );
+        assert!(matches!(
+            decrypt_error(file.as_ref(), &["password"], &identities),
+            Error::MissingData(_)
+        ));
+    }
+
+    /// Encrypts everything but the values below keys ending in `_unencrypted`, like sops does
+    fn unencrypted_suffix(identity: &age::x25519::Identity, mac_only_encrypted: bool) -> Sops {
+        Sops {
+            encrypted: |path| !path.iter().any(|k| k.ends_with("_unencrypted")),
+            mac_only_encrypted,
+            metadata: vec![("unencrypted_suffix", "_unencrypted")],
+            ..Sops::new(identity)
+        }
+    }
+
+    #[test]
+    fn unencrypted_suffix_values_are_read_as_plaintext() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let plaintext = "host_unencrypted: localhost\nport_unencrypted: 5432\nsettings_unencrypted:\n  debug: true\npassword: hunter2\n";
+        let file = load(
+            &scratch,
+            "secrets.yaml",
+            &unencrypted_suffix(&identity, false).yaml(plaintext),
+        );
+
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["host_unencrypted"], &identities),
+            "localhost"
+        );
+        assert!(matches!(
+            file.decrypt(&["port_unencrypted"], &identities),
+            Ok(DecryptedValue::Int(5432))
+        ));
+        assert!(matches!(
+            file.decrypt(&["settings_unencrypted", "debug"], &identities),
+            Ok(DecryptedValue::Bool(true))
+        ));
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["password"], &identities),
+            "hunter2"
+        );
+        assert_eq!(
+            file.decrypt_all(&identities).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Mapping>(plaintext).unwrap()
+        );
+    }
+
+    #[test]
+    fn mac_verifies_with_and_without_mac_only_encrypted() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let plaintext = "host_unencrypted: localhost\nratio: 0.5\nitems:\n  - one\n  - two\npassword: hunter2\n";
+        for mac_only_encrypted in [false, true] {
+            let sops = unencrypted_suffix(&identity, mac_only_encrypted);
+            let file = load(&scratch, "secrets.yaml", &sops.yaml(plaintext));
+            if let Err(e) = file.verify_mac(&identities) {
+                panic!("mac_only_encrypted: {}: {:#}", mac_only_encrypted, e);
+            }
+        }
+
+        // A plaintext value is only covered by the MAC if mac_only_encrypted isn't set
+        for (mac_only_encrypted, verifies) in [(false, false), (true, true)] {
+            let encrypted = unencrypted_suffix(&identity, mac_only_encrypted).yaml(plaintext);
+            let tampered = encrypted.replace("localhost", "example.com");
+            let file = load(&scratch, "secrets.yaml", &tampered);
+            assert_eq!(
+                file.verify_mac(&identities).is_ok(),
+                verifies,
+                "mac_only_encrypted: {}",
+                mac_only_encrypted
+            );
+        }
+    }
+
+    fn load_error(scratch: &Scratch, name: &str, contents: &str) -> Error {
+        let path = scratch.write(name, contents);
+        let error = load_sops_file(&path, None).err().unwrap();
+        error.downcast::<Error>().unwrap()
+    }
+
+    #[test]
+    fn files_without_sops_metadata_are_rejected() {
+        let scratch = Scratch::new();
+        assert!(matches!(
+            load_error(&scratch, "plain.yaml", "password: hunter2\n"),
+            Error::NotASopsFile(_, reason) if reason.contains("no `sops` key")
+        ));
+        assert!(matches!(
+            load_error(&scratch, "list.yaml", "- password\n- hunter2\n"),
+            Error::NotASopsFile(_, reason) if reason.contains("not a mapping")
+        ));
+    }
+
+    #[test]
+    fn unreadable_and_malformed_files_are_reported() {
+        let scratch = Scratch::new();
+        let missing = scratch.join("missing.yaml");
+        let error = load_sops_file(&missing, None).err().unwrap();
+        assert!(matches!(
+            error.downcast::<Error>().unwrap(),
+            Error::Read(path, _) if path == missing
+        ));
+
+        assert!(matches!(
+            load_error(&scratch, "broken.yaml", "password: [hunter2\n"),
+            Error::Syntax(..)
+        ));
+        assert!(matches!(
+            load_error(&scratch, "broken.json", "{\"password\": \"hunter2\"\n"),
+            Error::Syntax(..)
+        ));
+    }
+
+    #[test]
+    fn sops_metadata_can_be_relocated() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let (mut document, metadata) = Sops::new(&identity).encrypt("password: hunter2\n");
+        let mut meta = serde_yaml::Mapping::new();
+        meta.insert("sops".into(), metadata.into());
+        document.insert("meta".into(), meta.into());
+        let path = scratch.write("secrets.yaml", serde_yaml::to_string(&document).unwrap());
+
+        let file = match load_sops_file(&path, Some("meta.sops")) {
+            Ok(file) => file,
+            Err(e) => panic!("{:#}", e),
+        };
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["password"], &identities),
+            "hunter2"
+        );
+        let error = load_sops_file(&path, Some("meta.missing")).err().unwrap();
+        assert!(matches!(
+            error.downcast::<Error>().unwrap(),
+            Error::MissingData(key) if key == "meta.missing"
+        ));
+    }
+
+    #[test]
+    fn floats_are_hashed_the_way_go_formats_them() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let sops = Sops {
+            encrypted: |path| path.last().is_some_and(|k| k != "ratio"),
+            ..Sops::new(&identity)
+        };
+        let (mut document, mut metadata) =
+            sops.encrypt("ratio: 1.0\nport: 8080\nscale: 2.50\nenabled: true\n");
+        // The MAC sops computes, over the plaintexts as written by Go
+        let mac = Sha512::new()
+            .chain_update("1")
+            .chain_update("8080")
+            .chain_update("2.5")
+            .chain_update("True")
+            .finalize();
+        let mac = format!("{:X}", mac);
+        metadata.insert(
+            "mac".into(),
+            encrypt_value(mac.as_bytes(), LAST_MODIFIED.as_bytes(), "str").into(),
+        );
+        document.insert("sops".into(), metadata.into());
+        let file = load(
+            &scratch,
+            "floats.yaml",
+            &serde_yaml::to_string(&document).unwrap(),
+        );
+
+        file.verify_mac(&identities(&identity)).unwrap();
+        for (f, formatted) in [
+            (1e21, "1000000000000000000000"),
+            (1.5e-7, "0.00000015"),
+            (-0.0, "-0"),
+            (f64::NEG_INFINITY, "-Inf"),
+        ] {
+            assert_eq!(format_float(f), formatted);
+        }
+    }
+
+    #[test]
+    fn integer_keys_are_looked_up_by_their_text() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let plaintext = "ports:\n  8080: web\n  5432: db\n8443: tls\n";
+        let file = load(
+            &scratch,
+            "ports.yaml",
+            &Sops::new(&identity).yaml(plaintext),
+        );
+
+        file.verify_mac(&identities).unwrap();
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["ports", "8080"], &identities),
+            "web"
+        );
+        assert_eq!(decrypt_string(file.as_ref(), &["8443"], &identities), "tls");
+        assert_eq!(
+            file.decrypt_all(&identities).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Mapping>(plaintext).unwrap()
+        );
+    }
+
+    #[test]
+    fn recipients_are_matched_ignoring_case_and_whitespace() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let recipient = identity.to_public().to_string();
+        let encrypted = Sops::new(&identity).yaml("password: hunter2\n").replace(
+            &format!("recipient: {}", recipient),
+            &format!("recipient: \"  {}  \"", recipient.to_uppercase()),
+        );
+        let file = load(&scratch, "secrets.yaml", &encrypted);
+
+        let candidate =
+            find_candidate(file.sops_metadata(), std::slice::from_ref(&recipient)).unwrap();
+        assert_eq!(
+            candidate.recipient,
+            format!("  {}  ", recipient.to_uppercase())
+        );
+        assert_eq!(
+            decrypt_string(file.as_ref(), &["password"], &identities),
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn empty_and_null_values_decrypt_to_empty_strings() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let plaintext = "empty: ''\nnothing: null\npassword: hunter2\n";
+        let file = load(
+            &scratch,
+            "secrets.yaml",
+            &Sops::new(&identity).yaml(plaintext),
+        );
+
+        file.verify_mac(&identities).unwrap();
+        assert_eq!(decrypt_string(file.as_ref(), &["empty"], &identities), "");
+        assert_eq!(decrypt_string(file.as_ref(), &["nothing"], &identities), "");
+        assert!(matches!(
+            decrypt_error(file.as_ref(), &["missing"], &identities),
+            Error::MissingData(key) if key == "missing"
+        ));
+    }
+
+    #[test]
+    fn subtrees_decrypt_to_their_original_structure() {
+        let scratch = Scratch::new();
+        let identity = identity();
+        let identities = identities(&identity);
+        let subtree =
+            "user: admin\nport: 5432\nratio: 0.5\nhosts:\n  - a.example.com\n  - b.example.com\n";
+        let plaintext = format!(
+            "database:\n{}other: value\n",
+            subtree
+                .lines()
+                .map(|line| format!("  {}\n", line))
+                .collect::<String>()
+        );
+        let file = load(
+            &scratch,
+            "secrets.yaml",
+            &Sops::new(&identity).yaml(&plaintext),
+        );
+
+        let decrypted = file.decrypt_subtree(&["database"], &identities).unwrap();
+        assert_eq!(
+            decrypted,
+            serde_yaml::from_str::<serde_yaml::Value>(subtree).unwrap()
+        );
+    }
+
+    #[test]
+    fn rekeyed_files_decrypt_for_the_added_recipients() {
+        let scratch = Scratch::new();
+        let (ours, stale, added) = (identity(), identity(), identity());
+        let ours_recipient = ours.to_public().to_string();
+        let stale_recipient = stale.to_public().to_string();
+        let added_recipient = added.to_public().to_string();
+        let sops = Sops {
+            recipients: vec![ours_recipient.clone(), stale_recipient.clone()],
+            ..Sops::new(&ours)
+        };
+        let plaintext = "app:\n  password: hunter2\n";
+        for (name, contents, key) in [
+            (
+                "secrets.yaml",
+                sops.yaml(plaintext),
+                &["app", "password"][..],
+            ),
+            ("secrets.ini", sops.ini(plaintext), &["app", "password"][..]),
+            (
+                "secrets.json",
+                serde_json::to_string_pretty(
+                    &serde_yaml::from_str::<serde_json::Value>(&sops.yaml(plaintext)).unwrap(),
+                )
+                .unwrap(),
+                &["app", "password"][..],
+            ),
+            (
+                "secrets.env",
+                sops.dotenv("PASSWORD: hunter2\n"),
+                &["PASSWORD"][..],
+            ),
+        ] {
+            let path = scratch.write(name, contents);
+            let (add, remove) = ([added_recipient.clone()], [stale_recipient.clone()]);
+            if let Err(e) = rekey_sops_file(&path, None, &identities(&ours), &add, &remove) {
+                panic!("{}: {:#}", name, e);
+            }
+
+            let file = load_sops_file(&path, None).unwrap();
+            let recipients: Vec<&str> = file
+                .sops_metadata()
+                .age
+                .iter()
+                .map(|a| a.recipient.as_str())
+                .collect();
+            assert_eq!(
+                recipients,
+                [ours_recipient.as_str(), added_recipient.as_str()]
+            );
+            file.verify_mac(&identities(&added)).unwrap();
+            assert_eq!(
+                decrypt_string(file.as_ref(), key, &identities(&added)),
+                "hunter2"
+            );
+        }
+    }
+
+    #[test]
+    fn rekeys_only_change_the_age_list() {
+        let scratch = Scratch::new();
+        let (ours, added) = (identity(), identity());
+        let added_recipient = added.to_public().to_string();
+        let encrypted = Sops::new(&ours).yaml("user: admin\npassword: hunter2\n");
+        let mut nested: serde_yaml::Mapping = serde_yaml::from_str(&encrypted).unwrap();
+        let metadata = nested.remove("sops").unwrap();
+        nested.insert("meta".into(), serde_yaml::Mapping::new().into());
+        nested["meta"]["sops"] = metadata;
+        let nested = serde_yaml::to_string(&nested).unwrap();
+
+        for (contents, sops_path) in [(encrypted, None), (nested, Some("meta.sops"))] {
+            // Comments are dropped by any YAML parser, so they show the file wasn't rewritten
+            let contents = format!("# Application credentials\n{}", contents)
+                .replace("password:", "# Rotated yearly\npassword:");
+            let path = scratch.write("secrets.yaml", &contents);
+            let (before, rest) = contents.split_at(contents.find("age:").unwrap());
+            let after = &rest[rest.find("lastmodified:").unwrap()..];
+
+            let add = [added_recipient.clone()];
+            if let Err(e) = rekey_sops_file(&path, sops_path, &identities(&ours), &add, &[]) {
+                panic!("{:?}: {:#}", sops_path, e);
+            }
+
+            let rekeyed = std::fs::read_to_string(&path).unwrap();
+            assert!(rekeyed.starts_with(before), "{}", rekeyed);
+            assert!(rekeyed.ends_with(after), "{}", rekeyed);
+            assert!(rekeyed.contains(&added_recipient));
+            let file = load_sops_file(&path, sops_path).unwrap();
+            file.verify_mac(&identities(&added)).unwrap();
+        }
+    }
+
+    #[test]
+    fn rekeys_that_lock_us_out_leave_the_file_untouched() {
+        let scratch = Scratch::new();
+        let (ours, other) = (identity(), identity());
+        let ours_recipient = ours.to_public().to_string();
+        let sops = Sops {
+            recipients: vec![ours_recipient.clone(), other.to_public().to_string()],
+            ..Sops::new(&ours)
+        };
+        let contents = sops.yaml("password: hunter2\n");
+        let path = scratch.write("secrets.yaml", &contents);
+
+        let error = rekey_sops_file(&path, None, &identities(&ours), &[], &[ours_recipient])
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<DecryptionError>(),
+            Some(DecryptionError::NoRecipients { .. })
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        assert_eq!(std::fs::read_dir(scratch.path()).unwrap().count(), 1);
+    }
 }
@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    os::unix::fs::OpenOptionsExt,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use thiserror::Error;
+use tracing::debug;
+
+/// The environment variable holding the timeout for fetching sources, in seconds
+const TIMEOUT_VARIABLE: &str = "SECNIX_HTTP_TIMEOUT";
+/// The environment variable holding a bearer token sent when fetching sources
+const TOKEN_VARIABLE: &str = "SECNIX_HTTP_TOKEN";
+const DEFAULT_TIMEOUT: u64 = 30;
+/// The environment variable holding the maximum size of a source, in bytes
+const MAX_SIZE_VARIABLE: &str = "SECNIX_MAX_SOURCE_SIZE";
+const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Could not fetch {0}: {1}")]
+    Fetch(String, #[source] reqwest::Error),
+    #[error("Fetching {0} returned status {1}")]
+    Status(String, reqwest::StatusCode),
+    #[error("Invalid timeout in {TIMEOUT_VARIABLE}: {0}")]
+    InvalidTimeout(String),
+    #[error("Invalid size in {MAX_SIZE_VARIABLE}: {0}")]
+    InvalidMaxSize(String),
+    #[error("{0} is larger than the maximum source size of {1} bytes")]
+    TooLarge(String, u64),
+    #[error("{0} is not a regular file")]
+    NotAFile(String),
+}
+
+/// Sources fetched over HTTPS during this run. These are only kept in memory.
+static FETCHED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Checks if the source is fetched over HTTPS rather than read from the filesystem
+pub fn is_remote(source: &str) -> bool {
+    source.starts_with("https://")
+}
+
+/// Reads a source, which is either a path or an `https://` URL, refusing sources larger than the
+/// maximum source size
+pub fn read_source(source: &str) -> Result<String> {
+    read(source, Some(max_size()?))
+}
+
+/// Reads a source without limiting its size. Binary sources hold a whole file, so they can
+/// legitimately be larger than the files with a few values the limit is meant for.
+pub fn read_large_source(source: &str) -> Result<String> {
+    read(source, None)
+}
+
+fn read(source: &str, max_size: Option<u64>) -> Result<String> {
+    if !is_remote(source) {
+        return read_file(source, max_size);
+    }
+
+    let fetched = FETCHED.get_or_init(Default::default);
+    if let Some(data) = fetched.lock().unwrap().get(source) {
+        debug!("Using previously fetched {}", source);
+        return Ok(data.clone());
+    }
+
+    let data = fetch(source, max_size)?;
+    fetched
+        .lock()
+        .unwrap()
+        .insert(source.to_string(), data.clone());
+    Ok(data)
+}
+
+fn max_size() -> Result<u64> {
+    match std::env::var(MAX_SIZE_VARIABLE) {
+        Ok(size) => Ok(size.parse().map_err(|_| Error::InvalidMaxSize(size))?),
+        Err(_) => Ok(DEFAULT_MAX_SIZE),
+    }
+}
+
+fn read_file(path: &str, max_size: Option<u64>) -> Result<String> {
+    // Opening without blocking lets FIFOs and devices, which could block forever or never end, be
+    // refused through the handle that is read
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(Error::NotAFile(path.to_string()).into());
+    }
+    // The file can grow after it is opened, so reading stops as soon as the limit is passed
+    let mut data = vec![];
+    let limit = max_size.map_or(u64::MAX, |max| max + 1);
+    file.take(limit).read_to_end(&mut data)?;
+    if let Some(max_size) = max_size.filter(|max| data.len() as u64 > *max) {
+        return Err(Error::TooLarge(path.to_string(), max_size).into());
+    }
+    Ok(String::from_utf8(data)?)
+}
+
+fn fetch(url: &str, max_size: Option<u64>) -> Result<String> {
+    let timeout = match std::env::var(TIMEOUT_VARIABLE) {
+        Ok(timeout) => timeout
+            .parse()
+            .map_err(|_| Error::InvalidTimeout(timeout))?,
+        Err(_) => DEFAULT_TIMEOUT,
+    };
+    debug!("Fetching {} with a timeout of {}s", url, timeout);
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .map_err(|e| Error::Fetch(url.to_string(), e))?;
+    let mut request = client.get(url);
+    if let Ok(token) = std::env::var(TOKEN_VARIABLE) {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request
+        .send()
+        .map_err(|e| Error::Fetch(url.to_string(), e))?;
+    if !response.status().is_success() {
+        return Err(Error::Status(url.to_string(), response.status()).into());
+    }
+    if let Some(max_size) = max_size.filter(|max| response.content_length() > Some(*max)) {
+        return Err(Error::TooLarge(url.to_string(), max_size).into());
+    }
+    // Content-Length can be missing or lie, so reading stops as soon as the limit is passed
+    let mut data = vec![];
+    let limit = max_size.map_or(u64::MAX, |max| max + 1);
+    response.take(limit).read_to_end(&mut data)?;
+    if let Some(max_size) = max_size.filter(|max| data.len() as u64 > *max) {
+        return Err(Error::TooLarge(url.to_string(), max_size).into());
+    }
+    Ok(String::from_utf8(data)?)
+}
@@ -0,0 +1,412 @@
+//! Fixtures for the unit tests. Sops files are encrypted here the way sops writes them, so the
+//! tests need neither sops nor any keys on the machine running them.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Key, Nonce,
+};
+use age::secrecy::{ExposeSecret, SecretString};
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde_json::json;
+use sha2::{Digest, Sha512};
+use ulid::Ulid;
+
+use crate::{
+    enc::age::{encrypt_kek, Identities, SopsGcm},
+    sops::format_float,
+};
+
+/// The data key of every generated sops file
+pub const DATA_KEY: [u8; 32] = [7u8; 32];
+
+/// The modification time of every generated sops file, which the MAC is encrypted with
+pub const LAST_MODIFIED: &str = "2024-01-01T00:00:00Z";
+
+/// A directory in the temporary directory that is removed when dropped
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    pub fn new() -> Self {
+        let directory = std::env::temp_dir().join(format!("secnix-test-{}", Ulid::new()));
+        std::fs::create_dir_all(&directory).unwrap();
+        Scratch(directory)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Gets the path of a file in the directory as a string, which is how manifests refer to them
+    pub fn join(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+
+    /// Writes a file in the directory, creating its parents, and returns its path
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = self.0.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Generates an age identity
+pub fn identity() -> age::x25519::Identity {
+    age::x25519::Identity::generate()
+}
+
+/// Gets the identities to decrypt with a single identity
+pub fn identities(identity: &age::x25519::Identity) -> Identities {
+    let copy = identity.to_string().expose_secret().parse().unwrap();
+    Identities::new(vec![copy])
+}
+
+/// Writes an identity to an age identity file, which can be used as an ssh key in manifests
+pub fn write_identity(scratch: &Scratch, identity: &age::x25519::Identity) -> String {
+    scratch.write(
+        "keys.txt",
+        format!(
+            "# created: {}\n{}\n",
+            LAST_MODIFIED,
+            identity.to_string().expose_secret()
+        ),
+    )
+}
+
+/// A scratch directory holding the sources, store and links of a manifest, along with the identity
+/// its secrets are encrypted for
+pub struct Fixture {
+    pub scratch: Scratch,
+    pub identity: age::x25519::Identity,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Fixture {
+            scratch: Scratch::new(),
+            identity: identity(),
+        }
+    }
+
+    /// Where the manifest stores its generations
+    pub fn store(&self) -> PathBuf {
+        self.scratch.path().join("store")
+    }
+
+    /// Reads a file below the scratch directory
+    pub fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.scratch.path().join(name)).unwrap()
+    }
+
+    /// Writes a source encrypted for the identity and returns a secret reading its `value` key
+    pub fn secret(&self, name: &str, plaintext: &str) -> serde_json::Value {
+        let source = self.scratch.write(
+            &format!("{}.yaml", name),
+            Sops::new(&self.identity).yaml(plaintext),
+        );
+        json!({
+            "type": "yaml",
+            "name": name,
+            "source": source,
+            "key": "value",
+        })
+    }
+
+    /// Like [Fixture::secret], linked to `links/<name>`
+    pub fn linked_secret(&self, name: &str, plaintext: &str) -> serde_json::Value {
+        let mut secret = self.secret(name, plaintext);
+        secret["link"] = json!(self.scratch.join(&format!("links/{}", name)));
+        secret
+    }
+
+    /// Writes a template source and returns a template rendering it to `destination`
+    pub fn template(&self, name: &str, contents: &str, destination: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "source": self.scratch.write(&format!("{}.in", name), contents),
+            "destination": destination,
+        })
+    }
+
+    /// Writes a manifest that stores its generations in [Fixture::store] and decrypts with the
+    /// identity. `fields` are added to the defaults of no secrets or templates, replacing them.
+    pub fn manifest(&self, fields: serde_json::Value) -> String {
+        let mut manifest = json!({
+            "version": 1,
+            "secrets": [],
+            "ssh_keys": [write_identity(&self.scratch, &self.identity)],
+            "secret_directory": self.store(),
+            "templates": [],
+        });
+        if let (Some(manifest), Some(fields)) = (manifest.as_object_mut(), fields.as_object()) {
+            manifest.extend(fields.clone());
+        }
+        self.scratch.write("manifest.json", manifest.to_string())
+    }
+}
+
+/// Encrypts a value with the data key the way sops does, with the path as additional data
+pub fn encrypt_value(value: &[u8], aad: &[u8], data_type: &str) -> String {
+    encrypt_with("AES256_GCM", &[1u8; 32], value, aad, data_type)
+}
+
+/// Encrypts a value with the data key using the named sops cipher and iv
+pub fn encrypt_with(cipher: &str, iv: &[u8], value: &[u8], aad: &[u8], data_type: &str) -> String {
+    let payload = Payload { msg: value, aad };
+    let encrypted = match cipher {
+        "AES256_GCM" => SopsGcm::new(Key::<SopsGcm>::from_slice(&DATA_KEY))
+            .encrypt(Nonce::from_slice(iv), payload),
+        "CHACHA20_POLY1305" => ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&DATA_KEY))
+            .encrypt(chacha20poly1305::Nonce::from_slice(iv), payload),
+        other => panic!("unknown cipher {}", other),
+    }
+    .unwrap();
+    let (data, tag) = encrypted.split_at(encrypted.len() - 16);
+    format!(
+        "ENC[{},data:{},iv:{},tag:{},type:{}]",
+        cipher,
+        general_purpose::STANDARD.encode(data),
+        general_purpose::STANDARD.encode(iv),
+        general_purpose::STANDARD.encode(tag),
+        data_type
+    )
+}
+
+/// Wraps the data key with a passphrase, like sops does for `SOPS_AGE_PASSPHRASE`
+pub fn wrap_with_passphrase(passphrase: &str) -> String {
+    let encryptor = age::Encryptor::with_user_passphrase(SecretString::new(passphrase.into()));
+    let mut output = vec![];
+    let armor = age::armor::ArmoredWriter::wrap_output(&mut output, age::armor::Format::AsciiArmor)
+        .unwrap();
+    let mut writer = encryptor.wrap_output(armor).unwrap();
+    writer.write_all(&DATA_KEY).unwrap();
+    writer.finish().and_then(|armor| armor.finish()).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+/// How a sops file is encrypted
+pub struct Sops {
+    /// The recipients the data key is wrapped for
+    pub recipients: Vec<String>,
+    /// A passphrase the data key is wrapped with as well
+    pub passphrase: Option<String>,
+    /// Decides from its path if a value is encrypted, every value is by default
+    pub encrypted: fn(&[String]) -> bool,
+    /// If only encrypted values are part of the MAC
+    pub mac_only_encrypted: bool,
+    /// Other fields of the sops metadata, such as `unencrypted_suffix`
+    pub metadata: Vec<(&'static str, &'static str)>,
+}
+
+impl Sops {
+    pub fn new(identity: &age::x25519::Identity) -> Self {
+        Sops {
+            recipients: vec![identity.to_public().to_string()],
+            passphrase: None,
+            encrypted: |_| true,
+            mac_only_encrypted: false,
+            metadata: vec![],
+        }
+    }
+
+    /// Encrypts a YAML document, returning the encrypted document with its sops metadata
+    pub fn yaml(&self, plaintext: &str) -> String {
+        let (mut document, metadata) = self.encrypt(plaintext);
+        document.insert("sops".into(), metadata.into());
+        serde_yaml::to_string(&document).unwrap()
+    }
+
+    /// Encrypts a document of `KEY: value` pairs as a dotenv file
+    pub fn dotenv(&self, plaintext: &str) -> String {
+        let (document, metadata) = self.encrypt(plaintext);
+        let mut contents = String::new();
+        for (key, value) in &document {
+            contents.push_str(&format!("{}={}\n", key.as_str().unwrap(), scalar(value)));
+        }
+        for (key, value) in flatten(&metadata) {
+            contents.push_str(&format!("sops_{}={}\n", key, value));
+        }
+        contents
+    }
+
+    /// Encrypts a document of sections holding `key: value` pairs as an INI file
+    pub fn ini(&self, plaintext: &str) -> String {
+        let (document, metadata) = self.encrypt(plaintext);
+        let mut contents = String::new();
+        for (section, values) in &document {
+            contents.push_str(&format!("[{}]\n", section.as_str().unwrap()));
+            for (key, value) in values.as_mapping().unwrap() {
+                contents.push_str(&format!("{} = {}\n", key.as_str().unwrap(), scalar(value)));
+            }
+            contents.push('\n');
+        }
+        contents.push_str("[sops]\n");
+        for (key, value) in flatten(&metadata) {
+            contents.push_str(&format!("{} = {}\n", key, value));
+        }
+        contents
+    }
+
+    /// Encrypts the values of a YAML document and computes its MAC, returning the encrypted
+    /// document and the sops metadata
+    pub fn encrypt(&self, plaintext: &str) -> (serde_yaml::Mapping, serde_yaml::Mapping) {
+        let plaintext: serde_yaml::Mapping = serde_yaml::from_str(plaintext).unwrap();
+        let mut hasher = Sha512::new();
+        let mut encrypted = serde_yaml::Mapping::new();
+        for (key, value) in &plaintext {
+            let path = vec![key_segment(key)];
+            encrypted.insert(key.clone(), self.encrypt_tree(value, &path, &mut hasher));
+        }
+        let mac = format!("{:X}", hasher.finalize());
+        (encrypted, self.metadata(&mac))
+    }
+
+    fn encrypt_tree(
+        &self,
+        value: &serde_yaml::Value,
+        path: &[String],
+        hasher: &mut Sha512,
+    ) -> serde_yaml::Value {
+        let (plaintext, data_type) = match value {
+            serde_yaml::Value::Mapping(m) => {
+                let mut encrypted = serde_yaml::Mapping::new();
+                for (key, value) in m {
+                    let mut child = path.to_vec();
+                    child.push(key_segment(key));
+                    encrypted.insert(key.clone(), self.encrypt_tree(value, &child, hasher));
+                }
+                return encrypted.into();
+            }
+            // Items of a sequence share the path of the sequence
+            serde_yaml::Value::Sequence(s) => {
+                return s
+                    .iter()
+                    .map(|value| self.encrypt_tree(value, path, hasher))
+                    .collect::<Vec<_>>()
+                    .into();
+            }
+            serde_yaml::Value::String(s) => (s.clone(), "str"),
+            serde_yaml::Value::Number(n) if n.is_f64() => {
+                (format_float(n.as_f64().unwrap()), "float")
+            }
+            serde_yaml::Value::Number(n) => (n.to_string(), "int"),
+            // sops writes booleans with a capital letter
+            serde_yaml::Value::Bool(true) => ("True".to_string(), "bool"),
+            serde_yaml::Value::Bool(false) => ("False".to_string(), "bool"),
+            other => return other.clone(),
+        };
+        // Empty strings are never encrypted
+        if !(self.encrypted)(path) || plaintext.is_empty() {
+            if !self.mac_only_encrypted {
+                hasher.update(plaintext.as_bytes());
+            }
+            return value.clone();
+        }
+        hasher.update(plaintext.as_bytes());
+        let aad = path.join(":") + ":";
+        encrypt_value(plaintext.as_bytes(), aad.as_bytes(), data_type).into()
+    }
+
+    fn metadata(&self, mac: &str) -> serde_yaml::Mapping {
+        let mut age: Vec<serde_yaml::Value> = self
+            .recipients
+            .iter()
+            .map(|recipient| {
+                let mut entry = serde_yaml::Mapping::new();
+                entry.insert("recipient".into(), recipient.as_str().into());
+                entry.insert(
+                    "enc".into(),
+                    encrypt_kek(&DATA_KEY, recipient).unwrap().into(),
+                );
+                entry.into()
+            })
+            .collect();
+        if let Some(passphrase) = &self.passphrase {
+            let mut entry = serde_yaml::Mapping::new();
+            entry.insert("recipient".into(), "passphrase".into());
+            entry.insert("enc".into(), wrap_with_passphrase(passphrase).into());
+            age.push(entry.into());
+        }
+
+        let mut metadata = serde_yaml::Mapping::new();
+        metadata.insert("age".into(), age.into());
+        metadata.insert("lastmodified".into(), LAST_MODIFIED.into());
+        metadata.insert(
+            "mac".into(),
+            encrypt_value(mac.as_bytes(), LAST_MODIFIED.as_bytes(), "str").into(),
+        );
+        if self.mac_only_encrypted {
+            metadata.insert("mac_only_encrypted".into(), true.into());
+        }
+        for (key, value) in &self.metadata {
+            metadata.insert((*key).into(), (*value).into());
+        }
+        metadata.insert("version".into(), "3.8.1".into());
+        metadata
+    }
+}
+
+fn key_segment(key: &serde_yaml::Value) -> String {
+    match key {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        other => panic!("Unsupported key {:?}", other),
+    }
+}
+
+fn scalar(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        other => panic!("Unsupported value {:?}", other),
+    }
+}
+
+/// Flattens the sops metadata the way sops stores it in dotenv and INI files, with newlines
+/// escaped
+fn flatten(metadata: &serde_yaml::Mapping) -> Vec<(String, String)> {
+    fn flatten_into(value: &serde_yaml::Value, prefix: String, out: &mut Vec<(String, String)>) {
+        match value {
+            serde_yaml::Value::Mapping(m) => {
+                for (key, value) in m {
+                    flatten_into(
+                        value,
+                        format!("{}__map_{}", prefix, key.as_str().unwrap()),
+                        out,
+                    );
+                }
+            }
+            serde_yaml::Value::Sequence(s) => {
+                for (i, value) in s.iter().enumerate() {
+                    flatten_into(value, format!("{}__list_{}", prefix, i), out);
+                }
+            }
+            value => out.push((prefix, scalar(value).replace('\n', "\\n"))),
+        }
+    }
+
+    let mut out = vec![];
+    for (key, value) in metadata {
+        let key = key.as_str().unwrap().to_string();
+        match value {
+            serde_yaml::Value::Mapping(_) | serde_yaml::Value::Sequence(_) => {
+                flatten_into(value, key, &mut out)
+            }
+            value => out.push((key, scalar(value).replace('\n', "\\n"))),
+        }
+    }
+    out
+}